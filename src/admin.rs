@@ -0,0 +1,440 @@
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc};
+
+use poem::{
+    async_trait, http::StatusCode, web::Data, Endpoint, EndpointExt, Error, IntoResponse,
+    Middleware, Request, Response, Result,
+};
+use poem_openapi::{param::Path, payload::Json, Object, OpenApi};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, RequestOrigin},
+    auth::{protect, AuthData},
+    commands::{KickUser, ModerateMessage},
+    events::{DomainEvent, UserWasBanned, UserWasUnbanned},
+    Context,
+};
+
+/// Banned users can not log in and their sessions and event streams are
+/// ended when they are banned
+#[derive(Debug, Object, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Ban {
+    username: String,
+    banned_by: String,
+    reason: Option<String>,
+    banned_at: OffsetDateTime,
+}
+
+/// Banned users by username. When a file is given every change is written to
+/// it so that bans survive a restart.
+#[derive(Clone)]
+pub struct Bans {
+    bans: Arc<Mutex<HashMap<String, Ban>>>,
+    file: Option<PathBuf>,
+}
+
+impl Bans {
+    pub fn new(file: Option<PathBuf>) -> Bans {
+        Bans {
+            bans: Arc::new(Mutex::new(HashMap::new())),
+            file,
+        }
+    }
+
+    /// Load the bans that were persisted by a previous run
+    pub async fn restore(&self) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let contents = match tokio::fs::read(file).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        *self.bans.lock().await = serde_json::from_slice(&contents)?;
+
+        Ok(())
+    }
+
+    pub async fn contains(&self, username: &str) -> bool {
+        self.bans.lock().await.contains_key(username)
+    }
+
+    /// Whether a banned user matches the predicate
+    pub async fn any(&self, predicate: impl Fn(&str) -> bool) -> bool {
+        self.bans
+            .lock()
+            .await
+            .keys()
+            .any(|username| predicate(username))
+    }
+
+    /// Oldest ban first
+    pub async fn all(&self) -> Vec<Ban> {
+        let mut bans = self.bans.lock().await.values().cloned().collect::<Vec<_>>();
+        bans.sort_by_key(|ban| ban.banned_at);
+
+        bans
+    }
+
+    /// Returns false when the user already was banned
+    pub async fn insert(&self, ban: Ban) -> io::Result<bool> {
+        let mut bans = self.bans.lock().await;
+        if bans.contains_key(&ban.username) {
+            return Ok(false);
+        }
+        bans.insert(ban.username.clone(), ban);
+        self.persist(&bans).await?;
+
+        Ok(true)
+    }
+
+    /// Returns whether the user was banned
+    pub async fn remove(&self, username: &str) -> io::Result<bool> {
+        let mut bans = self.bans.lock().await;
+        if bans.remove(username).is_none() {
+            return Ok(false);
+        }
+        self.persist(&bans).await?;
+
+        Ok(true)
+    }
+
+    async fn persist(&self, bans: &HashMap<String, Ban>) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        if let Some(parent) = file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp = file.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(bans)?).await?;
+        tokio::fs::rename(&tmp, file).await
+    }
+}
+
+fn storage_error(error: io::Error) -> Error {
+    tracing::error!(%error, "Failed to store bans");
+
+    Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Includes every room together with its members, for moderation purposes
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct AdminRoom {
+    id: Uuid,
+    name: String,
+    users: Vec<String>,
+    message_count: u64,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct ModerationRequest {
+    /// Shown to other users and kept in the audit log
+    #[oai(validator(max_length = 1024))]
+    reason: Option<String>,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct ProjectionRebuild {
+    /// Number of stored events that were replayed
+    events: u64,
+}
+
+pub struct RequireAdmin;
+
+impl<E: Endpoint> Middleware<E> for RequireAdmin {
+    type Output = RequireAdminEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RequireAdminEndpoint { ep }
+    }
+}
+
+pub struct RequireAdminEndpoint<E> {
+    ep: E,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for RequireAdminEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let ctx = req.data::<Context>().cloned().unwrap();
+        let is_admin = req
+            .extensions()
+            .get::<AuthData>()
+            .is_some_and(|auth_data| ctx.config.is_admin(&auth_data.username));
+
+        if !is_admin {
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+
+        Ok(self.ep.call(req).await?.into_response())
+    }
+}
+
+pub fn protect_admin(ep: impl Endpoint) -> impl Endpoint {
+    protect(ep.with(RequireAdmin))
+}
+
+impl Context {
+    pub(crate) async fn is_banned(&self, username: &str) -> bool {
+        self.banned_users.contains(username).await
+    }
+}
+
+#[derive(Default)]
+pub struct Api;
+
+#[OpenApi]
+impl Api {
+    #[oai(
+        path = "/admin/rooms",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "admin_rooms_get"
+    )]
+    async fn get_rooms(&self, ctx: Data<&Context>) -> Result<Json<Vec<AdminRoom>>> {
+        let rooms = ctx.rooms.lock().await.clone();
+        let messages = ctx.messages_in_room.lock().await;
+        let users = ctx.users_in_room.lock().await;
+
+        let rooms = rooms
+            .into_iter()
+            .map(|room| AdminRoom {
+                users: users.get(&room.id).cloned().unwrap_or_default(),
+                message_count: messages.get(&room.id).map_or(0, Vec::len) as u64,
+                id: room.id,
+                name: room.name,
+            })
+            .collect();
+
+        Ok(Json(rooms))
+    }
+
+    #[oai(
+        path = "/admin/rooms/:room_id/messages/:message_id",
+        method = "delete",
+        transform = "protect_admin",
+        operation_id = "admin_rooms_room_messages_message_delete"
+    )]
+    async fn delete_message(
+        &self,
+        ctx: Data<&Context>,
+        room_id: Path<Uuid>,
+        message_id: Path<Uuid>,
+        request: Json<ModerationRequest>,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
+    ) -> Result<()> {
+        let (room_id, message_id) = (room_id.0, message_id.0);
+
+        // Uploads belong to the message, the read model forgets about them
+        // once the events are dispatched
+        let attachment_ids = ctx
+            .messages_in_room
+            .lock()
+            .await
+            .get(&room_id)
+            .and_then(|messages| messages.iter().find(|message| message.id == message_id))
+            .map(|message| {
+                message
+                    .attachments
+                    .iter()
+                    .map(|attachment| attachment.id)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        ctx.execute_audited(
+            AuditAction::DeleteMessage,
+            &auth_data.username,
+            format!("message:{}", message_id),
+            &origin,
+            ModerateMessage {
+                room_id,
+                message_id,
+                moderated_by: auth_data.username.clone(),
+                reason: request.0.reason,
+                moderated_at: OffsetDateTime::now_utc(),
+            },
+        )
+        .await?;
+
+        ctx.remove_attachments(|attachment| attachment_ids.contains(&attachment.id))
+            .await;
+
+        Ok(())
+    }
+
+    /// Remove a user from a room, they are able to join again unless banned
+    #[oai(
+        path = "/admin/rooms/:room_id/users/:username",
+        method = "delete",
+        transform = "protect_admin",
+        operation_id = "admin_rooms_room_users_user_delete"
+    )]
+    async fn kick_user(
+        &self,
+        ctx: Data<&Context>,
+        room_id: Path<Uuid>,
+        username: Path<String>,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
+    ) -> Result<()> {
+        ctx.execute_audited(
+            AuditAction::KickUser,
+            &auth_data.username,
+            format!("user:{}", username.0),
+            &origin,
+            KickUser {
+                room_id: room_id.0,
+                username: username.0.clone(),
+                kicked_by: auth_data.username.clone(),
+                kicked_at: OffsetDateTime::now_utc(),
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[oai(
+        path = "/admin/bans",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "admin_bans_get"
+    )]
+    async fn get_bans(&self, ctx: Data<&Context>) -> Result<Json<Vec<Ban>>> {
+        Ok(Json(ctx.banned_users.all().await))
+    }
+
+    /// Ban a user, logging them out everywhere. Banning a user that already
+    /// is banned has no effect.
+    #[oai(
+        path = "/admin/bans/:username",
+        method = "put",
+        transform = "protect_admin",
+        operation_id = "admin_bans_ban_put"
+    )]
+    async fn ban_user(
+        &self,
+        ctx: Data<&Context>,
+        username: Path<String>,
+        request: Json<ModerationRequest>,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
+    ) -> Result<()> {
+        if username.0 == auth_data.username {
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+
+        if ctx.banned_users.contains(&username.0).await {
+            return Ok(());
+        }
+
+        let ban = Ban {
+            username: username.0.clone(),
+            banned_by: auth_data.username.clone(),
+            reason: request.0.reason,
+            banned_at: OffsetDateTime::now_utc(),
+        };
+        let event = DomainEvent::UserWasBanned(UserWasBanned {
+            username: ban.username.clone(),
+            banned_by: ban.banned_by.clone(),
+            reason: ban.reason.clone(),
+            banned_at: ban.banned_at,
+        });
+        ctx.audit(
+            AuditAction::BanUser,
+            &ban.banned_by,
+            format!("user:{}", ban.username),
+            &origin,
+            event.clone(),
+        )
+        .await?;
+
+        // Another admin may have banned the user in the meantime
+        if !ctx
+            .banned_users
+            .insert(ban.clone())
+            .await
+            .map_err(storage_error)?
+        {
+            return Ok(());
+        }
+
+        ctx.sessions
+            .revoke_user(&ban.username)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        ctx.bus.dispatch_event(event).await;
+
+        Ok(())
+    }
+
+    #[oai(
+        path = "/admin/bans/:username",
+        method = "delete",
+        transform = "protect_admin",
+        operation_id = "admin_bans_ban_delete"
+    )]
+    async fn unban_user(
+        &self,
+        ctx: Data<&Context>,
+        username: Path<String>,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
+    ) -> Result<()> {
+        if !ctx.banned_users.contains(&username.0).await {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        }
+
+        let event = DomainEvent::UserWasUnbanned(UserWasUnbanned {
+            username: username.0.clone(),
+            unbanned_by: auth_data.username.clone(),
+            unbanned_at: OffsetDateTime::now_utc(),
+        });
+        ctx.audit(
+            AuditAction::UnbanUser,
+            &auth_data.username,
+            format!("user:{}", username.0),
+            &origin,
+            event.clone(),
+        )
+        .await?;
+
+        if ctx
+            .banned_users
+            .remove(&username.0)
+            .await
+            .map_err(storage_error)?
+        {
+            ctx.bus.dispatch_event(event).await;
+        }
+
+        Ok(())
+    }
+
+    /// Throw away the read models and rebuild them from the stored events
+    #[oai(
+        path = "/admin/projections/rebuild",
+        method = "post",
+        transform = "protect_admin",
+        operation_id = "admin_projections_rebuild_post"
+    )]
+    async fn rebuild_projections(&self, ctx: Data<&Context>) -> Result<Json<ProjectionRebuild>> {
+        let events = ctx.projections.rebuild().await;
+
+        Ok(Json(ProjectionRebuild {
+            events: events as u64,
+        }))
+    }
+}
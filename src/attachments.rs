@@ -0,0 +1,483 @@
+use std::{
+    collections::HashSet,
+    io::{self, Cursor},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use futures_util::StreamExt;
+use image::ImageFormat;
+use poem::{
+    async_trait, http::header, http::StatusCode, web::Data, Body, Endpoint, EndpointExt, Error,
+    IntoResponse, Middleware, Request, Response, Result,
+};
+use poem_openapi::{
+    param::Path,
+    payload::{Binary, Json},
+    types::multipart::Upload,
+    ApiResponse, Multipart, Object, OpenApi,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+use crate::{auth::protect, auth::AuthData, Context};
+
+/// Only these content types can be uploaded as an attachment
+pub const ALLOWED_CONTENT_TYPES: [&str; 7] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+    "application/zip",
+];
+
+/// Only these content types are shown in the browser, anything else is
+/// downloaded so uploads can't run scripts on our origin
+const INLINE_CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Room for the multipart boundaries and part headers around the file itself
+const MULTIPART_OVERHEAD: u64 = 16 * 1024;
+
+#[derive(Debug, Object, Clone, Serialize, Eq, PartialEq)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub uploaded_by: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Sha256 hash of the contents, used to locate the blob
+    pub hash: String,
+    /// Hash of a png thumbnail, only available for images
+    pub thumbnail: Option<String>,
+}
+
+/// Content addressed storage of uploaded files, blobs are stored in a
+/// directory named after the first two characters of their sha256 hash.
+///
+/// Blobs are removed together with the last attachment that refers to them,
+/// when a message is moderated or its room is removed. Uploads that are never
+/// sent with a message are kept until their room is removed.
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: impl Into<PathBuf>) -> BlobStore {
+        BlobStore { dir: dir.into() }
+    }
+
+    /// Store the given data and return its hash, storing the same contents
+    /// twice only results in a single file
+    pub async fn put(&self, data: &[u8]) -> std::io::Result<String> {
+        let hash = Sha256::digest(data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        let path = self.path(&hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(hash);
+        }
+
+        let parent = path.parent().expect("Blob paths always have a parent");
+        tokio::fs::create_dir_all(parent).await?;
+
+        // Write to a temporary file first so that readers never see a partial blob
+        let tmp = parent.join(format!("{}.{}.tmp", hash, Uuid::new_v4()));
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+
+        Ok(hash)
+    }
+
+    /// Make sure that blobs can be stored, by writing and removing a file
+    pub async fn check_writable(&self) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let probe = self.dir.join(format!(".probe.{}.tmp", Uuid::new_v4()));
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await
+    }
+
+    pub async fn get(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(self.path(hash)).await
+    }
+
+    /// Removing a blob that does not exist is not an error
+    pub async fn remove(&self, hash: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(hash)).await {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+}
+
+/// Create a png thumbnail for images, returns `None` if the data could not be
+/// decoded as an image
+pub fn generate_thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(data).ok()?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+
+    let mut png = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut png, ImageFormat::Png).ok()?;
+
+    Some(png.into_inner())
+}
+
+/// Rejects request bodies that are larger than an attachment is allowed to be,
+/// before they are written to disk by the multipart parser
+pub struct UploadSizeLimit;
+
+impl<E: Endpoint> Middleware<E> for UploadSizeLimit {
+    type Output = UploadSizeLimitEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        UploadSizeLimitEndpoint { ep }
+    }
+}
+
+pub struct UploadSizeLimitEndpoint<E> {
+    ep: E,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for UploadSizeLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let ctx = req.data::<Context>().cloned().unwrap();
+        let max_size = ctx.config.limits.max_attachment_size + MULTIPART_OVERHEAD;
+
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > max_size) {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+
+        // Bodies without a length are cut off as soon as they grow too large
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let mut size = 0;
+        let body = req.take_body().into_bytes_stream().map({
+            let exceeded = exceeded.clone();
+            move |chunk| {
+                let chunk = chunk?;
+                size += chunk.len() as u64;
+                if size > max_size {
+                    exceeded.store(true, Ordering::Relaxed);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Attachment is too large",
+                    ));
+                }
+
+                Ok(chunk)
+            }
+        });
+        req.set_body(Body::from_bytes_stream(body));
+
+        let resp = self.ep.get_response(req).await;
+        if exceeded.load(Ordering::Relaxed) {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        }
+
+        Ok(resp)
+    }
+}
+
+pub fn limit_upload(ep: impl Endpoint) -> impl Endpoint {
+    protect(ep.with(UploadSizeLimit))
+}
+
+#[derive(Debug, Multipart)]
+struct UploadAttachmentRequest {
+    file: Upload,
+}
+
+#[derive(ApiResponse)]
+enum AttachmentResponse {
+    #[oai(status = 200)]
+    Ok(
+        Binary<Vec<u8>>,
+        #[oai(header = "Content-Type")] String,
+        #[oai(header = "Content-Disposition")] String,
+        #[oai(header = "X-Content-Type-Options")] String,
+    ),
+}
+
+#[derive(Default)]
+pub struct Api;
+
+#[OpenApi]
+impl Api {
+    #[oai(
+        path = "/rooms/:room_id/attachments",
+        method = "post",
+        transform = "limit_upload",
+        operation_id = "rooms_room_attachments_post"
+    )]
+    async fn upload_attachment(
+        &self,
+        room_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        request: UploadAttachmentRequest,
+        auth_data: Data<&AuthData>,
+    ) -> Result<Json<Attachment>> {
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+
+        let content_type = request
+            .file
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        if !ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(Error::from_status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+
+        let filename = request.file.file_name().unwrap_or("attachment").to_string();
+
+        // The request body is limited by `UploadSizeLimit`, which leaves room
+        // for the multipart encoding. The file itself is checked here, reading
+        // one byte more than allowed to detect oversized files.
+        let max_attachment_size = ctx.config.limits.max_attachment_size;
+        let mut data = Vec::new();
+        request
+            .file
+            .into_async_read()
+            .take(max_attachment_size + 1)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::BAD_REQUEST))?;
+        if data.len() as u64 > max_attachment_size {
+            return Err(Error::from_status(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        let size = data.len() as u64;
+
+        let (data, thumbnail) = if content_type.starts_with("image/") {
+            let (data, thumbnail) = tokio::task::spawn_blocking(move || {
+                let thumbnail = generate_thumbnail(&data);
+                (data, thumbnail)
+            })
+            .await
+            .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+            match thumbnail {
+                // Files claiming to be an image should be decodable as one
+                None => return Err(Error::from_status(StatusCode::UNSUPPORTED_MEDIA_TYPE)),
+                Some(thumbnail) => (data, Some(thumbnail)),
+            }
+        } else {
+            (data, None)
+        };
+
+        let hash = ctx
+            .blobs
+            .put(&data)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+        let thumbnail = match thumbnail {
+            None => None,
+            Some(thumbnail) => Some(
+                ctx.blobs
+                    .put(&thumbnail)
+                    .await
+                    .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?,
+            ),
+        };
+
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            room_id: room_id.0,
+            uploaded_by: auth_data.username.clone(),
+            filename,
+            content_type,
+            size,
+            hash,
+            thumbnail,
+        };
+
+        ctx.attachments
+            .lock()
+            .await
+            .insert(attachment.id, attachment.clone());
+
+        Ok(Json(attachment))
+    }
+
+    #[oai(
+        path = "/rooms/:room_id/attachments/:attachment_id",
+        method = "get",
+        transform = "protect",
+        operation_id = "rooms_room_attachments_attachment_get"
+    )]
+    async fn download_attachment(
+        &self,
+        room_id: Path<Uuid>,
+        attachment_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<AttachmentResponse> {
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+        let attachment = find_attachment(&ctx, room_id.0, attachment_id.0).await?;
+
+        let data = ctx
+            .blobs
+            .get(&attachment.hash)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::NOT_FOUND))?;
+
+        let disposition = content_disposition(&attachment.filename, &attachment.content_type);
+        Ok(AttachmentResponse::Ok(
+            Binary(data),
+            attachment.content_type,
+            disposition,
+            "nosniff".to_string(),
+        ))
+    }
+
+    #[oai(
+        path = "/rooms/:room_id/attachments/:attachment_id/thumbnail",
+        method = "get",
+        transform = "protect",
+        operation_id = "rooms_room_attachments_attachment_thumbnail_get"
+    )]
+    async fn download_thumbnail(
+        &self,
+        room_id: Path<Uuid>,
+        attachment_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<AttachmentResponse> {
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+        let attachment = find_attachment(&ctx, room_id.0, attachment_id.0).await?;
+
+        let Some(thumbnail) = attachment.thumbnail else {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        };
+
+        let data = ctx
+            .blobs
+            .get(&thumbnail)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::NOT_FOUND))?;
+
+        Ok(AttachmentResponse::Ok(
+            Binary(data),
+            "image/png".to_string(),
+            content_disposition(
+                &format!("{}.thumbnail.png", attachment.filename),
+                "image/png",
+            ),
+            "nosniff".to_string(),
+        ))
+    }
+}
+
+impl Context {
+    /// Mark uploads as sent before they are sent with a message. Uploads can
+    /// only be sent once, so that moderating a message never removes the
+    /// attachments of another one.
+    pub(crate) async fn claim_attachments(&self, attachments: &[Attachment]) -> Result<()> {
+        let ids = attachments
+            .iter()
+            .map(|attachment| attachment.id)
+            .collect::<HashSet<_>>();
+
+        let mut sent = self.sent_attachments.lock().await;
+        if ids.len() < attachments.len() || !sent.is_disjoint(&ids) {
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+        sent.extend(ids);
+
+        Ok(())
+    }
+
+    /// Allow uploads to be sent again after their message was rejected
+    pub(crate) async fn release_attachments(&self, attachments: &[Attachment]) {
+        let mut sent = self.sent_attachments.lock().await;
+        for attachment in attachments {
+            sent.remove(&attachment.id);
+        }
+    }
+
+    /// Forget the attachments matching the predicate, and remove their blobs
+    /// unless another attachment has the same contents
+    pub(crate) async fn remove_attachments(&self, predicate: impl Fn(&Attachment) -> bool) {
+        let (removed, remaining) = {
+            let mut attachments = self.attachments.lock().await;
+            let removed = attachments
+                .values()
+                .filter(|attachment| predicate(attachment))
+                .cloned()
+                .collect::<Vec<_>>();
+            attachments.retain(|_, attachment| !predicate(attachment));
+            self.sent_attachments
+                .lock()
+                .await
+                .retain(|id| attachments.contains_key(id));
+
+            let remaining = attachments
+                .values()
+                .flat_map(|attachment| {
+                    std::iter::once(attachment.hash.clone()).chain(attachment.thumbnail.clone())
+                })
+                .collect::<HashSet<_>>();
+            (removed, remaining)
+        };
+
+        let hashes = removed
+            .into_iter()
+            .flat_map(|attachment| std::iter::once(attachment.hash).chain(attachment.thumbnail))
+            .filter(|hash| !remaining.contains(hash))
+            .collect::<HashSet<_>>();
+        for hash in hashes {
+            if let Err(error) = self.blobs.remove(&hash).await {
+                tracing::warn!(%error, hash, "Failed to remove blob");
+            }
+        }
+    }
+}
+
+async fn find_attachment(ctx: &Context, room_id: Uuid, attachment_id: Uuid) -> Result<Attachment> {
+    let attachments = ctx.attachments.lock().await;
+
+    match attachments.get(&attachment_id) {
+        Some(attachment) if attachment.room_id == room_id => Ok(attachment.clone()),
+        _ => Err(Error::from_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+fn content_disposition(filename: &str, content_type: &str) -> String {
+    // Only printable ascii is allowed in header values, and quotes would
+    // break out of the filename parameter
+    let filename: String = filename
+        .chars()
+        .filter(|c| c.is_ascii() && !c.is_ascii_control() && *c != '"' && *c != '\\')
+        .collect();
+
+    let disposition = if INLINE_CONTENT_TYPES.contains(&content_type) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    format!("{}; filename=\"{}\"", disposition, filename)
+}
@@ -0,0 +1,539 @@
+use std::{io, path::PathBuf, sync::Arc};
+
+use poem::{
+    async_trait, http::StatusCode, web::Data, Error, FromRequest, Request, RequestBody, Result,
+};
+use poem_openapi::{
+    param::Query,
+    payload::{Json, PlainText},
+    types::ToJSON,
+    ApiResponse, Enum, Object, OpenApi,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use uuid::{uuid, Uuid};
+
+use crate::{
+    admin::protect_admin,
+    commands::Command,
+    events::{DomainEvent, Envelope},
+    request_tracing::RequestId,
+    Context,
+};
+
+/// The audit log is a single stream, its entries are numbered in order
+const AUDIT_LOG_ID: Uuid = uuid!("5d0c4d04-3f3c-4a0f-9d0e-6a1b8a7a2f41");
+
+/// The privileged actions that are recorded. Roles can't be changed at
+/// runtime, admins are configured with `admin.usernames` and the audit log
+/// can't tell who edited the configuration.
+#[derive(Debug, Enum, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    DeleteRoom,
+    DeleteMessage,
+    KickUser,
+    BanUser,
+    UnbanUser,
+    LogoutEverywhere,
+}
+
+/// A privileged action, who took it and the event that resulted from it
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    id: Uuid,
+    /// Position in the audit log, starting at 1
+    sequence: i64,
+    recorded_at: OffsetDateTime,
+    action: AuditAction,
+    actor: String,
+    /// What the action was taken on, such as `room:<id>` or `user:<username>`
+    target: String,
+    request_id: Option<String>,
+    client_ip: Option<String>,
+    /// Serialized the same way as on the event stream
+    event: Value,
+}
+
+/// What is stored for every entry, the event is kept in its envelope so that
+/// it is upcast when read back after its schema changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditRecord {
+    envelope: Envelope,
+    action: AuditAction,
+    actor: String,
+    target: String,
+    #[serde(flatten)]
+    origin: RequestOrigin,
+}
+
+impl AuditRecord {
+    /// Read a stored record, including those written before events were
+    /// kept in their envelope. Those lines are shaped like [`AuditEntry`] and
+    /// their events have no schema version, every audited event was still at
+    /// version 1 when that format was replaced.
+    fn from_stored(value: Value) -> serde_json::Result<AuditRecord> {
+        if value.get("envelope").is_some() {
+            return serde_json::from_value(value);
+        }
+
+        let legacy: LegacyAuditRecord = serde_json::from_value(value)?;
+        let envelope = serde_json::from_value(json!({
+            "id": legacy.id,
+            "aggregate_id": AUDIT_LOG_ID,
+            "version": legacy.sequence,
+            "schema_version": 1,
+            "time": legacy.recorded_at,
+            "type": legacy.event.event_type,
+            "payload": legacy.event.payload,
+        }))?;
+
+        Ok(AuditRecord {
+            envelope,
+            action: legacy.action,
+            actor: legacy.actor,
+            target: legacy.target,
+            origin: legacy.origin,
+        })
+    }
+}
+
+/// A record as it was stored before it kept the event in its envelope
+#[derive(Deserialize)]
+struct LegacyAuditRecord {
+    id: Uuid,
+    sequence: i64,
+    recorded_at: Value,
+    action: AuditAction,
+    actor: String,
+    target: String,
+    #[serde(flatten)]
+    origin: RequestOrigin,
+    event: LegacyEvent,
+}
+
+#[derive(Deserialize)]
+struct LegacyEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    payload: Value,
+}
+
+impl From<&AuditRecord> for AuditEntry {
+    fn from(record: &AuditRecord) -> AuditEntry {
+        AuditEntry {
+            id: record.envelope.id,
+            sequence: record.envelope.version,
+            recorded_at: record.envelope.time,
+            action: record.action,
+            actor: record.actor.clone(),
+            target: record.target.clone(),
+            request_id: record.origin.request_id.clone(),
+            client_ip: record.origin.client_ip.clone(),
+            event: json!(record.envelope.event),
+        }
+    }
+}
+
+/// Where a request came from, as recorded in the audit log
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RequestOrigin {
+    pub request_id: Option<String>,
+    /// The address of the connecting peer, forwarding headers are not trusted
+    pub client_ip: Option<String>,
+}
+
+#[async_trait]
+impl<'a> FromRequest<'a> for RequestOrigin {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        Ok(RequestOrigin {
+            request_id: req
+                .extensions()
+                .get::<RequestId>()
+                .map(|request_id| request_id.0.clone()),
+            client_ip: req
+                .remote_addr()
+                .as_socket_addr()
+                .map(|addr| addr.ip().to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct AuditFilter {
+    actor: Option<String>,
+    action: Option<AuditAction>,
+    target: Option<String>,
+    since: Option<OffsetDateTime>,
+    until: Option<OffsetDateTime>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| *actor == entry.actor)
+            && self.action.is_none_or(|action| action == entry.action)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|target| *target == entry.target)
+            && self.since.is_none_or(|since| entry.recorded_at >= since)
+            && self.until.is_none_or(|until| entry.recorded_at < until)
+    }
+}
+
+/// Append-only record of privileged actions. When a file is given every entry
+/// is appended to it as a line of JSON.
+#[derive(Clone)]
+pub struct AuditLog {
+    records: Arc<Mutex<Vec<AuditRecord>>>,
+    file: Option<PathBuf>,
+}
+
+impl AuditLog {
+    pub fn new(file: Option<PathBuf>) -> AuditLog {
+        AuditLog {
+            records: Arc::new(Mutex::new(Vec::new())),
+            file,
+        }
+    }
+
+    /// Load the entries appended by earlier runs
+    pub async fn restore(&self) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let contents = match tokio::fs::read_to_string(file).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        let restored = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).and_then(AuditRecord::from_stored))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        *self.records.lock().await = restored;
+
+        Ok(())
+    }
+
+    pub async fn append(
+        &self,
+        action: AuditAction,
+        actor: &str,
+        target: String,
+        origin: &RequestOrigin,
+        event: DomainEvent,
+    ) -> io::Result<AuditEntry> {
+        // Held while writing so that the file stays in sequence order
+        let mut records = self.records.lock().await;
+
+        let record = AuditRecord {
+            envelope: Envelope::new(AUDIT_LOG_ID, records.len() as i64 + 1, event),
+            action,
+            actor: actor.to_string(),
+            target,
+            origin: origin.clone(),
+        };
+
+        if let Some(file) = &self.file {
+            if let Some(parent) = file.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            let mut line = serde_json::to_string(&record)?;
+            line.push('\n');
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .await?;
+            file.write_all(line.as_bytes()).await?;
+            file.sync_data().await?;
+        }
+
+        let entry = AuditEntry::from(&record);
+        records.push(record);
+
+        Ok(entry)
+    }
+
+    async fn entries(&self, filter: &AuditFilter) -> Vec<AuditEntry> {
+        self.records
+            .lock()
+            .await
+            .iter()
+            .map(AuditEntry::from)
+            .filter(|entry| filter.matches(entry))
+            .collect()
+    }
+}
+
+impl Context {
+    /// Record a privileged action in the audit log before taking it, the
+    /// action must not be taken when this fails
+    pub(crate) async fn audit(
+        &self,
+        action: AuditAction,
+        actor: &str,
+        target: String,
+        origin: &RequestOrigin,
+        event: DomainEvent,
+    ) -> Result<()> {
+        self.audit_log
+            .append(action, actor, target, origin, event)
+            .await
+            .map_err(|error| {
+                tracing::error!(%error, ?action, actor, "Failed to append to the audit log");
+                Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            })?;
+
+        Ok(())
+    }
+
+    /// Execute a privileged command, recording the events it results in
+    /// before they are stored. When the audit log can't be written the command
+    /// is neither stored nor dispatched, rejected commands aren't recorded.
+    pub(crate) async fn execute_audited(
+        &self,
+        action: AuditAction,
+        actor: &str,
+        target: String,
+        origin: &RequestOrigin,
+        command: impl Into<Command>,
+    ) -> Result<()> {
+        let command = command.into();
+        let room = self.load_room(command.room_id()).await;
+        for event in self.commands.handle(&room, command.clone())? {
+            self.audit(action, actor, target.clone(), origin, event)
+                .await?;
+        }
+
+        self.execute_and_dispatch(command).await
+    }
+}
+
+#[derive(ApiResponse)]
+enum ExportResponse {
+    #[oai(status = 200, content_type = "application/x-ndjson")]
+    Ok(
+        PlainText<String>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+}
+
+#[derive(Default)]
+pub struct Api;
+
+#[OpenApi]
+impl Api {
+    /// Privileged actions, oldest first. `since` is inclusive, `until` is
+    /// exclusive.
+    #[oai(
+        path = "/admin/audit",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "admin_audit_get"
+    )]
+    async fn get_audit_log(
+        &self,
+        ctx: Data<&Context>,
+        actor: Query<Option<String>>,
+        action: Query<Option<AuditAction>>,
+        target: Query<Option<String>>,
+        since: Query<Option<OffsetDateTime>>,
+        until: Query<Option<OffsetDateTime>>,
+    ) -> Result<Json<Vec<AuditEntry>>> {
+        let filter = AuditFilter {
+            actor: actor.0,
+            action: action.0,
+            target: target.0,
+            since: since.0,
+            until: until.0,
+        };
+
+        Ok(Json(ctx.audit_log.entries(&filter).await))
+    }
+
+    /// The same entries as `/admin/audit` as JSON lines, one entry per line
+    #[oai(
+        path = "/admin/audit/export",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "admin_audit_export_get"
+    )]
+    async fn export_audit_log(
+        &self,
+        ctx: Data<&Context>,
+        actor: Query<Option<String>>,
+        action: Query<Option<AuditAction>>,
+        target: Query<Option<String>>,
+        since: Query<Option<OffsetDateTime>>,
+        until: Query<Option<OffsetDateTime>>,
+    ) -> Result<ExportResponse> {
+        let filter = AuditFilter {
+            actor: actor.0,
+            action: action.0,
+            target: target.0,
+            since: since.0,
+            until: until.0,
+        };
+
+        let lines = ctx
+            .audit_log
+            .entries(&filter)
+            .await
+            .iter()
+            .map(|entry| entry.to_json_string() + "\n")
+            .collect();
+
+        Ok(ExportResponse::Ok(
+            PlainText(lines),
+            "attachment; filename=\"audit.jsonl\"".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::{Duration, OffsetDateTime};
+    use uuid::Uuid;
+
+    use super::{AuditAction, AuditFilter, AuditLog, RequestOrigin};
+    use crate::events::{DomainEvent, RoomWasRemoved};
+
+    fn room_was_removed() -> DomainEvent {
+        DomainEvent::RoomWasRemoved(RoomWasRemoved {
+            id: Uuid::new_v4(),
+            removed_at: OffsetDateTime::now_utc(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_restore_legacy_records() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", Uuid::new_v4()));
+        let file = dir.join("audit.jsonl");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy("fixtures/audit/audit.v1.jsonl", &file).unwrap();
+
+        let log = AuditLog::new(Some(file));
+        log.restore().await.unwrap();
+        let entries = log.entries(&AuditFilter::default()).await;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::BanUser);
+        assert_eq!(entries[0].client_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(
+            entries[1].target,
+            "room:6d1b1f3c-3c1e-4a53-8a6a-4c1c5e1fbd52"
+        );
+        assert_eq!(
+            entries[1].event["payload"]["id"],
+            "6d1b1f3c-3c1e-4a53-8a6a-4c1c5e1fbd52"
+        );
+
+        // New entries continue the sequence and are stored in the new format
+        let appended = log
+            .append(
+                AuditAction::UnbanUser,
+                "Jane",
+                "user:Mark".to_string(),
+                &RequestOrigin::default(),
+                room_was_removed(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(appended.sequence, 3);
+
+        let restored = AuditLog::new(log.file.clone());
+        restored.restore().await.unwrap();
+        assert_eq!(
+            restored.entries(&AuditFilter::default()).await,
+            log.entries(&AuditFilter::default()).await
+        );
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_filter_and_restore() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", Uuid::new_v4()));
+        let file = dir.join("audit.jsonl");
+        let origin = RequestOrigin {
+            request_id: Some("abc-123".to_string()),
+            client_ip: Some("127.0.0.1".to_string()),
+        };
+
+        let log = AuditLog::new(Some(file.clone()));
+        let first = log
+            .append(
+                AuditAction::DeleteRoom,
+                "Jane",
+                "room:1".to_string(),
+                &origin,
+                room_was_removed(),
+            )
+            .await
+            .unwrap();
+        let second = log
+            .append(
+                AuditAction::BanUser,
+                "John",
+                "user:Mark".to_string(),
+                &RequestOrigin::default(),
+                room_was_removed(),
+            )
+            .await
+            .unwrap();
+        assert_eq!((first.sequence, second.sequence), (1, 2));
+        assert_eq!(first.request_id.as_deref(), Some("abc-123"));
+        assert_eq!(first.event["type"], "RoomWasRemoved");
+
+        let by_actor = AuditFilter {
+            actor: Some("John".to_string()),
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.entries(&by_actor).await, vec![second.clone()]);
+
+        let by_action = AuditFilter {
+            action: Some(AuditAction::DeleteRoom),
+            ..AuditFilter::default()
+        };
+        assert_eq!(log.entries(&by_action).await, vec![first.clone()]);
+
+        let in_the_future = AuditFilter {
+            since: Some(OffsetDateTime::now_utc() + Duration::minutes(1)),
+            ..AuditFilter::default()
+        };
+        assert!(log.entries(&in_the_future).await.is_empty());
+
+        let restored = AuditLog::new(Some(file));
+        restored.restore().await.unwrap();
+        assert_eq!(
+            restored.entries(&AuditFilter::default()).await,
+            vec![first, second]
+        );
+
+        let third = restored
+            .append(
+                AuditAction::UnbanUser,
+                "John",
+                "user:Mark".to_string(),
+                &RequestOrigin::default(),
+                room_was_removed(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(third.sequence, 3);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
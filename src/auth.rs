@@ -4,6 +4,8 @@ use poem::{
 };
 use poem_openapi::Object;
 
+use crate::request_tracing::record_username;
+
 pub struct AuthMiddleware;
 
 #[derive(Object, Clone)]
@@ -34,6 +36,8 @@ impl<E: Endpoint> Endpoint for AuthMiddlewareEndpoint<E> {
 
         match username {
             Some(username) => {
+                record_username(&username);
+
                 // Attach user information to the request extensions
                 req.extensions_mut().insert(AuthData { username });
 
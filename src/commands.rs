@@ -0,0 +1,412 @@
+use poem::{http::StatusCode, Error};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    attachments::Attachment,
+    events::{DomainEvent, MessageWasSend, UserWasMentioned},
+    link_preview::LinkPreview,
+    markdown::{self, MessageFormat},
+    mentions::parse_mentions,
+    room::{RoomAggregate, RoomError},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreateRoom {
+    pub room_id: Uuid,
+    pub name: String,
+    pub username: String,
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveRoom {
+    pub room_id: Uuid,
+    pub removed_by: String,
+    pub removed_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinRoom {
+    pub room_id: Uuid,
+    pub username: String,
+    pub joined_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaveRoom {
+    pub room_id: Uuid,
+    pub username: String,
+    pub left_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeTopic {
+    pub room_id: Uuid,
+    pub topic: String,
+    pub changed_by: String,
+    pub changed_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendMessage {
+    pub room_id: Uuid,
+    pub id: Uuid,
+    pub username: String,
+    pub message: String,
+    pub format: MessageFormat,
+    pub send_at: OffsetDateTime,
+    pub attachments: Vec<Attachment>,
+    /// Sent through an incoming webhook instead of by a user
+    pub bot: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KickUser {
+    pub room_id: Uuid,
+    pub username: String,
+    pub kicked_by: String,
+    pub kicked_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModerateMessage {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub moderated_by: String,
+    pub reason: Option<String>,
+    pub moderated_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadMention {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub username: String,
+    pub read_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddLinkPreview {
+    pub room_id: Uuid,
+    pub message_id: Uuid,
+    pub preview: LinkPreview,
+}
+
+/// Everything that can be done to a room, regardless of whether it was
+/// requested over HTTP, a websocket or by the server itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    CreateRoom(CreateRoom),
+    RemoveRoom(RemoveRoom),
+    JoinRoom(JoinRoom),
+    LeaveRoom(LeaveRoom),
+    ChangeTopic(ChangeTopic),
+    SendMessage(SendMessage),
+    KickUser(KickUser),
+    ModerateMessage(ModerateMessage),
+    ReadMention(ReadMention),
+    AddLinkPreview(AddLinkPreview),
+}
+
+impl Command {
+    /// The room the command applies to
+    pub fn room_id(&self) -> Uuid {
+        match self {
+            Command::CreateRoom(command) => command.room_id,
+            Command::RemoveRoom(command) => command.room_id,
+            Command::JoinRoom(command) => command.room_id,
+            Command::LeaveRoom(command) => command.room_id,
+            Command::ChangeTopic(command) => command.room_id,
+            Command::SendMessage(command) => command.room_id,
+            Command::KickUser(command) => command.room_id,
+            Command::ModerateMessage(command) => command.room_id,
+            Command::ReadMention(command) => command.room_id,
+            Command::AddLinkPreview(command) => command.room_id,
+        }
+    }
+}
+
+impl From<CreateRoom> for Command {
+    fn from(command: CreateRoom) -> Self {
+        Command::CreateRoom(command)
+    }
+}
+
+impl From<RemoveRoom> for Command {
+    fn from(command: RemoveRoom) -> Self {
+        Command::RemoveRoom(command)
+    }
+}
+
+impl From<JoinRoom> for Command {
+    fn from(command: JoinRoom) -> Self {
+        Command::JoinRoom(command)
+    }
+}
+
+impl From<LeaveRoom> for Command {
+    fn from(command: LeaveRoom) -> Self {
+        Command::LeaveRoom(command)
+    }
+}
+
+impl From<ChangeTopic> for Command {
+    fn from(command: ChangeTopic) -> Self {
+        Command::ChangeTopic(command)
+    }
+}
+
+impl From<SendMessage> for Command {
+    fn from(command: SendMessage) -> Self {
+        Command::SendMessage(command)
+    }
+}
+
+impl From<KickUser> for Command {
+    fn from(command: KickUser) -> Self {
+        Command::KickUser(command)
+    }
+}
+
+impl From<ModerateMessage> for Command {
+    fn from(command: ModerateMessage) -> Self {
+        Command::ModerateMessage(command)
+    }
+}
+
+impl From<ReadMention> for Command {
+    fn from(command: ReadMention) -> Self {
+        Command::ReadMention(command)
+    }
+}
+
+impl From<AddLinkPreview> for Command {
+    fn from(command: AddLinkPreview) -> Self {
+        Command::AddLinkPreview(command)
+    }
+}
+
+/// Why a command was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    Room(RoomError),
+    MessageTooLong,
+    /// An attachment was uploaded to a different room than the message
+    /// is sent to
+    ForeignAttachment,
+}
+
+impl From<RoomError> for CommandError {
+    fn from(error: RoomError) -> Self {
+        CommandError::Room(error)
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(error: CommandError) -> Error {
+        match error {
+            CommandError::Room(error) => error.into(),
+            CommandError::MessageTooLong | CommandError::ForeignAttachment => {
+                Error::from_status(StatusCode::BAD_REQUEST)
+            }
+        }
+    }
+}
+
+/// Validates commands against the current state of a room and decides which
+/// events they result in. Handling a command has no side effects, the events
+/// have to be appended to the room to take effect.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandHandler {
+    max_message_length: usize,
+}
+
+impl CommandHandler {
+    pub fn new(max_message_length: usize) -> CommandHandler {
+        CommandHandler { max_message_length }
+    }
+
+    pub fn handle(
+        &self,
+        room: &RoomAggregate,
+        command: Command,
+    ) -> Result<Vec<DomainEvent>, CommandError> {
+        let events = match command {
+            Command::CreateRoom(command) => {
+                room.create(command.name, command.username, command.created_at)?
+            }
+            Command::RemoveRoom(command) => room.remove(command.removed_at)?,
+            Command::JoinRoom(command) => room.join(command.username, command.joined_at)?,
+            Command::LeaveRoom(command) => room.leave(command.username, command.left_at)?,
+            Command::ChangeTopic(command) => {
+                room.change_topic(command.topic, command.changed_by, command.changed_at)?
+            }
+            Command::SendMessage(command) => self.send_message(room, command)?,
+            Command::KickUser(command) => {
+                room.kick(command.username, command.kicked_by, command.kicked_at)?
+            }
+            Command::ModerateMessage(command) => room.moderate_message(
+                command.message_id,
+                command.moderated_by,
+                command.reason,
+                command.moderated_at,
+            )?,
+            Command::ReadMention(command) => {
+                room.read_mention(command.message_id, command.username, command.read_at)?
+            }
+            Command::AddLinkPreview(command) => {
+                room.add_link_preview(command.message_id, command.preview)?
+            }
+        };
+
+        Ok(events)
+    }
+
+    fn send_message(
+        &self,
+        room: &RoomAggregate,
+        command: SendMessage,
+    ) -> Result<Vec<DomainEvent>, CommandError> {
+        if command.message.chars().count() > self.max_message_length {
+            return Err(CommandError::MessageTooLong);
+        }
+
+        // Attachments have to be uploaded to the same room before they can be sent
+        if command
+            .attachments
+            .iter()
+            .any(|attachment| attachment.room_id != command.room_id)
+        {
+            return Err(CommandError::ForeignAttachment);
+        }
+
+        // Users can't get their own attention
+        let mentions = parse_mentions(&command.message)
+            .into_iter()
+            .filter(|mentioned| *mentioned != command.username)
+            .map(|mentioned| UserWasMentioned {
+                message_id: command.id,
+                room_id: command.room_id,
+                username: mentioned,
+                mentioned_by: command.username.clone(),
+                mentioned_at: command.send_at,
+            })
+            .collect();
+
+        let message = MessageWasSend {
+            id: command.id,
+            room_id: command.room_id,
+            rendered_html: markdown::render(command.format, &command.message),
+            username: command.username,
+            message: command.message,
+            format: command.format,
+            send_at: command.send_at,
+            attachments: command.attachments,
+            bot: command.bot,
+        };
+
+        Ok(room.send_message(message, mentions)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{CommandError, CommandHandler, CreateRoom, SendMessage};
+    use crate::{
+        attachments::Attachment,
+        events::{DomainEvent, Envelope},
+        markdown::MessageFormat,
+        room::{RoomAggregate, RoomError},
+    };
+
+    fn created_room(handler: &CommandHandler, room_id: Uuid) -> RoomAggregate {
+        let events = handler
+            .handle(
+                &RoomAggregate::from_history(room_id, &[]),
+                CreateRoom {
+                    room_id,
+                    name: "Lustrum".to_string(),
+                    username: "Jane".to_string(),
+                    created_at: OffsetDateTime::now_utc(),
+                }
+                .into(),
+            )
+            .unwrap();
+        let history = events
+            .into_iter()
+            .zip(1..)
+            .map(|(event, version)| Envelope::new(room_id, version, event))
+            .collect::<Vec<_>>();
+
+        RoomAggregate::from_history(room_id, &history)
+    }
+
+    fn message(room_id: Uuid, message: &str) -> SendMessage {
+        SendMessage {
+            room_id,
+            id: Uuid::new_v4(),
+            username: "Jane".to_string(),
+            message: message.to_string(),
+            format: MessageFormat::Markdown,
+            send_at: OffsetDateTime::now_utc(),
+            attachments: Vec::new(),
+            bot: false,
+        }
+    }
+
+    #[test]
+    fn test_send_message() {
+        let handler = CommandHandler::new(32);
+        let room_id = Uuid::new_v4();
+        let room = created_room(&handler, room_id);
+
+        let events = handler
+            .handle(&room, message(room_id, "**Hi** @John and @Jane").into())
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[0],
+            DomainEvent::MessageWasSend(message)
+                if message.rendered_html.contains("<strong>Hi</strong>")
+        ));
+        assert!(matches!(
+            &events[1],
+            DomainEvent::UserWasMentioned(mention)
+                if mention.username == "John" && mention.mentioned_by == "Jane"
+        ));
+
+        assert_eq!(
+            handler.handle(&room, message(room_id, &"a".repeat(33)).into()),
+            Err(CommandError::MessageTooLong)
+        );
+
+        let mut with_attachment = message(room_id, "See attached");
+        with_attachment.attachments.push(Attachment {
+            id: Uuid::new_v4(),
+            room_id: Uuid::new_v4(),
+            uploaded_by: "Jane".to_string(),
+            filename: "cat.png".to_string(),
+            content_type: "image/png".to_string(),
+            size: 42,
+            hash: "abc".to_string(),
+            thumbnail: None,
+        });
+        assert_eq!(
+            handler.handle(&room, with_attachment.into()),
+            Err(CommandError::ForeignAttachment)
+        );
+
+        let unknown_room = Uuid::new_v4();
+        assert_eq!(
+            handler.handle(
+                &RoomAggregate::from_history(unknown_room, &[]),
+                message(unknown_room, "Hi").into()
+            ),
+            Err(CommandError::Room(RoomError::NotFound))
+        );
+    }
+}
@@ -0,0 +1,718 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use poem::web::cookie::{CookieKey, SameSite};
+use serde::Deserialize;
+use url::Url;
+
+use crate::rate_limit::{self, Limit};
+
+/// Messages are never allowed to be longer than this, regardless of configuration
+pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+/// Rate limits refill at least once a day
+const MAX_REFILL_INTERVAL_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub events: EventsConfig,
+    pub cors: CorsConfig,
+    pub session: SessionConfig,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    /// Configured limits replace the default limit of their operation, the
+    /// other operations keep their default
+    #[serde(deserialize_with = "merge_rate_limits")]
+    pub rate_limits: HashMap<String, RateLimitConfig>,
+    pub log: LogConfig,
+    pub admin: AdminConfig,
+    pub audit: AuditConfig,
+    pub webhooks: WebhooksConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// The url at which the server can be reached by browsers, used in the
+    /// OpenAPI spec
+    pub public_url: String,
+    /// How long open connections get to finish when shutting down
+    pub shutdown_timeout_secs: u64,
+    /// How long clients wait before reconnecting after a shutdown, each event
+    /// stream adds up to the same amount of random jitter
+    pub reconnect_after_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Number of events buffered for slow subscribers before they start
+    /// missing events
+    pub broadcast_capacity: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins besides the one of `server.public_url` that may make
+    /// credentialed requests, such as the development server of the UI
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSiteConfig {
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<SameSiteConfig> for SameSite {
+    fn from(same_site: SameSiteConfig) -> Self {
+        match same_site {
+            SameSiteConfig::Strict => SameSite::Strict,
+            SameSiteConfig::Lax => SameSite::Lax,
+            SameSiteConfig::None => SameSite::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    /// Stored in the data directory so that it survives restarts
+    File,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub same_site: SameSiteConfig,
+    /// Only send the session cookie over https
+    pub secure: bool,
+    /// Key used to sign the session cookie, at least 32 bytes. When missing a
+    /// random key is used and sessions do not survive a restart
+    pub secret: Option<String>,
+    /// Sessions that are not used for this long are logged out
+    pub idle_timeout_secs: u64,
+    /// Sessions are logged out this long after logging in, even when in use
+    pub absolute_timeout_secs: u64,
+    pub store: StoreKind,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub data_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_message_length: usize,
+    pub max_attachment_size: u64,
+    /// How often a command is tried when other changes to the same room are
+    /// stored first
+    pub command_attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub capacity: u32,
+    pub refill_interval_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Used when `RUST_LOG` is not set
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Users that may use the admin api
+    pub usernames: Vec<String>,
+    /// With `file` bans are kept in `bans.json` in the data directory
+    pub store: StoreKind,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// With `file` the log is appended to `audit.jsonl` in the data directory
+    pub store: StoreKind,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// How long a webhook gets to respond to a single delivery attempt
+    pub timeout_secs: u64,
+    /// Deliveries that still fail after this many attempts end up in the
+    /// dead letter list
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every retry after that
+    pub initial_backoff_ms: u64,
+    /// Number of recent delivery attempts kept per webhook
+    pub delivery_log_size: usize,
+    /// Events waiting to be delivered, events are dropped once it is full
+    pub queue_size: usize,
+    /// Deliveries, including their retries, that run at the same time
+    pub max_concurrent_deliveries: usize,
+    /// Number of recent dead letters that are kept
+    pub max_dead_letters: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            events: EventsConfig::default(),
+            cors: CorsConfig::default(),
+            session: SessionConfig::default(),
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+            rate_limits: default_rate_limits(),
+            log: LogConfig::default(),
+            admin: AdminConfig::default(),
+            audit: AuditConfig::default(),
+            webhooks: WebhooksConfig::default(),
+        }
+    }
+}
+
+fn default_rate_limits() -> HashMap<String, RateLimitConfig> {
+    rate_limit::default_limits()
+        .into_iter()
+        .map(|(operation_id, limit)| {
+            (
+                operation_id,
+                RateLimitConfig {
+                    capacity: limit.capacity,
+                    refill_interval_secs: limit.refill_interval.as_secs_f64(),
+                },
+            )
+        })
+        .collect()
+}
+
+fn merge_rate_limits<'de, D>(deserializer: D) -> Result<HashMap<String, RateLimitConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let configured = HashMap::<String, RateLimitConfig>::deserialize(deserializer)?;
+    let mut rate_limits = default_rate_limits();
+
+    for (operation_id, limit) in configured {
+        // Only operations that are rate limited can be configured, so typos
+        // don't go unnoticed
+        if !rate_limits.contains_key(&operation_id) {
+            let mut known = rate_limits.keys().map(String::as_str).collect::<Vec<_>>();
+            known.sort();
+            return Err(serde::de::Error::custom(format!(
+                "unknown rate limited operation `{}`, expected one of {}",
+                operation_id,
+                known.join(", ")
+            )));
+        }
+        rate_limits.insert(operation_id, limit);
+    }
+
+    Ok(rate_limits)
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "0.0.0.0:3000".to_string(),
+            public_url: "http://localhost:3000".to_string(),
+            shutdown_timeout_secs: 10,
+            reconnect_after_ms: 1000,
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            broadcast_capacity: 32,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            same_site: SameSiteConfig::Strict,
+            secure: false,
+            secret: None,
+            idle_timeout_secs: 24 * 60 * 60,
+            absolute_timeout_secs: 7 * 24 * 60 * 60,
+            store: StoreKind::Memory,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            data_dir: PathBuf::from("./data"),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_length: 1024,
+            max_attachment_size: 10 * 1024 * 1024,
+            command_attempts: 3,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            usernames: Vec::new(),
+            store: StoreKind::Memory,
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            store: StoreKind::Memory,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            timeout_secs: 10,
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            delivery_log_size: 100,
+            queue_size: 1024,
+            max_concurrent_deliveries: 16,
+            max_dead_letters: 1000,
+        }
+    }
+}
+
+impl WebhooksConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// How long to wait after the given failed attempt, starting at 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+/// Command line flags, each of these can also be given as an environment
+/// variable and takes precedence over the configuration file
+#[derive(Debug, Default, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(long, env = "CHAT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, env = "CHAT_BIND_ADDRESS")]
+    pub bind_address: Option<String>,
+
+    #[arg(long, env = "CHAT_PUBLIC_URL")]
+    pub public_url: Option<String>,
+
+    /// Comma separated list of origins
+    #[arg(long, env = "CHAT_CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub cors_allowed_origins: Option<Vec<String>>,
+
+    #[arg(long, env = "CHAT_SESSION_SECRET", hide_env_values = true)]
+    pub session_secret: Option<String>,
+
+    #[arg(long, env = "CHAT_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    #[arg(long, env = "CHAT_MAX_MESSAGE_LENGTH")]
+    pub max_message_length: Option<usize>,
+
+    #[arg(long, env = "CHAT_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Comma separated list of usernames
+    #[arg(long, env = "CHAT_ADMINS", value_delimiter = ',')]
+    pub admins: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => {
+                write!(f, "could not read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse(path, error) => {
+                write!(f, "could not parse {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid(errors) => {
+                write!(f, "invalid configuration:")?;
+                for error in errors {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the configuration file (if any) and apply the overrides given on
+    /// the command line or through the environment
+    pub fn load(args: Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(bind_address) = args.bind_address {
+            config.server.bind_address = bind_address;
+        }
+        if let Some(public_url) = args.public_url {
+            config.server.public_url = public_url;
+        }
+        if let Some(allowed_origins) = args.cors_allowed_origins {
+            config.cors.allowed_origins = allowed_origins;
+        }
+        if let Some(secret) = args.session_secret {
+            config.session.secret = Some(secret);
+        }
+        if let Some(data_dir) = args.data_dir {
+            config.storage.data_dir = data_dir;
+        }
+        if let Some(max_message_length) = args.max_message_length {
+            config.limits.max_message_length = max_message_length;
+        }
+        if let Some(log_format) = args.log_format {
+            config.log.format = log_format;
+        }
+        if let Some(admins) = args.admins {
+            config.admin.usernames = admins;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| ConfigError::Read(path.to_path_buf(), error))?;
+
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_path_buf(), error))
+    }
+
+    /// Check all values at once so that every problem is reported at startup
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.server.bind_address.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "server.bind_address \"{}\" is not a valid socket address",
+                self.server.bind_address
+            ));
+        }
+
+        match Url::parse(&self.server.public_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => errors.push(format!(
+                "server.public_url \"{}\" is not a http(s) url",
+                self.server.public_url
+            )),
+        }
+
+        if self.events.broadcast_capacity == 0 {
+            errors.push("events.broadcast_capacity must be at least 1".to_string());
+        }
+
+        for origin in &self.cors.allowed_origins {
+            let is_origin = Url::parse(origin).is_ok_and(|url| {
+                matches!(url.scheme(), "http" | "https")
+                    && url.host().is_some()
+                    && url.path() == "/"
+                    && !origin.ends_with('/')
+            });
+
+            if !is_origin {
+                errors.push(format!(
+                    "cors.allowed_origins \"{}\" is not an origin like https://example.com",
+                    origin
+                ));
+            }
+        }
+
+        if self.session.same_site == SameSiteConfig::None && !self.session.secure {
+            errors.push("session.same_site = \"none\" requires session.secure".to_string());
+        }
+
+        if self
+            .session
+            .secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < 32)
+        {
+            errors.push("session.secret must be at least 32 bytes".to_string());
+        }
+
+        if self.session.idle_timeout_secs == 0
+            || self.session.idle_timeout_secs > self.session.absolute_timeout_secs
+        {
+            errors.push(
+                "session.idle_timeout_secs must be between 1 and session.absolute_timeout_secs"
+                    .to_string(),
+            );
+        }
+
+        if !(1..=MAX_MESSAGE_LENGTH).contains(&self.limits.max_message_length) {
+            errors.push(format!(
+                "limits.max_message_length must be between 1 and {}",
+                MAX_MESSAGE_LENGTH
+            ));
+        }
+
+        if self.limits.max_attachment_size == 0 {
+            errors.push("limits.max_attachment_size must be at least 1".to_string());
+        }
+
+        if self.limits.command_attempts == 0 {
+            errors.push("limits.command_attempts must be at least 1".to_string());
+        }
+
+        if self.webhooks.timeout_secs == 0 {
+            errors.push("webhooks.timeout_secs must be at least 1".to_string());
+        }
+
+        if self.webhooks.max_attempts == 0 {
+            errors.push("webhooks.max_attempts must be at least 1".to_string());
+        }
+
+        if self.webhooks.queue_size == 0 || self.webhooks.max_concurrent_deliveries == 0 {
+            errors.push(
+                "webhooks.queue_size and webhooks.max_concurrent_deliveries must be at least 1"
+                    .to_string(),
+            );
+        }
+
+        for (operation_id, limit) in &self.rate_limits {
+            // Also keeps `Duration::from_secs_f64` from panicking
+            if limit.capacity == 0
+                || !(limit.refill_interval_secs > 0.0
+                    && limit.refill_interval_secs <= MAX_REFILL_INTERVAL_SECS)
+            {
+                errors.push(format!(
+                    "rate_limits.{} needs a capacity above 0 and refill_interval_secs between 0 and {}",
+                    operation_id, MAX_REFILL_INTERVAL_SECS
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    pub fn api_url(&self) -> String {
+        format!("{}/api", self.server.public_url.trim_end_matches('/'))
+    }
+
+    /// Origins that are allowed to make credentialed requests and to change
+    /// state using the session cookie
+    pub fn trusted_origins(&self) -> Vec<String> {
+        let public_origin = Url::parse(&self.server.public_url)
+            .map(|url| url.origin().ascii_serialization())
+            .ok();
+
+        public_origin
+            .into_iter()
+            .chain(self.cors.allowed_origins.iter().cloned())
+            .collect()
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admin.usernames.iter().any(|admin| admin == username)
+    }
+
+    pub fn cookie_key(&self) -> CookieKey {
+        match &self.session.secret {
+            Some(secret) => CookieKey::derive_from(secret.as_bytes()),
+            None => CookieKey::generate(),
+        }
+    }
+
+    pub fn session_file(&self) -> Option<PathBuf> {
+        match self.session.store {
+            StoreKind::Memory => None,
+            StoreKind::File => Some(self.storage.data_dir.join("sessions.json")),
+        }
+    }
+
+    pub fn ban_file(&self) -> Option<PathBuf> {
+        match self.admin.store {
+            StoreKind::Memory => None,
+            StoreKind::File => Some(self.storage.data_dir.join("bans.json")),
+        }
+    }
+
+    pub fn audit_file(&self) -> Option<PathBuf> {
+        match self.audit.store {
+            StoreKind::Memory => None,
+            StoreKind::File => Some(self.storage.data_dir.join("audit.jsonl")),
+        }
+    }
+
+    pub fn rate_limits(&self) -> HashMap<String, Limit> {
+        self.rate_limits
+            .iter()
+            .map(|(operation_id, limit)| {
+                (
+                    operation_id.clone(),
+                    Limit::new(
+                        limit.capacity,
+                        Duration::from_secs_f64(limit.refill_interval_secs),
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Args, Config, ConfigError, SameSiteConfig, WebhooksConfig};
+    use crate::rate_limit::{CREATE_ROOM, SEND_MESSAGE};
+
+    #[test]
+    fn test_parse_and_override() {
+        let mut config: Config = toml::from_str(
+            r#"
+                [server]
+                bind_address = "127.0.0.1:8080"
+
+                [session]
+                same_site = "lax"
+
+                [rate_limits.rooms_post]
+                capacity = 1
+                refill_interval_secs = 0.5
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.bind_address, "127.0.0.1:8080");
+        assert_eq!(config.server.public_url, "http://localhost:3000");
+        assert_eq!(config.session.same_site, SameSiteConfig::Lax);
+        assert!(config.validate().is_ok());
+
+        // Only the configured limit changes, the others keep their default
+        let defaults = Config::default().rate_limits;
+        assert_eq!(config.rate_limits.len(), defaults.len());
+        assert_eq!(config.rate_limits[CREATE_ROOM].capacity, 1);
+        assert_eq!(config.rate_limits[SEND_MESSAGE], defaults[SEND_MESSAGE]);
+
+        assert!(toml::from_str::<Config>("[server]\nport = 3000").is_err());
+        assert!(toml::from_str::<Config>(
+            "[rate_limits.rooms_pots]\ncapacity = 1\nrefill_interval_secs = 1"
+        )
+        .is_err());
+
+        for refill_interval_secs in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e20] {
+            config
+                .rate_limits
+                .get_mut(CREATE_ROOM)
+                .unwrap()
+                .refill_interval_secs = refill_interval_secs;
+            assert!(config.validate().is_err(), "{}", refill_interval_secs);
+        }
+    }
+
+    #[test]
+    fn test_example_matches_defaults() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_validation_reports_all_errors() {
+        let config = Config::load(Args {
+            bind_address: Some("localhost".to_string()),
+            cors_allowed_origins: Some(vec![
+                "https://chat.example.com".to_string(),
+                "https://chat.example.com/path".to_string(),
+            ]),
+            max_message_length: Some(0),
+            ..Args::default()
+        });
+
+        let Err(ConfigError::Invalid(errors)) = config else {
+            panic!("Expected the configuration to be invalid");
+        };
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+    }
+
+    #[test]
+    fn test_webhook_backoff() {
+        let config = WebhooksConfig::default();
+
+        assert_eq!(config.backoff(1), Duration::from_secs(1));
+        assert_eq!(config.backoff(2), Duration::from_secs(2));
+        assert_eq!(config.backoff(4), Duration::from_secs(8));
+    }
+}
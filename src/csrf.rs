@@ -0,0 +1,168 @@
+use std::{collections::HashSet, sync::Arc};
+
+use poem::{
+    async_trait,
+    http::{header, HeaderMap, Method, StatusCode},
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+/// Rejects cross-site requests that could change state using the session
+/// cookie of the user.
+///
+/// Browsers send an `Origin` header (and recent ones `Sec-Fetch-Site`) with
+/// every non-GET request, these have to match one of the trusted origins.
+/// Requests without either header do not come from a browser and requests that
+/// authenticate with a bearer token can not be forged by another site, so both
+/// are let through. Websocket handshakes are checked as well since they are
+/// plain GET requests that carry the session cookie.
+pub struct CsrfProtection {
+    trusted_origins: Arc<HashSet<String>>,
+}
+
+impl CsrfProtection {
+    pub fn new(trusted_origins: impl IntoIterator<Item = String>) -> CsrfProtection {
+        CsrfProtection {
+            trusted_origins: Arc::new(trusted_origins.into_iter().collect()),
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for CsrfProtection {
+    type Output = CsrfProtectionEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        CsrfProtectionEndpoint {
+            ep,
+            trusted_origins: self.trusted_origins.clone(),
+        }
+    }
+}
+
+pub struct CsrfProtectionEndpoint<E> {
+    ep: E,
+    trusted_origins: Arc<HashSet<String>>,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for CsrfProtectionEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if needs_check(&req) && !self.is_trusted(req.headers()) {
+            let origin = req.headers().get(header::ORIGIN);
+            tracing::warn!(?origin, "Rejected cross-site request");
+
+            return Ok(StatusCode::FORBIDDEN.into_response());
+        }
+
+        Ok(self.ep.call(req).await?.into_response())
+    }
+}
+
+impl<E> CsrfProtectionEndpoint<E> {
+    fn is_trusted(&self, headers: &HeaderMap) -> bool {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(origin) = header(header::ORIGIN.as_str()) {
+            return self.trusted_origins.contains(origin);
+        }
+
+        // Without an origin we have to rely on the fetch metadata
+        match header(SEC_FETCH_SITE) {
+            Some(site) => matches!(site, "same-origin" | "none"),
+            None => true,
+        }
+    }
+}
+
+fn needs_check(req: &Request) -> bool {
+    let is_bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "));
+    if is_bearer {
+        return false;
+    }
+
+    let is_safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let is_websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"));
+
+    !is_safe || is_websocket
+}
+
+#[cfg(test)]
+mod test {
+    use poem::{
+        get, handler,
+        http::{header, StatusCode},
+        test::TestClient,
+        EndpointExt, Route,
+    };
+
+    use super::{CsrfProtection, SEC_FETCH_SITE};
+
+    #[handler]
+    fn index() -> &'static str {
+        "Hoi"
+    }
+
+    #[tokio::test]
+    async fn test_csrf_protection() {
+        let app = Route::new()
+            .at("/", get(index).post(index))
+            .with(CsrfProtection::new(["http://localhost:3000".to_string()]));
+        let client = TestClient::new(app);
+
+        let post = |headers: &[(&str, &str)]| {
+            let mut req = client.post("/");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.send()
+        };
+
+        post(&[]).await.assert_status_is_ok();
+        post(&[("origin", "http://localhost:3000")])
+            .await
+            .assert_status_is_ok();
+        post(&[(SEC_FETCH_SITE, "same-origin")])
+            .await
+            .assert_status_is_ok();
+        post(&[
+            ("origin", "https://evil.example.com"),
+            ("authorization", "Bearer token"),
+        ])
+        .await
+        .assert_status_is_ok();
+
+        post(&[("origin", "https://evil.example.com")])
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        post(&[("origin", "null")])
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        post(&[(SEC_FETCH_SITE, "cross-site")])
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        client
+            .get("/")
+            .header("origin", "https://evil.example.com")
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .get("/")
+            .header("origin", "https://evil.example.com")
+            .header(header::UPGRADE, "websocket")
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
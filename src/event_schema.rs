@@ -0,0 +1,120 @@
+use poem_openapi::{registry::Registry, Object};
+use serde_json::{json, Map, Value};
+
+use crate::events::EventPayload;
+
+/// One kind of event sent on the event streams, also used as the name of the
+/// server sent event
+#[derive(Debug, Object, Clone, PartialEq)]
+pub struct EventSchema {
+    name: String,
+    version: u32,
+    description: Option<String>,
+    /// JSON Schema of the event as sent on the event streams
+    schema: Value,
+}
+
+impl EventSchema {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Describe the payload of one event type, see [`DomainEvent::schemas`]
+///
+/// [`DomainEvent::schemas`]: crate::events::DomainEvent::schemas
+pub fn event_schema<T: EventPayload>() -> EventSchema {
+    let mut registry = Registry::new();
+    T::register(&mut registry);
+
+    let name = T::name().into_owned();
+    let description = registry
+        .schemas
+        .get(&name)
+        .and_then(|schema| schema.description)
+        .map(str::to_string);
+    let definitions = registry
+        .schemas
+        .iter()
+        .map(|(name, schema)| (name.clone(), to_json_schema(json!(schema))))
+        .collect::<Map<_, _>>();
+
+    let schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": name,
+        "description": description,
+        "type": "object",
+        "required": ["type", "payload"],
+        "properties": {
+            "type": { "const": name },
+            "payload": { "$ref": format!("#/$defs/{}", name) },
+        },
+        "$defs": definitions,
+    });
+
+    EventSchema {
+        name,
+        version: T::VERSION,
+        description,
+        schema,
+    }
+}
+
+/// Point references to OpenAPI components at the definitions included in the
+/// schema itself
+fn to_json_schema(mut value: Value) -> Value {
+    match &mut value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match (key.as_str(), value) {
+                    ("$ref", Value::String(reference)) => {
+                        if let Some(name) = reference.strip_prefix("#/components/schemas/") {
+                            *reference = format!("#/$defs/{}", name);
+                        }
+                    }
+                    (_, value) => *value = to_json_schema(value.take()),
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                *item = to_json_schema(item.take());
+            }
+        }
+        _ => {}
+    }
+
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::event_schema;
+    use crate::events::{DomainEvent, MessageLinkPreviewAdded};
+
+    #[test]
+    fn test_event_schema() {
+        let schema = event_schema::<MessageLinkPreviewAdded>();
+        assert_eq!(schema.name, "MessageLinkPreviewAdded");
+        assert_eq!(schema.version, 1);
+        assert_eq!(
+            schema.description.as_deref(),
+            Some("A preview was fetched for the first link in a message")
+        );
+
+        let definitions = &schema.schema["$defs"];
+        assert_eq!(
+            definitions["MessageLinkPreviewAdded"]["properties"]["preview"]["$ref"],
+            json!("#/$defs/LinkPreview")
+        );
+        assert!(definitions["LinkPreview"].is_object());
+
+        let names = DomainEvent::schemas()
+            .into_iter()
+            .map(|schema| schema.name)
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"ServerShuttingDown".to_string()));
+    }
+}
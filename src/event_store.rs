@@ -0,0 +1,191 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::events::{DomainEvent, Envelope};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub aggregate_id: Uuid,
+    pub expected: i64,
+    pub actual: i64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected {} to be at version {} but it is at version {}",
+            self.aggregate_id, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+#[derive(Default)]
+struct Streams {
+    /// Every envelope in the order it was appended
+    envelopes: Vec<Envelope>,
+    /// Positions in `envelopes` per aggregate
+    positions: HashMap<Uuid, Vec<usize>>,
+}
+
+/// Keeps the events of every aggregate in the order they were appended, each
+/// wrapped in an envelope that numbers it within its aggregate
+#[derive(Clone, Default)]
+pub struct EventStore {
+    streams: Arc<Mutex<Streams>>,
+}
+
+impl EventStore {
+    /// The events of an aggregate after `version`, to bring an aggregate that
+    /// was loaded before up to date
+    pub async fn load_since(&self, aggregate_id: Uuid, version: i64) -> Vec<Envelope> {
+        let streams = self.streams.lock().await;
+
+        streams
+            .positions
+            .get(&aggregate_id)
+            .and_then(|positions| positions.get(version.max(0) as usize..))
+            .map(|positions| {
+                positions
+                    .iter()
+                    .map(|position| streams.envelopes[*position].clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The events of all aggregates that were appended after the first
+    /// `position` events, in the order they were appended
+    pub async fn since(&self, position: usize) -> Vec<Envelope> {
+        self.streams
+            .lock()
+            .await
+            .envelopes
+            .get(position..)
+            .map(<[Envelope]>::to_vec)
+            .unwrap_or_default()
+    }
+
+    /// Append events to an aggregate, unless another append happened since
+    /// `expected_version` was loaded. Aggregates without events are at
+    /// version 0.
+    pub async fn append(
+        &self,
+        aggregate_id: Uuid,
+        expected_version: i64,
+        events: Vec<DomainEvent>,
+    ) -> Result<Vec<Envelope>, VersionConflict> {
+        let mut streams = self.streams.lock().await;
+        let Streams {
+            envelopes: all,
+            positions,
+        } = &mut *streams;
+        let positions = positions.entry(aggregate_id).or_default();
+        let actual = positions.len() as i64;
+
+        if expected_version != actual {
+            return Err(VersionConflict {
+                aggregate_id,
+                expected: expected_version,
+                actual,
+            });
+        }
+
+        let envelopes = events
+            .into_iter()
+            .zip(actual + 1..)
+            .map(|(event, version)| Envelope::new(aggregate_id, version, event))
+            .collect::<Vec<_>>();
+        positions.extend(all.len()..all.len() + envelopes.len());
+        all.extend(envelopes.iter().cloned());
+
+        Ok(envelopes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{EventStore, VersionConflict};
+    use crate::events::{DomainEvent, RoomWasCreated, RoomWasRemoved};
+
+    #[tokio::test]
+    async fn test_append_with_expected_version() {
+        let store = EventStore::default();
+        let id = Uuid::new_v4();
+        let created = DomainEvent::RoomWasCreated(RoomWasCreated {
+            id,
+            name: "Lustrum Crash & Compile".to_string(),
+            created_at: OffsetDateTime::now_utc(),
+        });
+        let removed = DomainEvent::RoomWasRemoved(RoomWasRemoved {
+            id,
+            removed_at: OffsetDateTime::now_utc(),
+        });
+
+        let envelopes = store.append(id, 0, vec![created.clone()]).await.unwrap();
+        assert_eq!(envelopes[0].version, 1);
+        assert_eq!(envelopes[0].aggregate_id, id);
+
+        // Someone else appended since version 0 was loaded
+        let conflict = store
+            .append(id, 0, vec![removed.clone()])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            conflict,
+            VersionConflict {
+                aggregate_id: id,
+                expected: 0,
+                actual: 1,
+            }
+        );
+
+        let other = Uuid::new_v4();
+        let other_created = DomainEvent::RoomWasCreated(RoomWasCreated {
+            id: other,
+            name: "Borrel".to_string(),
+            created_at: OffsetDateTime::now_utc(),
+        });
+        store
+            .append(other, 0, vec![other_created.clone()])
+            .await
+            .unwrap();
+
+        store.append(id, 1, vec![removed.clone()]).await.unwrap();
+        let history = store.load_since(id, 0).await;
+        assert_eq!(
+            history
+                .iter()
+                .map(|envelope| (envelope.version, envelope.event.clone()))
+                .collect::<Vec<_>>(),
+            vec![(1, created.clone()), (2, removed.clone())]
+        );
+        assert_eq!(store.load_since(id, 1).await, history[1..]);
+        assert!(store.load_since(id, 2).await.is_empty());
+
+        let all = store.since(0).await;
+        assert_eq!(
+            all.into_iter()
+                .map(|envelope| envelope.event)
+                .collect::<Vec<_>>(),
+            vec![created, other_created, removed.clone()]
+        );
+        assert_eq!(
+            store
+                .since(2)
+                .await
+                .into_iter()
+                .map(|envelope| envelope.event)
+                .collect::<Vec<_>>(),
+            vec![removed]
+        );
+        assert!(store.since(4).await.is_empty());
+    }
+}
@@ -1,32 +1,50 @@
 use futures_util::{stream::BoxStream, StreamExt};
 use poem::async_trait;
+use poem::session::Session;
+use poem::web::sse::Event;
 use poem::web::Data;
 use poem::Result;
 use poem_openapi::payload::Json;
+use poem_openapi::types::{ParseFromJSON, ToJSON, Type};
 use poem_openapi::Union;
 use poem_openapi::{payload::EventStream, Object, OpenApi};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use serde_json::Value;
+use rand::Rng;
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::fmt;
 use std::sync::Arc;
 use time::OffsetDateTime;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::{sync::Mutex, time::Duration};
 use uuid::Uuid;
 
+use crate::attachments::Attachment;
+use crate::auth::{protect, AuthData};
+use crate::commands::CreateRoom;
+use crate::event_schema::{event_schema, EventSchema};
+use crate::link_preview::LinkPreview;
+use crate::markdown::MessageFormat;
+use crate::metrics::Subscription;
+use crate::rate_limit::limit_generate_events;
+use crate::sessions::session_id;
+use crate::subscriptions::{
+    EventFilter, EventSubscription, Listener, Listeners, SubscriptionHandle,
+};
+use crate::upcasting::UpcasterRegistry;
 use crate::Context;
 
+/// A user started a session
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct UserLoggedIn {
     pub username: String,
 }
 
+/// A user ended their session, or all of their sessions were revoked
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct UserLoggedOut {
     pub username: String,
 }
 
+/// A new room that users can join
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct RoomWasCreated {
     pub id: Uuid,
@@ -34,12 +52,14 @@ pub struct RoomWasCreated {
     pub created_at: OffsetDateTime,
 }
 
+/// A room was deleted together with its messages
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct RoomWasRemoved {
     pub id: Uuid,
     pub removed_at: OffsetDateTime,
 }
 
+/// A user became a member of a room
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct UserJoinedRoom {
     pub room_id: Uuid,
@@ -47,6 +67,7 @@ pub struct UserJoinedRoom {
     pub joined_at: OffsetDateTime,
 }
 
+/// A user is no longer a member of a room
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct UserLeftRoom {
     pub room_id: Uuid,
@@ -54,111 +75,509 @@ pub struct UserLeftRoom {
     pub left_at: OffsetDateTime,
 }
 
+/// The topic of a room was changed, an empty topic clears it
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct RoomTopicWasChanged {
+    pub room_id: Uuid,
+    pub topic: String,
+    pub changed_by: String,
+    pub changed_at: OffsetDateTime,
+}
+
+/// A message was sent to a room
 #[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
 pub struct MessageWasSend {
     pub id: Uuid,
     pub room_id: Uuid,
     pub username: String,
     pub message: String,
+    pub format: MessageFormat,
+    /// Sanitised html version of the message, safe to insert into the page
+    pub rendered_html: String,
     pub send_at: OffsetDateTime,
+    /// Files that were uploaded to the room before sending the message
+    pub attachments: Vec<Attachment>,
+    /// Posted through an incoming webhook, the username is the name of the
+    /// webhook rather than a user
+    pub bot: bool,
+}
+
+/// A message mentioned a user, only sent to the mentioned user
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct UserWasMentioned {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub username: String,
+    pub mentioned_by: String,
+    pub mentioned_at: OffsetDateTime,
 }
 
-#[derive(Debug, Clone, Serialize, PartialEq, Eq, Union)]
-#[serde(tag = "type", content = "payload")]
-pub enum DomainEvent {
-    UserLoggedIn(UserLoggedIn),
-    UserLoggedOut(UserLoggedOut),
-    RoomWasCreated(RoomWasCreated),
-    RoomWasRemoved(RoomWasRemoved),
+/// A user read a mention, only sent to that user
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct MentionWasRead {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub username: String,
+    pub read_at: OffsetDateTime,
+}
 
-    UserJoinedRoom(UserJoinedRoom),
-    UserLeftRoom(UserLeftRoom),
-    MessageWasSend(MessageWasSend),
+/// A preview was fetched for the first link in a message
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct MessageLinkPreviewAdded {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub preview: LinkPreview,
 }
 
-#[allow(dead_code)]
-struct Envelope {
+/// A user is typing in a room, clients should hide the indicator again after
+/// a few seconds
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct UserStartedTyping {
+    pub room_id: Uuid,
+    pub username: String,
+    pub started_at: OffsetDateTime,
+}
+
+/// Answer to a slash command, only sent to the user that used the command and
+/// never stored
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct SlashCommandReplied {
+    pub room_id: Uuid,
+    pub username: String,
+    /// The message that contained the command
+    pub command: String,
+    pub reply: String,
+    pub replied_at: OffsetDateTime,
+}
+
+/// A user was banned by an admin, their sessions and event streams are ended
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct UserWasBanned {
+    pub username: String,
+    pub banned_by: String,
+    pub reason: Option<String>,
+    pub banned_at: OffsetDateTime,
+}
+
+/// A ban was lifted by an admin
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct UserWasUnbanned {
+    pub username: String,
+    pub unbanned_by: String,
+    pub unbanned_at: OffsetDateTime,
+}
+
+/// An admin removed a user from a room
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct UserWasKicked {
+    pub room_id: Uuid,
+    pub username: String,
+    pub kicked_by: String,
+    pub kicked_at: OffsetDateTime,
+}
+
+/// A message was removed by an admin
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct MessageWasModerated {
+    pub message_id: Uuid,
+    pub room_id: Uuid,
+    pub moderated_by: String,
+    pub reason: Option<String>,
+    pub moderated_at: OffsetDateTime,
+}
+
+/// Sessions of a user were revoked by logging out, ending their event
+/// streams. Only sent to the user.
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct SessionWasRevoked {
+    pub username: String,
+    /// The revoked session, every session of the user was revoked when empty
+    pub session_id: Option<Uuid>,
+}
+
+/// Sent right before the server stops, after which event streams are closed
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct ServerShuttingDown {
+    /// Clients should wait at least this long before reconnecting, plus some
+    /// random jitter so that they do not all reconnect at once
+    pub reconnect_after_ms: u64,
+}
+
+/// Defines [`DomainEvent`] with a variant per payload type, named after it,
+/// together with everything that has to list every variant. Adding a payload
+/// here is all that is needed to store, decode and describe it.
+macro_rules! domain_events {
+    ($($variant:ident),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Union)]
+        pub enum DomainEvent {
+            $($variant($variant),)*
+        }
+
+        impl DomainEvent {
+            /// Name of the variant, which is also used as `type` when serialized
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(DomainEvent::$variant(_) => stringify!($variant),)*
+                }
+            }
+
+            /// Schema version of the payload
+            pub fn version(&self) -> u32 {
+                match self {
+                    $(DomainEvent::$variant(_) => <$variant as EventPayload>::VERSION,)*
+                }
+            }
+
+            /// Schemas of every event type, in the same order as the variants
+            pub fn schemas() -> Vec<EventSchema> {
+                vec![$(event_schema::<$variant>(),)*]
+            }
+
+            /// Read a payload stored with the current schema version
+            fn decode_current(
+                event_type: &str,
+                version: u32,
+                payload: Value,
+            ) -> std::result::Result<DomainEvent, DecodeError> {
+                match event_type {
+                    $(stringify!($variant) => Ok(DomainEvent::$variant(decode_payload(
+                        event_type, version, payload,
+                    )?)),)*
+                    _ => Err(DecodeError::UnknownEventType(event_type.to_string())),
+                }
+            }
+        }
+    };
+}
+
+domain_events!(
+    UserLoggedIn,
+    UserLoggedOut,
+    RoomWasCreated,
+    RoomWasRemoved,
+    UserJoinedRoom,
+    UserLeftRoom,
+    RoomTopicWasChanged,
+    MessageWasSend,
+    UserWasMentioned,
+    MentionWasRead,
+    MessageLinkPreviewAdded,
+    UserStartedTyping,
+    SlashCommandReplied,
+    UserWasBanned,
+    UserWasUnbanned,
+    UserWasKicked,
+    MessageWasModerated,
+    SessionWasRevoked,
+    ServerShuttingDown,
+);
+
+/// Serialized as `{"type": ..., "payload": ...}` where the payload matches
+/// the OpenAPI schema of the variant, including RFC 3339 dates
+impl Serialize for DomainEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut event = serializer.serialize_struct("DomainEvent", 2)?;
+        event.serialize_field("type", self.name())?;
+        event.serialize_field("payload", &self.to_json())?;
+        event.end()
+    }
+}
+
+/// Implemented by the payload of every [`DomainEvent`] variant
+pub trait EventPayload: Type {
+    /// Incremented whenever the payload changes in a way that is not
+    /// compatible with earlier versions
+    const VERSION: u32 = 1;
+}
+
+impl EventPayload for UserLoggedIn {}
+impl EventPayload for UserLoggedOut {}
+impl EventPayload for RoomWasCreated {}
+impl EventPayload for RoomWasRemoved {}
+impl EventPayload for UserJoinedRoom {}
+impl EventPayload for UserLeftRoom {}
+impl EventPayload for RoomTopicWasChanged {}
+impl EventPayload for MessageWasSend {
+    /// Version 2 added `format` and `rendered_html`, version 3 added
+    /// `attachments` and version 4 added `bot`
+    const VERSION: u32 = 4;
+}
+impl EventPayload for UserWasMentioned {}
+impl EventPayload for MentionWasRead {}
+impl EventPayload for MessageLinkPreviewAdded {}
+impl EventPayload for UserStartedTyping {}
+impl EventPayload for SlashCommandReplied {}
+impl EventPayload for UserWasBanned {}
+impl EventPayload for UserWasUnbanned {}
+impl EventPayload for UserWasKicked {}
+impl EventPayload for MessageWasModerated {}
+impl EventPayload for SessionWasRevoked {}
+impl EventPayload for ServerShuttingDown {}
+
+impl DomainEvent {
+    /// The room this event belongs to, if any
+    pub fn room_id(&self) -> Option<Uuid> {
+        match self {
+            DomainEvent::UserLoggedIn(_)
+            | DomainEvent::UserLoggedOut(_)
+            | DomainEvent::UserWasBanned(_)
+            | DomainEvent::UserWasUnbanned(_)
+            | DomainEvent::SessionWasRevoked(_)
+            | DomainEvent::ServerShuttingDown(_) => None,
+            DomainEvent::RoomWasCreated(event) => Some(event.id),
+            DomainEvent::RoomWasRemoved(event) => Some(event.id),
+            DomainEvent::UserJoinedRoom(event) => Some(event.room_id),
+            DomainEvent::UserLeftRoom(event) => Some(event.room_id),
+            DomainEvent::RoomTopicWasChanged(event) => Some(event.room_id),
+            DomainEvent::MessageWasSend(event) => Some(event.room_id),
+            DomainEvent::UserWasMentioned(event) => Some(event.room_id),
+            DomainEvent::MentionWasRead(event) => Some(event.room_id),
+            DomainEvent::MessageLinkPreviewAdded(event) => Some(event.room_id),
+            DomainEvent::UserStartedTyping(event) => Some(event.room_id),
+            DomainEvent::SlashCommandReplied(event) => Some(event.room_id),
+            DomainEvent::UserWasKicked(event) => Some(event.room_id),
+            DomainEvent::MessageWasModerated(event) => Some(event.room_id),
+        }
+    }
+
+    /// Read a stored payload, upcasting it first when it was stored with an
+    /// older schema version
+    pub fn decode(
+        event_type: &str,
+        version: u32,
+        payload: Value,
+        upcasters: &UpcasterRegistry,
+    ) -> std::result::Result<DomainEvent, DecodeError> {
+        let (version, payload) = upcasters.upcast(event_type, version, payload);
+
+        DomainEvent::decode_current(event_type, version, payload)
+    }
+
+    /// Whether the given user is allowed to receive this event.
+    /// Mentions, replies to slash commands and revoked sessions are private to
+    /// the user they are meant for, all other events are visible to everyone.
+    pub fn is_visible_to(&self, username: Option<&str>) -> bool {
+        match self {
+            DomainEvent::UserWasMentioned(event) => username == Some(event.username.as_str()),
+            DomainEvent::MentionWasRead(event) => username == Some(event.username.as_str()),
+            DomainEvent::SlashCommandReplied(event) => username == Some(event.username.as_str()),
+            DomainEvent::SessionWasRevoked(event) => username == Some(event.username.as_str()),
+            _ => true,
+        }
+    }
+
+    /// Whether this event may end event streams, these must reach every
+    /// stream even when it lags behind
+    pub fn ends_streams(&self) -> bool {
+        matches!(
+            self,
+            DomainEvent::ServerShuttingDown(_)
+                | DomainEvent::UserWasBanned(_)
+                | DomainEvent::SessionWasRevoked(_)
+        )
+    }
+
+    /// Whether the event stream of the given user and session should be
+    /// closed after sending this event
+    pub fn ends_stream_of(&self, username: Option<&str>, session_id: Uuid) -> bool {
+        match self {
+            DomainEvent::ServerShuttingDown(_) => true,
+            DomainEvent::UserWasBanned(event) => username == Some(event.username.as_str()),
+            DomainEvent::SessionWasRevoked(event) => {
+                username == Some(event.username.as_str())
+                    && event.session_id.is_none_or(|revoked| revoked == session_id)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn decode_payload<T: EventPayload + ParseFromJSON>(
+    event_type: &str,
+    version: u32,
+    payload: Value,
+) -> std::result::Result<T, DecodeError> {
+    if version != T::VERSION {
+        return Err(DecodeError::UnsupportedVersion {
+            event_type: event_type.to_string(),
+            version,
+        });
+    }
+
+    T::parse_from_json(Some(payload)).map_err(|error| DecodeError::Invalid(error.into_message()))
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Invalid(String),
+    UnknownEventType(String),
+    /// The event was stored with a schema version that can not be upcast to
+    /// the current one
+    UnsupportedVersion {
+        event_type: String,
+        version: u32,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Invalid(error) => write!(f, "invalid event: {}", error),
+            DecodeError::UnknownEventType(event_type) => {
+                write!(f, "unknown event type {}", event_type)
+            }
+            DecodeError::UnsupportedVersion {
+                event_type,
+                version,
+            } => write!(f, "unsupported version {} of {}", version, event_type),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Metadata recorded alongside an event once it is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: Uuid,
+    pub aggregate_id: Uuid,
+    /// Position of the event within its aggregate, starting at 1
+    pub version: i64,
+    /// Version of the schema of the event payload
+    pub schema_version: u32,
+    pub event: DomainEvent,
+    pub time: OffsetDateTime,
+}
+
+/// The stored form of an envelope, before the payload is upcast
+#[derive(Deserialize)]
+struct StoredEnvelope {
     id: Uuid,
     aggregate_id: Uuid,
     version: i64,
-    event: DomainEvent,
-    time: OffsetDateTime,
+    schema_version: u32,
+    time: Value,
+    #[serde(rename = "type")]
+    event_type: String,
+    payload: Value,
+}
+
+impl Envelope {
+    pub fn new(aggregate_id: Uuid, version: i64, event: DomainEvent) -> Envelope {
+        Envelope {
+            id: Uuid::new_v4(),
+            aggregate_id,
+            version,
+            schema_version: event.version(),
+            event,
+            time: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "aggregate_id": self.aggregate_id,
+            "version": self.version,
+            "schema_version": self.schema_version,
+            "time": self.time.to_json(),
+            "type": self.event.name(),
+            "payload": self.event.to_json(),
+        })
+    }
+
+    /// Read a stored envelope, upcasting the event to its current schema
+    pub fn from_json(
+        value: Value,
+        upcasters: &UpcasterRegistry,
+    ) -> std::result::Result<Envelope, DecodeError> {
+        let stored: StoredEnvelope = serde_json::from_value(value)
+            .map_err(|error| DecodeError::Invalid(error.to_string()))?;
+        let time = OffsetDateTime::parse_from_json(Some(stored.time))
+            .map_err(|error| DecodeError::Invalid(error.into_message()))?;
+        let event = DomainEvent::decode(
+            &stored.event_type,
+            stored.schema_version,
+            stored.payload,
+            upcasters,
+        )?;
+
+        Ok(Envelope {
+            id: stored.id,
+            aggregate_id: stored.aggregate_id,
+            version: stored.version,
+            schema_version: event.version(),
+            event,
+            time,
+        })
+    }
+}
+
+impl Serialize for Envelope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+/// Deserializing upcasts with the default upcasters
+impl<'de> Deserialize<'de> for Envelope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        Envelope::from_json(value, &UpcasterRegistry::default()).map_err(de::Error::custom)
+    }
 }
 
 #[async_trait]
 pub trait EventBus {
     async fn dispatch_event(&self, event: DomainEvent);
 
-    // TODO: look into changing this so that it receives a listener callbak instead
-    // This would change the intervace to return nothing so that the same trait
-    // can be used for our BroadcastingEventBus and RecordingEventBus
-    async fn subscribe(&self) -> Option<Receiver<DomainEvent>>;
+    /// Call `listener` for every event that matches `filter` and is dispatched
+    /// from now on, until the returned handle is dropped
+    async fn subscribe(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle;
+
+    /// Whether events can currently be dispatched
+    async fn is_ready(&self) -> bool {
+        true
+    }
 }
 
 pub type ShareableEventBus = Arc<dyn EventBus + std::marker::Sync + std::marker::Send + 'static>;
 
-#[derive(Clone)]
+/// Hands every event to the listeners that are subscribed at that moment
+#[derive(Clone, Default)]
 pub struct BroadcastingEventBus {
-    bus: Arc<Mutex<broadcast::Sender<DomainEvent>>>,
-}
-
-impl BroadcastingEventBus {
-    /// Creates a EventBus out of a broadcast channel that can be used to
-    /// dispatch and subscribe to domain events
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use tokio::sync::Mutex;
-    ///
-    /// #[tokio::main]
-    /// async fn main() {
-    ///     let (tx, _rx) = broadcast::channel::<DomainEvent>(32);
-    ///     let bus = EventBus::from_broadcast(tx);
-    /// }
-    /// ```
-    pub fn from_broadcast(tx: Sender<DomainEvent>) -> BroadcastingEventBus {
-        BroadcastingEventBus {
-            bus: Arc::new(Mutex::new(tx)),
-        }
-    }
+    listeners: Listeners,
 }
 
 #[async_trait]
 impl EventBus for BroadcastingEventBus {
+    #[tracing::instrument(
+        name = "dispatch_event",
+        skip_all,
+        fields(event = event.name(), room_id = ?event.room_id())
+    )]
     async fn dispatch_event(&self, event: DomainEvent) {
-        let bus = self.bus.lock().await;
-
-        if let Err(send_error) = bus.send(event.clone()) {
-            println!(
-                "Something wrong while dispatching event: {:?}: {:?}",
-                event, send_error
-            );
-        };
+        self.listeners.notify(&event);
+        tracing::debug!(subscribers = self.listeners.len(), "dispatched event");
     }
 
-    async fn subscribe(&self) -> Option<Receiver<DomainEvent>> {
-        Some(self.bus.lock().await.subscribe())
+    async fn subscribe(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle {
+        self.listeners.add(filter, listener)
     }
 }
 
-// Not used yet, this event bus can be used in our tests so that we only record
-// the events that are being produced
+/// Records the events that are dispatched so that tests can assert on them,
+/// while still delivering them to subscribers
+#[allow(dead_code)]
 #[derive(Clone, Default)]
 pub struct RecordingEventBus {
     recorded_events: Arc<Mutex<Vec<DomainEvent>>>,
+    listeners: Listeners,
 }
 
 #[allow(dead_code)]
 impl RecordingEventBus {
-    /// Create a new event bus that records all events which will be dispatched
-    /// Used for unit testing
-    fn new() -> RecordingEventBus {
-        RecordingEventBus {
-            recorded_events: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-
     pub async fn recorded_events(&self) -> Vec<DomainEvent> {
         let events = self.recorded_events.lock().await;
 
@@ -169,12 +588,27 @@ impl RecordingEventBus {
 #[async_trait]
 impl EventBus for RecordingEventBus {
     async fn dispatch_event(&self, event: DomainEvent) {
-        let mut events = self.recorded_events.lock().await;
-        events.push(event);
+        self.recorded_events.lock().await.push(event.clone());
+        self.listeners.notify(&event);
     }
 
-    async fn subscribe(&self) -> Option<Receiver<DomainEvent>> {
-        None
+    async fn subscribe(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle {
+        self.listeners.add(filter, listener)
+    }
+}
+
+impl Context {
+    /// Subscribe a client to the events on the bus, the subscription is
+    /// tracked in the metrics by the transport it is sent over
+    pub(crate) async fn subscribe(&self, transport: &str, filter: EventFilter) -> Subscription {
+        let subscription = EventSubscription::new(
+            self.bus.as_ref(),
+            filter,
+            self.config.events.broadcast_capacity,
+        )
+        .await;
+
+        self.metrics.subscription(transport, subscription)
     }
 }
 
@@ -187,75 +621,133 @@ impl EventBus for RecordingEventBus {
 // As an example a player may only be allowed to see a SecretAchievementUnlocked
 // event if they unlocked that same event earlier.
 
+/// Server sent events are named after the event type, the data is the same
+/// JSON as sent over the websocket. `None` sets the reconnection time of the
+/// stream instead, which is jittered per stream so that browsers don't all
+/// reconnect at once after a shutdown.
+fn to_sse_event(ctx: &Context) -> impl FnMut(Option<DomainEvent>) -> Event + Send + 'static {
+    let reconnect_after_ms = ctx.config.server.reconnect_after_ms;
+    let retry = reconnect_after_ms + rand::thread_rng().gen_range(0..=reconnect_after_ms);
+
+    move |event| match event {
+        Some(event) => Event::message(json!(event).to_string()).event_type(event.name()),
+        None => Event::retry(retry),
+    }
+}
+
 #[derive(Default)]
 pub struct Api;
 
 #[OpenApi]
 impl Api {
-    #[oai(path = "/events", method = "get")]
-    async fn index(&self, ctx: Data<&Context>) -> EventStream<BoxStream<'static, Value>> {
-        let mut rx = ctx.bus.subscribe().await.unwrap();
+    /// Requires a session, so banned users can't keep reading along
+    #[oai(path = "/events", method = "get", transform = "protect")]
+    async fn index(
+        &self,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+        session: &Session,
+    ) -> EventStream<BoxStream<'static, Option<DomainEvent>>> {
+        let mut subscription = ctx.subscribe("sse", EventFilter::all()).await;
+        let username = Some(auth_data.username.clone());
+        let session_id = session_id(session);
 
         EventStream::new(
             async_stream::stream! {
-                while let Ok(event) = rx.recv().await {
-                    let json = json!(event);
-                    yield json;
+                while let Some(event) = subscription.recv().await {
+                    if !event.is_visible_to(username.as_deref()) {
+                        continue;
+                    }
+
+                    let ends_stream = event.ends_stream_of(username.as_deref(), session_id);
+                    if matches!(event, DomainEvent::ServerShuttingDown(_)) {
+                        yield None;
+                    }
+                    yield Some(event);
+
+                    if ends_stream {
+                        break;
+                    }
                 };
             }
             .boxed(),
         )
+        .to_event(to_sse_event(&ctx))
     }
 
     // These two endpoints show how we can filter events,
     // we can do this for instance to filter events for a specific player,
     // for admins, for the scoreboard etc
 
-    #[oai(path = "/events/:room_id", method = "get")]
-    async fn index_my_event(&self, ctx: Data<&Context>) -> EventStream<BoxStream<'static, Value>> {
-        let mut rx = ctx.bus.subscribe().await.unwrap();
+    #[oai(path = "/events/:room_id", method = "get", transform = "protect")]
+    async fn index_my_event(
+        &self,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+        session: &Session,
+    ) -> EventStream<BoxStream<'static, Option<DomainEvent>>> {
+        let mut subscription = ctx.subscribe("sse", EventFilter::all()).await;
+        let username = Some(auth_data.username.clone());
+        let session_id = session_id(session);
 
         EventStream::new(
             async_stream::stream! {
-                while let Ok(event) = rx.recv().await {
-                    let json = json!(event);
-                    yield json;
+                while let Some(event) = subscription.recv().await {
+                    if !event.is_visible_to(username.as_deref()) {
+                        continue;
+                    }
+
+                    let ends_stream = event.ends_stream_of(username.as_deref(), session_id);
+
+                    if matches!(event, DomainEvent::ServerShuttingDown(_)) {
+                        yield None;
+                    }
+                    yield Some(event);
+
+                    if ends_stream {
+                        break;
+                    }
                 };
             }
             .boxed(),
         )
+        .to_event(to_sse_event(&ctx))
     }
 
-    #[oai(path = "/generate-events", method = "get")]
+    #[oai(
+        path = "/generate-events",
+        method = "get",
+        transform = "limit_generate_events",
+        operation_id = "generate_events"
+    )]
     async fn generate_events(&self, ctx: Data<&Context>) -> Result<()> {
         for _ in 0..10 {
             let second = Duration::from_millis(1);
             tokio::time::sleep(second).await;
 
-            let now = OffsetDateTime::now_utc();
-            let room_id = Uuid::new_v4();
-            let event = DomainEvent::RoomWasCreated(RoomWasCreated {
-                id: room_id,
+            // Goes through the event store like any other room, so the rooms
+            // survive a rebuild of the read models
+            ctx.execute_and_dispatch(CreateRoom {
+                room_id: Uuid::new_v4(),
                 name: String::from("Random room"),
-                created_at: now,
-            });
-            ctx.bus.dispatch_event(event.clone()).await;
-
-            tokio::time::sleep(second).await;
-
-            let event = DomainEvent::UserJoinedRoom(UserJoinedRoom {
-                room_id,
                 username: "Francken".to_string(),
-                joined_at: now,
-            });
-            ctx.bus.dispatch_event(event.clone()).await;
+                created_at: OffsetDateTime::now_utc(),
+            })
+            .await?;
         }
 
         Ok(())
     }
 
-    #[oai(path = "/get-event-types", method = "get")]
-    async fn get_event_types(&self) -> Result<Json<Option<DomainEvent>>> {
-        Ok(Json(None))
+    /// Every event type that can be received from the event streams, with a
+    /// JSON Schema of the event. Events are sent with their type as name, so
+    /// browsers can listen to a single type with `addEventListener`.
+    #[oai(
+        path = "/events/schema",
+        method = "get",
+        operation_id = "events_schema_get"
+    )]
+    async fn get_event_schemas(&self) -> Result<Json<Vec<EventSchema>>> {
+        Ok(Json(DomainEvent::schemas()))
     }
 }
@@ -0,0 +1,70 @@
+use poem::{handler, http::StatusCode, web::Data, web::Json, IntoResponse, Response};
+use serde::Serialize;
+
+use crate::{ui_assets_present, Context};
+
+/// Liveness, answers as long as the process is able to serve requests
+#[handler]
+pub fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: Checks,
+}
+
+#[derive(Debug, Serialize)]
+struct Checks {
+    event_bus: bool,
+    store: bool,
+    ui: bool,
+}
+
+/// Readiness, only route traffic to this instance when all of its
+/// dependencies are usable
+#[handler]
+pub async fn readyz(ctx: Data<&Context>) -> Response {
+    let store = match ctx.blobs.check_writable().await {
+        Ok(()) => true,
+        Err(error) => {
+            tracing::warn!(%error, "Blob store is not writable");
+            false
+        }
+    };
+    let checks = Checks {
+        event_bus: ctx.bus.is_ready().await,
+        store,
+        ui: ui_assets_present(),
+    };
+    let ready = checks.event_bus && checks.store && checks.ui;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Json(Readiness { ready, checks })
+        .with_status(status)
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    name: &'static str,
+    version: &'static str,
+    /// Set through the `GIT_SHA` environment variable at compile time
+    git_sha: Option<&'static str>,
+    embedded_ui: bool,
+}
+
+#[handler]
+pub fn version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("GIT_SHA"),
+        embedded_ui: cfg!(feature = "embed-ui"),
+    })
+}
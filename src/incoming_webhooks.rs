@@ -0,0 +1,424 @@
+use std::{collections::HashMap, sync::Arc};
+
+use poem::{http::StatusCode, web::Data, Error, Result};
+use poem_openapi::{param::Path, payload::Json, Object, OpenApi};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+use crate::{
+    auth::{protect, AuthData},
+    commands::SendMessage,
+    events::{DomainEvent, EventBus, RoomWasRemoved, UserLeftRoom, UserWasBanned, UserWasKicked},
+    markdown::MessageFormat,
+    rate_limit::limit_incoming_webhook,
+    subscriptions::{EventFilter, Listener},
+    Context,
+};
+
+/// Lets a tool post messages into a room without logging in, the messages
+/// are sent under the name of the webhook. Names can't be shared with users,
+/// so a webhook can't pass itself off as one.
+///
+/// A webhook is revoked once its creator leaves the room, is kicked from it
+/// or is banned, and when the room is removed.
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+pub struct IncomingWebhook {
+    id: Uuid,
+    room_id: Uuid,
+    /// Username of the messages posted through the webhook
+    name: String,
+    created_by: String,
+    created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct CreateIncomingWebhookRequest {
+    #[oai(validator(min_length = 1, max_length = 64))]
+    name: String,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct CreatedIncomingWebhook {
+    #[oai(flatten)]
+    webhook: IncomingWebhook,
+    /// Where to post messages, the url contains the secret token of the
+    /// webhook and is only shown once
+    url: String,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct IncomingMessageRequest {
+    /// Makes retries safe, a random id is used when missing
+    id: Option<Uuid>,
+    #[oai(validator(max_length = 65536, min_length = 1))]
+    message: String,
+    #[oai(default)]
+    format: MessageFormat,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct IncomingMessageResponse {
+    id: Uuid,
+}
+
+/// Only a hash of each token is kept, so the tokens can't be recovered from
+/// the state of the server
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Incoming webhooks by the hash of their token
+#[derive(Clone, Default)]
+pub struct IncomingWebhooks {
+    webhooks: Arc<Mutex<HashMap<String, IncomingWebhook>>>,
+}
+
+impl IncomingWebhooks {
+    /// Returns the new webhook together with its token
+    pub async fn create(
+        &self,
+        room_id: Uuid,
+        name: String,
+        created_by: String,
+    ) -> (IncomingWebhook, String) {
+        let webhook = IncomingWebhook {
+            id: Uuid::new_v4(),
+            room_id,
+            name,
+            created_by,
+            created_at: OffsetDateTime::now_utc(),
+        };
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        self.webhooks
+            .lock()
+            .await
+            .insert(hash_token(&token), webhook.clone());
+
+        (webhook, token)
+    }
+
+    /// Revoke the webhooks that lost their creator or room by the event
+    async fn revoke(&self, event: &DomainEvent) {
+        let revoked = |webhook: &IncomingWebhook| match event {
+            DomainEvent::UserLeftRoom(UserLeftRoom {
+                room_id, username, ..
+            })
+            | DomainEvent::UserWasKicked(UserWasKicked {
+                room_id, username, ..
+            }) => webhook.room_id == *room_id && webhook.created_by == *username,
+            DomainEvent::UserWasBanned(UserWasBanned { username, .. }) => {
+                webhook.created_by == *username
+            }
+            DomainEvent::RoomWasRemoved(RoomWasRemoved { id, .. }) => webhook.room_id == *id,
+            _ => false,
+        };
+
+        self.webhooks.lock().await.retain(|_, webhook| {
+            if revoked(webhook) {
+                tracing::info!(webhook_id = %webhook.id, "Revoked incoming webhook");
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Subscribe to the bus and revoke webhooks in the background
+    pub async fn start(&self, bus: &(dyn EventBus + Send + Sync)) {
+        // Unbounded, these events are rare and none of them may be missed
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let listener: Listener = Arc::new(move |event: &DomainEvent| {
+            let _ = tx.send(event.clone());
+        });
+        let filter = EventFilter::all()
+            .of::<UserLeftRoom>()
+            .of::<UserWasKicked>()
+            .of::<UserWasBanned>()
+            .of::<RoomWasRemoved>();
+        let handle = bus.subscribe(filter, listener).await;
+
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let _handle = handle;
+            while let Some(event) = rx.recv().await {
+                webhooks.revoke(&event).await;
+            }
+        });
+    }
+
+    /// Whether a webhook with the name exists, ignoring case
+    pub async fn has_name(&self, name: &str) -> bool {
+        self.webhooks
+            .lock()
+            .await
+            .values()
+            .any(|webhook| webhook.name.to_lowercase() == name.to_lowercase())
+    }
+
+    pub async fn find(&self, token: &str) -> Option<IncomingWebhook> {
+        self.webhooks.lock().await.get(&hash_token(token)).cloned()
+    }
+
+    pub async fn in_room(&self, room_id: Uuid) -> Vec<IncomingWebhook> {
+        let mut webhooks = self
+            .webhooks
+            .lock()
+            .await
+            .values()
+            .filter(|webhook| webhook.room_id == room_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+
+        webhooks
+    }
+
+    /// Returns whether the webhook existed
+    pub async fn remove(&self, room_id: Uuid, webhook_id: Uuid) -> bool {
+        let mut webhooks = self.webhooks.lock().await;
+        let count = webhooks.len();
+        webhooks.retain(|_, webhook| webhook.room_id != room_id || webhook.id != webhook_id);
+
+        webhooks.len() < count
+    }
+}
+
+impl Context {
+    /// Whether anyone uses the name, ignoring case: admins, logged in users,
+    /// members of a room, users that sent a message and other webhooks
+    async fn is_username_taken(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        let matches = |username: &str| username.to_lowercase() == name;
+
+        if self
+            .config
+            .admin
+            .usernames
+            .iter()
+            .any(|admin| matches(admin))
+            || self.sessions.has_user(matches).await
+            || self.banned_users.any(matches).await
+            || self.incoming_webhooks.has_name(&name).await
+        {
+            return true;
+        }
+        if self
+            .users_in_room
+            .lock()
+            .await
+            .values()
+            .flatten()
+            .any(|user| matches(user))
+        {
+            return true;
+        }
+
+        self.messages_in_room
+            .lock()
+            .await
+            .values()
+            .flatten()
+            .any(|message| !message.bot && matches(&message.username))
+    }
+}
+
+#[derive(Default)]
+pub struct Api;
+
+#[OpenApi]
+impl Api {
+    #[oai(
+        path = "/rooms/:room_id/webhooks",
+        method = "post",
+        transform = "protect",
+        operation_id = "rooms_room_webhooks_post"
+    )]
+    async fn create_incoming_webhook(
+        &self,
+        room_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        request: Json<CreateIncomingWebhookRequest>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<Json<CreatedIncomingWebhook>> {
+        // Only members of a room can manage its webhooks
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+        if ctx.is_username_taken(&request.0.name).await {
+            return Err(Error::from_status(StatusCode::CONFLICT));
+        }
+
+        let (webhook, token) = ctx
+            .incoming_webhooks
+            .create(room_id.0, request.0.name, auth_data.username.clone())
+            .await;
+        tracing::info!(webhook_id = %webhook.id, room_id = %room_id.0, "Created incoming webhook");
+
+        Ok(Json(CreatedIncomingWebhook {
+            webhook,
+            url: format!("{}/hooks/{}", ctx.config.api_url(), token),
+        }))
+    }
+
+    #[oai(
+        path = "/rooms/:room_id/webhooks",
+        method = "get",
+        transform = "protect",
+        operation_id = "rooms_room_webhooks_get"
+    )]
+    async fn get_incoming_webhooks(
+        &self,
+        room_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<Json<Vec<IncomingWebhook>>> {
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+
+        Ok(Json(ctx.incoming_webhooks.in_room(room_id.0).await))
+    }
+
+    #[oai(
+        path = "/rooms/:room_id/webhooks/:webhook_id",
+        method = "delete",
+        transform = "protect",
+        operation_id = "rooms_room_webhooks_webhook_delete"
+    )]
+    async fn delete_incoming_webhook(
+        &self,
+        room_id: Path<Uuid>,
+        webhook_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<()> {
+        ctx.ensure_member(room_id.0, &auth_data.username).await?;
+
+        if !ctx.incoming_webhooks.remove(room_id.0, webhook_id.0).await {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        }
+
+        Ok(())
+    }
+
+    /// Post a message as the webhook, authenticated by the token in the url
+    /// instead of a session. The message is validated like any other message,
+    /// but is never treated as a slash command.
+    #[oai(
+        path = "/hooks/:token",
+        method = "post",
+        transform = "limit_incoming_webhook",
+        operation_id = "hooks_post"
+    )]
+    async fn post_incoming_message(
+        &self,
+        token: Path<String>,
+        ctx: Data<&Context>,
+        request: Json<IncomingMessageRequest>,
+    ) -> Result<Json<IncomingMessageResponse>> {
+        let Some(webhook) = ctx.incoming_webhooks.find(&token.0).await else {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        };
+        // Revoking happens in the background, so the creator is checked here
+        // as well
+        if ctx.is_banned(&webhook.created_by).await
+            || ctx
+                .ensure_member(webhook.room_id, &webhook.created_by)
+                .await
+                .is_err()
+        {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        }
+
+        let id = request.0.id.unwrap_or_else(Uuid::new_v4);
+        ctx.post_message(SendMessage {
+            room_id: webhook.room_id,
+            id,
+            username: webhook.name,
+            message: request.0.message,
+            format: request.0.format,
+            send_at: OffsetDateTime::now_utc(),
+            attachments: Vec::new(),
+            bot: true,
+        })
+        .await?;
+
+        Ok(Json(IncomingMessageResponse { id }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::IncomingWebhooks;
+    use crate::events::{DomainEvent, RoomWasRemoved, UserWasBanned, UserWasKicked};
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let webhooks = IncomingWebhooks::default();
+        let (room_id, other_room_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let (webhook, token) = webhooks
+            .create(room_id, "CI".to_string(), "Jane".to_string())
+            .await;
+
+        assert_eq!(webhooks.find(&token).await, Some(webhook.clone()));
+        assert_eq!(webhooks.find(&webhook.id.to_string()).await, None);
+        // Only the hash of the token is kept
+        assert!(!webhooks.webhooks.lock().await.contains_key(&token));
+
+        assert!(!webhooks.remove(other_room_id, webhook.id).await);
+        assert_eq!(webhooks.in_room(room_id).await, vec![webhook.clone()]);
+        assert!(webhooks.remove(room_id, webhook.id).await);
+        assert_eq!(webhooks.find(&token).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let webhooks = IncomingWebhooks::default();
+        let (room_id, other_room_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = OffsetDateTime::now_utc();
+        let (_, jane) = webhooks
+            .create(room_id, "CI".to_string(), "Jane".to_string())
+            .await;
+        let (_, jane_elsewhere) = webhooks
+            .create(other_room_id, "Deploy".to_string(), "Jane".to_string())
+            .await;
+        let (_, john) = webhooks
+            .create(room_id, "Alerts".to_string(), "John".to_string())
+            .await;
+        assert!(webhooks.has_name("ci").await);
+
+        webhooks
+            .revoke(&DomainEvent::UserWasKicked(UserWasKicked {
+                room_id,
+                username: "Jane".to_string(),
+                kicked_by: "Admin".to_string(),
+                kicked_at: now,
+            }))
+            .await;
+        assert!(webhooks.find(&jane).await.is_none());
+        assert!(webhooks.find(&jane_elsewhere).await.is_some());
+
+        webhooks
+            .revoke(&DomainEvent::UserWasBanned(UserWasBanned {
+                username: "Jane".to_string(),
+                banned_by: "Admin".to_string(),
+                reason: None,
+                banned_at: now,
+            }))
+            .await;
+        assert!(webhooks.find(&jane_elsewhere).await.is_none());
+        assert!(webhooks.find(&john).await.is_some());
+
+        webhooks
+            .revoke(&DomainEvent::RoomWasRemoved(RoomWasRemoved {
+                id: room_id,
+                removed_at: now,
+            }))
+            .await;
+        assert!(webhooks.find(&john).await.is_none());
+    }
+}
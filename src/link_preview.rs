@@ -0,0 +1,591 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use poem::async_trait;
+use poem_openapi::Object;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect,
+};
+use serde::Serialize;
+use url::{Host, Url};
+use uuid::Uuid;
+
+use crate::{commands::AddLinkPreview, Context};
+
+/// Only the first few links of a message are unfurled
+pub const MAX_PREVIEWS_PER_MESSAGE: usize = 3;
+
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug, Object, Clone, Serialize, PartialEq, Eq)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub site_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The url points to an address that we are not allowed to connect to
+    Blocked,
+    Request(reqwest::Error),
+    TooLarge,
+    NotHtml,
+    NoMetadata,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Blocked => write!(f, "url points to a private address"),
+            FetchError::Request(error) => write!(f, "request failed: {}", error),
+            FetchError::TooLarge => write!(f, "response body is too large"),
+            FetchError::NotHtml => write!(f, "response is not html"),
+            FetchError::NoMetadata => write!(f, "page has no metadata"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+#[async_trait]
+pub trait LinkPreviewFetcher {
+    async fn fetch(&self, url: &Url) -> Result<LinkPreview, FetchError>;
+}
+
+pub type ShareableLinkPreviewFetcher =
+    Arc<dyn LinkPreviewFetcher + std::marker::Sync + std::marker::Send + 'static>;
+
+/// Fetches Open Graph metadata over http(s).
+///
+/// By default connections to loopback, private and other non public addresses
+/// are refused, both for the initial url and for any redirects, so that users
+/// can't use link previews to probe our internal network.
+pub struct HttpLinkPreviewFetcher {
+    client: reqwest::Client,
+    timeout: Duration,
+    max_body_size: usize,
+    allow_private_addresses: bool,
+}
+
+impl HttpLinkPreviewFetcher {
+    pub fn new(timeout: Duration, max_body_size: usize) -> HttpLinkPreviewFetcher {
+        HttpLinkPreviewFetcher {
+            client: build_client(timeout, false),
+            timeout,
+            max_body_size,
+            allow_private_addresses: false,
+        }
+    }
+
+    /// Disable the SSRF guard, only meant for testing against a local server
+    #[allow(dead_code)]
+    pub fn allow_private_addresses(self) -> HttpLinkPreviewFetcher {
+        HttpLinkPreviewFetcher {
+            client: build_client(self.timeout, true),
+            allow_private_addresses: true,
+            ..self
+        }
+    }
+}
+
+impl Default for HttpLinkPreviewFetcher {
+    fn default() -> Self {
+        HttpLinkPreviewFetcher::new(Duration::from_secs(5), 512 * 1024)
+    }
+}
+
+fn build_client(timeout: Duration, allow_private_addresses: bool) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent("poem-sse-chat link preview")
+        // A proxy would resolve the host for us, bypassing our guarded resolver
+        .no_proxy();
+
+    if !allow_private_addresses {
+        builder = builder
+            .dns_resolver(Arc::new(PublicAddressResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.stop()
+                } else if !is_allowed_url(attempt.url()) {
+                    attempt.error(FetchError::Blocked)
+                } else {
+                    attempt.follow()
+                }
+            }));
+    } else {
+        builder = builder.redirect(redirect::Policy::limited(MAX_REDIRECTS));
+    }
+
+    builder.build().expect("Failed to build http client")
+}
+
+#[async_trait]
+impl LinkPreviewFetcher for HttpLinkPreviewFetcher {
+    async fn fetch(&self, url: &Url) -> Result<LinkPreview, FetchError> {
+        if !self.allow_private_addresses && !is_allowed_url(url) {
+            return Err(FetchError::Blocked);
+        }
+
+        let mut response = self
+            .client
+            .get(url.clone())
+            .header(header::ACCEPT, "text/html")
+            .send()
+            .await
+            .map_err(FetchError::Request)?
+            .error_for_status()
+            .map_err(FetchError::Request)?;
+
+        let is_html = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        if !is_html {
+            return Err(FetchError::NotHtml);
+        }
+
+        if response
+            .content_length()
+            .is_some_and(|length| length as usize > self.max_body_size)
+        {
+            return Err(FetchError::TooLarge);
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(FetchError::Request)? {
+            body.extend_from_slice(&chunk);
+
+            if body.len() > self.max_body_size {
+                return Err(FetchError::TooLarge);
+            }
+        }
+
+        parse_open_graph(response.url(), &String::from_utf8_lossy(&body))
+            .ok_or(FetchError::NoMetadata)
+    }
+}
+
+/// Resolves host names while dropping any address that isn't publicly routable
+pub struct PublicAddressResolver;
+
+impl Resolve for PublicAddressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(Box::new(FetchError::Blocked) as Box<_>);
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether the url is http(s) and does not point to a private ip address, host
+/// names are checked when resolving them with [`PublicAddressResolver`]
+pub fn is_allowed_url(url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+
+    // Host names are checked by our resolver, ip addresses never reach it
+    match url.host() {
+        None => false,
+        Some(Host::Domain(_)) => true,
+        Some(Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+    }
+}
+
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+/// IPv6 addresses that reach the IPv4 address in their last 32 bits: mapped
+/// (::ffff:0:0/96), compatible (::/96) and NAT64 (64:ff9b::/96) addresses
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return Some(Ipv4Addr::new(a, b, c, d));
+    }
+
+    ip.to_ipv4()
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || a == 0
+        // 100.64.0.0/10 shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // 192.0.0.0/24 protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (b == 18 || b == 19))
+        // 240.0.0.0/4 reserved
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 link local
+        || (first & 0xffc0) == 0xfe80
+        // 64:ff9b:1::/48 local use NAT64, its IPv4 addresses may be anywhere
+        || (first == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1)
+        // 2001:db8::/32 documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// Find the http(s) links in a message
+pub fn extract_urls(message: &str) -> Vec<Url> {
+    let mut urls: Vec<Url> = Vec::new();
+
+    for word in message.split_whitespace() {
+        // Links are often followed by punctuation or wrapped in brackets
+        let word = word
+            .trim_start_matches(['(', '<', '"', '\''])
+            .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', '"', '\'']);
+
+        if !word.starts_with("http://") && !word.starts_with("https://") {
+            continue;
+        }
+
+        if let Ok(url) = Url::parse(word) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+    }
+
+    urls
+}
+
+/// Extract Open Graph metadata from a html document, falling back to the
+/// `<title>` and description meta tags
+pub fn parse_open_graph(url: &Url, html: &str) -> Option<LinkPreview> {
+    let mut preview = LinkPreview {
+        url: url.to_string(),
+        title: None,
+        description: None,
+        image: None,
+        site_name: None,
+    };
+    let mut fallback_title = None;
+    let mut fallback_description = None;
+
+    let lowercase = html.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = lowercase[offset..].find('<').map(|start| start + offset) {
+        let Some(end) = lowercase[start..].find('>').map(|end| end + start) else {
+            break;
+        };
+        offset = end + 1;
+
+        let tag = &html[start + 1..end];
+        let lowercase_tag = &lowercase[start + 1..end];
+
+        if lowercase_tag.starts_with("title") && fallback_title.is_none() {
+            if let Some(close) = lowercase[offset..].find("</title") {
+                fallback_title = Some(decode_entities(html[offset..offset + close].trim()));
+            }
+            continue;
+        }
+
+        if !lowercase_tag.starts_with("meta ") {
+            continue;
+        }
+
+        let attributes = parse_attributes(&tag[5..]);
+        let attribute = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let Some(content) = attribute("content").filter(|content| !content.is_empty()) else {
+            continue;
+        };
+        let property = attribute("property")
+            .or_else(|| attribute("name"))
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match property.as_str() {
+            "og:title" => preview.title = Some(content),
+            "og:description" => preview.description = Some(content),
+            "og:site_name" => preview.site_name = Some(content),
+            // Relative images are resolved against the page they're found on,
+            // only http(s) images are shown
+            "og:image" => {
+                preview.image = url
+                    .join(&content)
+                    .ok()
+                    .filter(|image| matches!(image.scheme(), "http" | "https"))
+                    .map(|image| image.to_string())
+            }
+            "description" => fallback_description = Some(content),
+            _ => {}
+        }
+    }
+
+    preview.title = preview.title.or(fallback_title);
+    preview.description = preview.description.or(fallback_description);
+
+    if preview.title.is_none() && preview.description.is_none() {
+        return None;
+    }
+
+    Some(preview)
+}
+
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.trim_start();
+
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let value = if let Some(after_equals) = rest.strip_prefix('=') {
+            let after_equals = after_equals.trim_start();
+
+            match after_equals.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let value_end = after_equals[1..]
+                        .find(quote)
+                        .map(|end| end + 1)
+                        .unwrap_or(after_equals.len());
+                    rest = after_equals.get(value_end + 1..).unwrap_or_default();
+                    &after_equals[1..value_end]
+                }
+                _ => {
+                    let value_end = after_equals
+                        .find(char::is_whitespace)
+                        .unwrap_or(after_equals.len());
+                    rest = &after_equals[value_end..];
+                    &after_equals[..value_end]
+                }
+            }
+        } else {
+            rest = rest.trim_start_matches('/');
+            ""
+        };
+
+        if !name.is_empty() {
+            attributes.push((name, decode_entities(value)));
+        }
+        rest = rest.trim_start();
+    }
+
+    attributes
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Fetch previews for the links in a message and attach them to it,
+/// previews are dispatched one by one as soon as they are available
+pub async fn unfurl_message(ctx: Context, room_id: Uuid, message_id: Uuid, urls: Vec<Url>) {
+    for url in urls.into_iter().take(MAX_PREVIEWS_PER_MESSAGE) {
+        let preview = match ctx.link_preview_fetcher.fetch(&url).await {
+            Ok(preview) => preview,
+            Err(error) => {
+                tracing::warn!(%url, %error, "Could not fetch link preview");
+                continue;
+            }
+        };
+
+        // The message or its room may have been removed in the meantime
+        if ctx
+            .execute_and_dispatch(AddLinkPreview {
+                room_id,
+                message_id,
+                preview,
+            })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::Duration};
+
+    use poem::{
+        handler,
+        listener::{Acceptor, Listener, TcpListener},
+        web::Html,
+        Route, Server,
+    };
+    use url::Url;
+
+    use super::{
+        extract_urls, is_public_ip, parse_open_graph, FetchError, HttpLinkPreviewFetcher,
+        LinkPreview, LinkPreviewFetcher,
+    };
+
+    #[test]
+    fn test_extract_urls() {
+        assert_eq!(
+            extract_urls("Look (https://example.com/a?b=c), http://example.org. ftp://x"),
+            vec![
+                Url::parse("https://example.com/a?b=c").unwrap(),
+                Url::parse("http://example.org").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in [
+            "93.184.216.34",
+            "2606:2800:220:1:248:1893:25c8:1946",
+            "64:ff9b::93.184.216.34",
+        ] {
+            assert!(is_public_ip(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::a9fe:a9fe",
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b:1::5db8:d822",
+        ] {
+            assert!(!is_public_ip(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_parse_open_graph() {
+        let url = Url::parse("https://example.com/posts/1").unwrap();
+        let html = r#"
+            <html><head>
+                <title>Fallback</title>
+                <meta property="og:title" content="Crash &amp; Compile">
+                <META name='description' content='A programming contest'/>
+                <meta property="og:image" content="/image.png" />
+                <meta property="og:site_name" content=Francken>
+            </head></html>
+        "#;
+
+        assert_eq!(
+            parse_open_graph(&url, html),
+            Some(LinkPreview {
+                url: "https://example.com/posts/1".to_string(),
+                title: Some("Crash & Compile".to_string()),
+                description: Some("A programming contest".to_string()),
+                image: Some("https://example.com/image.png".to_string()),
+                site_name: Some("Francken".to_string()),
+            })
+        );
+        assert_eq!(parse_open_graph(&url, "<p>Nothing here</p>"), None);
+
+        for image in ["javascript:alert(1)", "data:image/png;base64,AAAA"] {
+            let html = format!(
+                r#"<meta property="og:title" content="Title"><meta property="og:image" content="{}">"#,
+                image
+            );
+            assert_eq!(
+                parse_open_graph(&url, &html).unwrap().image,
+                None,
+                "{}",
+                image
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_fetcher() {
+        #[handler]
+        fn page() -> Html<&'static str> {
+            Html(r#"<meta property="og:title" content="Local page">"#)
+        }
+
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(Route::new().at("/", page)));
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+
+        // Local addresses are refused by default
+        let fetcher = HttpLinkPreviewFetcher::default();
+        assert!(matches!(
+            fetcher.fetch(&url).await,
+            Err(FetchError::Blocked)
+        ));
+        let localhost = Url::parse(&format!("http://localhost:{}/", addr.port())).unwrap();
+        assert!(matches!(
+            fetcher.fetch(&localhost).await,
+            Err(FetchError::Request(_))
+        ));
+
+        let fetcher =
+            HttpLinkPreviewFetcher::new(Duration::from_secs(1), 1024).allow_private_addresses();
+        let preview = fetcher.fetch(&url).await.unwrap();
+        assert_eq!(preview.title, Some("Local page".to_string()));
+    }
+}
@@ -1,27 +1,86 @@
+mod admin;
+mod attachments;
+mod audit;
 mod auth;
+mod commands;
+mod config;
+mod csrf;
+mod event_schema;
+mod event_store;
 mod events;
+mod health;
+mod incoming_webhooks;
+mod link_preview;
+mod markdown;
+mod mentions;
+mod metrics;
+mod projections;
+mod rate_limit;
+mod request_tracing;
+mod room;
+mod sessions;
+mod slash_commands;
+mod subscriptions;
+#[cfg(any(test, feature = "embed-ui"))]
+mod ui;
+mod upcasting;
+mod webhooks;
+mod websocket;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use std::{collections::HashMap, sync::Arc};
-
+use admin::Bans;
+use attachments::{Attachment, BlobStore};
+use audit::{AuditAction, AuditLog, RequestOrigin};
 use auth::{protect, AuthData};
+use clap::Parser;
+use commands::{
+    Command, CommandHandler, CreateRoom, JoinRoom, LeaveRoom, ReadMention, RemoveRoom, SendMessage,
+};
+use config::{Args, Config, LogFormat};
+use csrf::CsrfProtection;
+use event_store::EventStore;
 use events::{
-    BroadcastingEventBus, DomainEvent, MessageWasSend, RoomWasCreated, RoomWasRemoved,
-    ShareableEventBus, UserJoinedRoom, UserLeftRoom, UserLoggedIn, UserLoggedOut,
+    BroadcastingEventBus, DomainEvent, ServerShuttingDown, SessionWasRevoked, ShareableEventBus,
+    UserLoggedIn, UserLoggedOut,
+};
+use incoming_webhooks::IncomingWebhooks;
+use link_preview::{
+    extract_urls, unfurl_message, HttpLinkPreviewFetcher, LinkPreview, ShareableLinkPreviewFetcher,
 };
+use markdown::MessageFormat;
+use metrics::{MeteredEventBus, Metrics, MetricsMiddleware};
+#[cfg(not(feature = "embed-ui"))]
+use poem::endpoint::StaticFilesEndpoint;
 use poem::{
-    endpoint::StaticFilesEndpoint,
+    get,
     http::StatusCode,
     listener::TcpListener,
     middleware::Cors,
-    session::{CookieConfig, CookieSession, Session},
+    session::{CookieConfig, ServerSession, Session},
     web::{cookie::SameSite, Data},
     Endpoint, EndpointExt, Error, Result, Route, Server,
 };
-use poem_openapi::{param::Path, payload::Json, Object, OpenApi, OpenApiService, OperationId};
+use poem_openapi::{param::Path, payload::Json, Object, OpenApi, OpenApiService};
+use projections::{
+    MentionProjection, MessageProjection, ProjectingEventBus, Projections, RoomProjection,
+};
+use rate_limit::{limit_create_room, limit_send_message, RateLimiter};
+use request_tracing::{RequestTracing, REQUEST_ID_HEADER};
+use room::RoomAggregate;
 use serde::Serialize;
+use sessions::{assign_session_id, session_id, SessionStore};
+use slash_commands::{Invocation, SlashCommands};
 use time::OffsetDateTime;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::Mutex;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
+use webhooks::Webhooks;
 
 #[derive(Debug, Object, Clone, Serialize, Eq, PartialEq)]
 struct Cursor {
@@ -67,13 +126,31 @@ pub struct Message {
     room_id: Uuid,
     username: String,
     message: String,
+    format: MessageFormat,
+    rendered_html: String,
     send_at: OffsetDateTime,
+    mentions: Vec<String>,
+    attachments: Vec<Attachment>,
+    /// Previews are added asynchronously after the message was sent
+    link_previews: Vec<LinkPreview>,
+    /// Posted by a tool through an incoming webhook instead of by a user
+    bot: bool,
+}
+
+#[derive(Debug, Object, Clone, Serialize, Eq, PartialEq)]
+struct Mention {
+    message_id: Uuid,
+    room_id: Uuid,
+    mentioned_by: String,
+    mentioned_at: OffsetDateTime,
 }
 
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
 struct Room {
     id: Uuid,
     name: String,
+    /// Set by members with the `/topic` command
+    topic: Option<String>,
 }
 
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
@@ -88,6 +165,7 @@ struct IndexRoom {
 struct DetailedRoom {
     id: Uuid,
     name: String,
+    topic: Option<String>,
     messages: Vec<Message>,
     users: Vec<String>,
 }
@@ -117,9 +195,16 @@ struct RemoveRoomRequest {
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
 struct SendMessageRequest {
     id: Uuid,
-    #[oai(validator(max_length = 1024, min_length = 1))]
+    /// The configured maximum length is checked when sending, this is the
+    /// upper bound of that setting (`config::MAX_MESSAGE_LENGTH`)
+    #[oai(validator(max_length = 65536, min_length = 1))]
     message: String,
+    #[oai(default)]
+    format: MessageFormat,
     send_at: OffsetDateTime,
+    /// Ids of attachments that were uploaded to the room beforehand
+    #[oai(default)]
+    attachments: Vec<Uuid>,
 }
 
 #[derive(Debug, Object, Clone, Eq, PartialEq)]
@@ -132,6 +217,46 @@ struct LeaveRoomRequest {
     left_at: OffsetDateTime,
 }
 
+impl SendMessageRequest {
+    async fn into_command(
+        self,
+        ctx: &Context,
+        room_id: Uuid,
+        username: String,
+    ) -> Result<SendMessage> {
+        Ok(SendMessage {
+            room_id,
+            id: self.id,
+            username,
+            attachments: ctx.uploaded_attachments(&self.attachments).await?,
+            message: self.message,
+            format: self.format,
+            send_at: self.send_at,
+            bot: false,
+        })
+    }
+}
+
+impl JoinRoomRequest {
+    fn into_command(self, room_id: Uuid, username: String) -> JoinRoom {
+        JoinRoom {
+            room_id,
+            username,
+            joined_at: self.joined_at,
+        }
+    }
+}
+
+impl LeaveRoomRequest {
+    fn into_command(self, room_id: Uuid, username: String) -> LeaveRoom {
+        LeaveRoom {
+            room_id,
+            username,
+            left_at: self.left_at,
+        }
+    }
+}
+
 #[OpenApi]
 impl Api {
     #[oai(path = "/session", method = "post", operation_id = "session_post")]
@@ -141,7 +266,18 @@ impl Api {
         request: Json<LoginRequest>,
         session: &Session,
     ) -> Result<()> {
+        if ctx.is_banned(&request.username).await {
+            return Err(Error::from_status(StatusCode::FORBIDDEN));
+        }
+        // Messages of incoming webhooks are sent under their name
+        if ctx.incoming_webhooks.has_name(&request.username).await {
+            return Err(Error::from_status(StatusCode::CONFLICT));
+        }
+
+        // A fresh session id prevents session fixation
+        session.renew();
         session.set("username", request.username.clone());
+        assign_session_id(session);
 
         ctx.bus
             .dispatch_event(DomainEvent::UserLoggedIn(UserLoggedIn {
@@ -165,6 +301,7 @@ impl Api {
         auth_data: Data<&AuthData>,
     ) -> Result<()> {
         let username = auth_data.username.clone();
+        let session_id = session_id(session);
 
         ctx.bus
             .dispatch_event(DomainEvent::UserLoggedOut(UserLoggedOut {
@@ -172,7 +309,59 @@ impl Api {
             }))
             .await;
 
-        session.remove("username");
+        session.purge();
+        ctx.bus
+            .dispatch_event(DomainEvent::SessionWasRevoked(SessionWasRevoked {
+                username,
+                session_id: Some(session_id),
+            }))
+            .await;
+
+        Ok(())
+    }
+
+    /// Log out every session of the current user, for instance after a
+    /// cookie was leaked
+    #[oai(
+        path = "/sessions",
+        method = "delete",
+        transform = "protect",
+        operation_id = "sessions_delete"
+    )]
+    async fn logout_everywhere(
+        &self,
+        ctx: Data<&Context>,
+        session: &Session,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
+    ) -> Result<()> {
+        let username = auth_data.username.clone();
+
+        let event = DomainEvent::UserLoggedOut(UserLoggedOut {
+            username: username.clone(),
+        });
+        ctx.audit(
+            AuditAction::LogoutEverywhere,
+            &username,
+            format!("user:{}", username),
+            &origin,
+            event.clone(),
+        )
+        .await?;
+
+        ctx.sessions
+            .revoke_user(&username)
+            .await
+            .map_err(|_| Error::from_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+        ctx.bus.dispatch_event(event).await;
+
+        session.purge();
+        ctx.bus
+            .dispatch_event(DomainEvent::SessionWasRevoked(SessionWasRevoked {
+                username,
+                session_id: None,
+            }))
+            .await;
 
         Ok(())
     }
@@ -258,6 +447,7 @@ impl Api {
                 Ok(Json(DetailedRoom {
                     id: room.id,
                     name: room.name.clone(),
+                    topic: room.topic.clone(),
                     messages,
                     users,
                 }))
@@ -276,42 +466,24 @@ impl Api {
         request: Json<RemoveRoomRequest>,
         ctx: Data<&Context>,
         room_id: Path<Uuid>,
+        auth_data: Data<&AuthData>,
+        origin: RequestOrigin,
     ) -> Result<()> {
-        let rooms = ctx.rooms.lock().await.clone();
-
-        let room = rooms.iter().find(|room| room.id == room_id.0);
-
-        match room {
-            None => Err(Error::from_status(StatusCode::NOT_FOUND)),
-            Some(room) => {
-                let mut rooms = ctx.rooms.lock().await;
-
-                if let Some(pos) = rooms.iter().position(|item| item.id == room_id.0) {
-                    rooms.remove(pos);
-                }
-
-                let mut msgs = ctx.messages_in_room.lock().await;
-                msgs.remove(&room_id.0);
-
-                let mut users = ctx.users_in_room.lock().await;
-                users.remove(&room_id.0);
-
-                ctx.bus
-                    .dispatch_event(DomainEvent::RoomWasRemoved(RoomWasRemoved {
-                        id: room.id,
-                        removed_at: request.removed_at,
-                    }))
-                    .await;
-
-                Ok(())
-            }
-        }
+        ctx.remove_room(
+            RemoveRoom {
+                room_id: room_id.0,
+                removed_by: auth_data.username.clone(),
+                removed_at: request.removed_at,
+            },
+            &origin,
+        )
+        .await
     }
 
     #[oai(
         path = "/rooms",
         method = "post",
-        transform = "protect",
+        transform = "limit_create_room",
         operation_id = "rooms_post"
     )]
     async fn create_room(
@@ -320,39 +492,19 @@ impl Api {
         request: Json<CreateRoomRequest>,
         auth_data: Data<&AuthData>,
     ) -> Result<Json<Room>> {
-        let username = auth_data.username.clone();
-
-        let room = Room {
-            id: request.id,
+        ctx.execute_and_dispatch(CreateRoom {
+            room_id: request.id,
             name: request.name.clone(),
-        };
-
-        ctx.bus
-            .dispatch_event(DomainEvent::RoomWasCreated(RoomWasCreated {
-                id: room.id,
-                name: room.name.clone(),
-                created_at: request.created_at,
-            }))
-            .await;
-
-        ctx.rooms.lock().await.push(room.clone());
-
-        ctx.bus
-            .dispatch_event(DomainEvent::UserJoinedRoom(UserJoinedRoom {
-                room_id: room.id,
-                username: username.clone(),
-                joined_at: request.created_at,
-            }))
-            .await;
-
-        ctx.users_in_room
-            .lock()
-            .await
-            .entry(request.id)
-            .or_insert(Vec::new())
-            .push(username.clone());
+            username: auth_data.username.clone(),
+            created_at: request.created_at,
+        })
+        .await?;
 
-        Ok(Json(room))
+        Ok(Json(Room {
+            id: request.id,
+            name: request.0.name,
+            topic: None,
+        }))
     }
 
     #[oai(
@@ -368,30 +520,18 @@ impl Api {
         ctx: Data<&Context>,
         auth_data: Data<&AuthData>,
     ) -> Result<()> {
-        let username = auth_data.username.clone();
-
-        let mut users_in_room = ctx.users_in_room.lock().await;
-        let users = users_in_room.entry(room_id.0).or_insert(Vec::new());
-
-        if !users.contains(&username) {
-            users.push(auth_data.username.clone());
-
-            ctx.bus
-                .dispatch_event(DomainEvent::UserJoinedRoom(UserJoinedRoom {
-                    room_id: room_id.0,
-                    username: auth_data.username.clone(),
-                    joined_at: request.joined_at,
-                }))
-                .await;
-        }
-
-        Ok(())
+        ctx.execute_and_dispatch(
+            request
+                .0
+                .into_command(room_id.0, auth_data.username.clone()),
+        )
+        .await
     }
 
     #[oai(
         path = "/rooms/:room_id/messages",
         method = "post",
-        transform = "protect",
+        transform = "limit_send_message",
         operation_id = "rooms_room_messages_post"
     )]
     async fn send_message(
@@ -401,30 +541,12 @@ impl Api {
         request: Json<SendMessageRequest>,
         auth_data: Data<&AuthData>,
     ) -> Result<()> {
-        ctx.bus
-            .dispatch_event(DomainEvent::MessageWasSend(MessageWasSend {
-                id: request.id,
-                room_id: room_id.0,
-                username: auth_data.username.clone(),
-                message: request.message.clone(),
-                send_at: request.send_at,
-            }))
-            .await;
-
-        ctx.messages_in_room
-            .lock()
-            .await
-            .entry(room_id.0)
-            .or_insert(Vec::new())
-            .push(Message {
-                id: request.id,
-                room_id: room_id.0,
-                username: auth_data.username.clone(),
-                message: request.message.clone(),
-                send_at: request.send_at,
-            });
+        let command = request
+            .0
+            .into_command(&ctx, room_id.0, auth_data.username.clone())
+            .await?;
 
-        Ok(())
+        ctx.send_message(command).await
     }
 
     #[oai(
@@ -454,7 +576,7 @@ impl Api {
             pagination: Pagination {
                 current_page: 0,
                 per_page: total_items,
-                total_items: total_items,
+                total_items,
                 total_pages: 1,
                 next_page: None,
                 previous_page: None,
@@ -475,160 +597,524 @@ impl Api {
         request: Json<LeaveRoomRequest>,
         auth_data: Data<&AuthData>,
     ) -> Result<()> {
-        ctx.bus
-            .dispatch_event(DomainEvent::UserLeftRoom(UserLeftRoom {
-                room_id: room_id.0,
-                username: auth_data.username.clone(),
-                left_at: request.left_at,
-            }))
-            .await;
+        ctx.execute_and_dispatch(
+            request
+                .0
+                .into_command(room_id.0, auth_data.username.clone()),
+        )
+        .await
+    }
 
-        Ok(())
+    #[oai(
+        path = "/mentions",
+        method = "get",
+        transform = "protect",
+        operation_id = "mentions_get"
+    )]
+    async fn get_mentions(
+        &self,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<Json<Vec<Mention>>> {
+        let mentions = ctx
+            .mentions
+            .lock()
+            .await
+            .get(&auth_data.username)
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(Json(mentions))
+    }
+
+    #[oai(
+        path = "/mentions/:message_id",
+        method = "delete",
+        transform = "protect",
+        operation_id = "mentions_mention_delete"
+    )]
+    async fn read_mention(
+        &self,
+        message_id: Path<Uuid>,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<()> {
+        // The mention is looked up to find its room, which decides whether
+        // it can still be read
+        let room_id = ctx
+            .mentions
+            .lock()
+            .await
+            .get(&auth_data.username)
+            .and_then(|mentions| {
+                mentions
+                    .iter()
+                    .find(|mention| mention.message_id == message_id.0)
+            })
+            .map(|mention| mention.room_id)
+            .ok_or_else(|| Error::from_status(StatusCode::NOT_FOUND))?;
+
+        ctx.execute_and_dispatch(ReadMention {
+            room_id,
+            message_id: message_id.0,
+            username: auth_data.username.clone(),
+            read_at: OffsetDateTime::now_utc(),
+        })
+        .await
     }
 }
 
 #[derive(Clone)]
 pub struct Context {
+    config: Arc<Config>,
     bus: ShareableEventBus,
 
+    // Read models, only changed by the projections
+    projections: Projections,
     rooms: Arc<Mutex<Vec<Room>>>,
     messages_in_room: Arc<Mutex<HashMap<Uuid, Vec<Message>>>>,
     users_in_room: Arc<Mutex<HashMap<Uuid, Vec<String>>>>,
+    // Unread mentions per username
+    mentions: Arc<Mutex<HashMap<String, Vec<Mention>>>>,
+
+    attachments: Arc<Mutex<HashMap<Uuid, Attachment>>>,
+    // Uploads that were sent with a message, every upload can be sent once
+    sent_attachments: Arc<Mutex<HashSet<Uuid>>>,
+    blobs: BlobStore,
+
+    link_preview_fetcher: ShareableLinkPreviewFetcher,
+    incoming_webhooks: IncomingWebhooks,
+
+    rate_limiter: Arc<RateLimiter>,
+    sessions: SessionStore,
+    metrics: Arc<Metrics>,
+    event_store: EventStore,
+    room_aggregates: Arc<Mutex<HashMap<Uuid, RoomAggregate>>>,
+    commands: CommandHandler,
+    slash_commands: SlashCommands,
+
+    banned_users: Bans,
+    audit_log: AuditLog,
+    webhooks: Webhooks,
 }
 
-pub async fn create_app(ctx: Context) -> Result<impl Endpoint, Box<dyn std::error::Error>> {
-    let all_endpoints = (Api, events::Api);
+impl Context {
+    /// Creates a context with empty in-memory state that dispatches its events
+    /// to the given bus
+    pub fn new(bus: ShareableEventBus) -> Context {
+        Context::from_config(bus, Config::default())
+    }
+
+    pub fn from_config(bus: ShareableEventBus, config: Config) -> Context {
+        let metrics = Arc::new(Metrics::new());
+
+        let rooms = Arc::new(Mutex::new(Vec::new()));
+        let messages_in_room = Arc::new(Mutex::new(HashMap::new()));
+        let users_in_room = Arc::new(Mutex::new(HashMap::new()));
+        let mentions = Arc::new(Mutex::new(HashMap::new()));
+        let event_store = EventStore::default();
+        let projections = Projections::new(
+            vec![
+                Arc::new(RoomProjection {
+                    rooms: rooms.clone(),
+                    users_in_room: users_in_room.clone(),
+                }),
+                Arc::new(MessageProjection {
+                    messages_in_room: messages_in_room.clone(),
+                }),
+                Arc::new(MentionProjection {
+                    mentions: mentions.clone(),
+                }),
+            ],
+            event_store.clone(),
+        );
+        let bus = Arc::new(ProjectingEventBus::new(bus, projections.clone()));
+
+        Context {
+            bus: Arc::new(MeteredEventBus::new(bus, metrics.clone())),
+            metrics,
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits())),
+            blobs: BlobStore::new(config.storage.data_dir.join("blobs")),
+            sessions: SessionStore::new(
+                Duration::from_secs(config.session.idle_timeout_secs),
+                Duration::from_secs(config.session.absolute_timeout_secs),
+                config.session_file(),
+            ),
+            audit_log: AuditLog::new(config.audit_file()),
+            banned_users: Bans::new(config.ban_file()),
+            commands: CommandHandler::new(config.limits.max_message_length),
+            slash_commands: SlashCommands::builtin(),
+            webhooks: Webhooks::new(config.webhooks.clone()),
+            config: Arc::new(config),
+            event_store,
+            room_aggregates: Arc::new(Mutex::new(HashMap::new())),
+            projections,
+            rooms,
+            messages_in_room,
+            users_in_room,
+            mentions,
+            attachments: Arc::new(Mutex::new(HashMap::new())),
+            sent_attachments: Arc::new(Mutex::new(HashSet::new())),
+            link_preview_fetcher: Arc::new(HttpLinkPreviewFetcher::default()),
+            incoming_webhooks: IncomingWebhooks::default(),
+        }
+    }
+
+    async fn remove_room(&self, command: RemoveRoom, origin: &RequestOrigin) -> Result<()> {
+        let (room_id, removed_by) = (command.room_id, command.removed_by.clone());
+        self.execute_audited(
+            AuditAction::DeleteRoom,
+            &removed_by,
+            format!("room:{}", room_id),
+            origin,
+            command,
+        )
+        .await?;
+
+        self.remove_attachments(|attachment| attachment.room_id == room_id)
+            .await;
+
+        Ok(())
+    }
+
+    /// Messages starting with a slash are handled as slash commands, anything
+    /// else is posted to the room
+    async fn send_message(&self, command: SendMessage) -> Result<()> {
+        match Invocation::parse(&command) {
+            Some(invocation) => self.run_slash_command(invocation).await,
+            None => self.post_message(command).await,
+        }
+    }
+
+    pub(crate) async fn post_message(&self, command: SendMessage) -> Result<()> {
+        let (room_id, message_id) = (command.room_id, command.id);
+        let urls = extract_urls(&command.message);
+        let attachments = command.attachments.clone();
+        self.claim_attachments(&attachments).await?;
+        if let Err(error) = self.execute_and_dispatch(command).await {
+            self.release_attachments(&attachments).await;
+            return Err(error);
+        }
+
+        if !urls.is_empty() {
+            tokio::spawn(unfurl_message(self.clone(), room_id, message_id, urls).in_current_span());
+        }
+
+        Ok(())
+    }
+
+    /// Fails with 403 unless the user is a member of the room
+    pub(crate) async fn ensure_member(&self, room_id: Uuid, username: &str) -> Result<()> {
+        let is_member = self
+            .users_in_room
+            .lock()
+            .await
+            .get(&room_id)
+            .is_some_and(|users| users.iter().any(|user| user == username));
+        if !is_member {
+            return Err(Error::from_status(StatusCode::FORBIDDEN));
+        }
+
+        Ok(())
+    }
+
+    /// Look up attachments that were uploaded before sending a message
+    async fn uploaded_attachments(&self, ids: &[Uuid]) -> Result<Vec<Attachment>> {
+        let uploaded = self.attachments.lock().await;
+        let mut attachments = Vec::new();
+
+        for id in ids {
+            match uploaded.get(id) {
+                Some(attachment) => attachments.push(attachment.clone()),
+                None => return Err(Error::from_status(StatusCode::BAD_REQUEST)),
+            }
+        }
 
-    let api_service = OpenApiService::new(all_endpoints, "Hello World", "1.0")
-        .server("http://localhost:3000/api");
+        Ok(attachments)
+    }
+
+    /// The current state of a room. Rooms are cached, so only the events
+    /// appended since the room was last loaded are replayed.
+    async fn load_room(&self, room_id: Uuid) -> RoomAggregate {
+        let cached = self.room_aggregates.lock().await.get(&room_id).cloned();
+        let mut room = cached.unwrap_or_else(|| RoomAggregate::from_history(room_id, &[]));
+        room.catch_up(&self.event_store.load_since(room_id, room.version()).await);
+
+        let mut room_aggregates = self.room_aggregates.lock().await;
+        let cached = room_aggregates
+            .entry(room_id)
+            .or_insert_with(|| room.clone());
+        if cached.version() < room.version() {
+            *cached = room.clone();
+        }
+
+        room
+    }
+
+    /// Decide on the events for a command based on the history of its room,
+    /// and append them unless the room was changed by someone else in the
+    /// meantime. Commands are decided again on the changed room a few times
+    /// before giving up with a conflict.
+    pub(crate) async fn execute(&self, command: impl Into<Command>) -> Result<Vec<DomainEvent>> {
+        let command = command.into();
+        let room_id = command.room_id();
+        let mut attempt = 1;
+
+        loop {
+            let room = self.load_room(room_id).await;
+            let events = self.commands.handle(&room, command.clone())?;
+            if events.is_empty() {
+                return Ok(events);
+            }
+
+            match self
+                .event_store
+                .append(room_id, room.version(), events.clone())
+                .await
+            {
+                Ok(_) => return Ok(events),
+                Err(conflict) if attempt < self.config.limits.command_attempts => {
+                    tracing::debug!(%conflict, attempt, "Retrying concurrent change to a room");
+                    attempt += 1;
+                }
+                Err(conflict) => {
+                    tracing::info!(%conflict, "Rejected concurrent change to a room");
+                    return Err(Error::from_status(StatusCode::CONFLICT));
+                }
+            }
+        }
+    }
+
+    /// Execute a command and dispatch the resulting events, which also brings
+    /// the read models up to date
+    pub(crate) async fn execute_and_dispatch(&self, command: impl Into<Command>) -> Result<()> {
+        for event in self.execute(command).await? {
+            self.bus.dispatch_event(event).await;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn create_app(ctx: Context) -> Result<impl Endpoint, Box<dyn std::error::Error>> {
+    let all_endpoints = (
+        Api,
+        events::Api,
+        attachments::Api,
+        websocket::Api,
+        admin::Api,
+        audit::Api,
+        webhooks::Api,
+        incoming_webhooks::Api,
+    );
+
+    let api_service =
+        OpenApiService::new(all_endpoints, "Hello World", "1.0").server(ctx.config.api_url());
 
     let ui = api_service.swagger_ui();
     let spec = api_service.spec_endpoint();
-    let cors = Cors::new().allow_credentials(true);
-    let cookie_config = CookieConfig::default().same_site(SameSite::Strict);
+    let trusted_origins = ctx.config.trusted_origins();
+    let cors = Cors::new()
+        .allow_credentials(true)
+        .allow_origins(trusted_origins.clone())
+        .expose_header(REQUEST_ID_HEADER);
+    let csrf = CsrfProtection::new(trusted_origins);
+    let cookie_config = CookieConfig::signed(ctx.config.cookie_key())
+        .same_site(SameSite::from(ctx.config.session.same_site))
+        .secure(ctx.config.session.secure)
+        .max_age(Duration::from_secs(
+            ctx.config.session.absolute_timeout_secs,
+        ));
+    let session = ServerSession::new(cookie_config, ctx.sessions.clone());
+    let metrics = MetricsMiddleware::new(ctx.metrics.clone());
 
     Ok(Route::new()
         .nest("/api", api_service)
         .nest("/api/docs", ui)
         .nest("/spec.json", spec)
-        .nest(
-            "/",
-            StaticFilesEndpoint::new("./ui/dist")
-                .index_file("index.html")
-                .fallback_to_index(),
-        )
+        .at("/metrics", get(metrics::metrics_endpoint))
+        .at("/healthz", get(health::healthz))
+        .at("/readyz", get(health::readyz))
+        .at("/version", get(health::version))
+        .nest("/", ui_endpoint())
         .data(ctx)
-        .with(CookieSession::new(cookie_config))
-        .with(cors))
+        .with(session)
+        .with(csrf)
+        .with(metrics)
+        .with(cors)
+        .with(RequestTracing))
+}
+
+#[cfg(feature = "embed-ui")]
+fn ui_endpoint() -> impl Endpoint {
+    ui::EmbeddedUiEndpoint::<ui::UiAssets>::new()
+}
+
+/// During development the UI is served from disk so that it can be rebuilt
+/// without recompiling the server
+#[cfg(not(feature = "embed-ui"))]
+fn ui_endpoint() -> impl Endpoint {
+    StaticFilesEndpoint::new("./ui/dist")
+        .index_file("index.html")
+        .fallback_to_index()
+}
+
+#[cfg(feature = "embed-ui")]
+fn ui_assets_present() -> bool {
+    use rust_embed::RustEmbed;
+
+    ui::UiAssets::get("index.html").is_some()
+}
+
+#[cfg(not(feature = "embed-ui"))]
+fn ui_assets_present() -> bool {
+    std::path::Path::new("./ui/dist/index.html").is_file()
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "poem=debug");
-    }
-    tracing_subscriber::fmt::init();
-
-    let (tx, _rx) = broadcast::channel::<DomainEvent>(32);
-    let bus: ShareableEventBus = Arc::new(BroadcastingEventBus::from_broadcast(tx));
-    let ctx = Context {
-        bus,
-        messages_in_room: Arc::new(Mutex::new(HashMap::new())),
-        users_in_room: Arc::new(Mutex::new(HashMap::new())),
-        rooms: Arc::new(Mutex::new(Vec::new())),
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
     };
 
-    let app = create_app(ctx).await?.around(|ep, req| async move {
-        let uri = req.uri().clone();
-        let method = req.method().clone();
-        let resp = ep.get_response(req).await;
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(config.log.filter.as_str()));
+    match config.log.format {
+        LogFormat::Text => tracing_subscriber::fmt().with_env_filter(filter).init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init(),
+    }
 
-        if let Some(operation_id) = resp.data::<OperationId>() {
-            println!("[{}] {} {} {}", operation_id, method, uri, resp.status());
-        } else {
-            println!("{} {} {}", method, uri, resp.status());
-        }
+    if config.session.secret.is_none() {
+        tracing::warn!("No session secret configured, sessions will not survive a restart");
+    }
 
-        Ok(resp)
-    });
+    let bind_address = config.server.bind_address.clone();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let bus: ShareableEventBus = Arc::new(BroadcastingEventBus::default());
+    let ctx = Context::from_config(bus, config);
+    ctx.sessions.restore().await?;
+    ctx.audit_log.restore().await?;
+    ctx.banned_users.restore().await?;
+    ctx.webhooks.start(ctx.bus.as_ref()).await;
+    ctx.incoming_webhooks.start(ctx.bus.as_ref()).await;
 
-    Server::new(TcpListener::bind("0.0.0.0:3000"))
-        .run(app)
+    let app = create_app(ctx.clone()).await?;
+
+    Server::new(TcpListener::bind(bind_address))
+        .run_with_graceful_shutdown(app, shutdown_signal(ctx.clone()), Some(shutdown_timeout))
         .await?;
 
+    // Persist what was only kept in memory while running
+    ctx.sessions.flush().await?;
+
     Ok(())
 }
 
+/// Resolves on ctrl-c or SIGTERM, after telling all connected clients that
+/// they should reconnect
+async fn shutdown_signal(ctx: Context) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down");
+
+    ctx.bus
+        .dispatch_event(DomainEvent::ServerShuttingDown(ServerShuttingDown {
+            reconnect_after_ms: ctx.config.server.reconnect_after_ms,
+        }))
+        .await;
+}
+
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, sync::Arc};
+    use std::{collections::HashMap, sync::Arc, time::Duration};
 
     use crate::{
+        config::{Config, StoreKind},
         events::{
-            DomainEvent, MessageWasSend, RecordingEventBus, RoomWasCreated, RoomWasRemoved,
-            UserJoinedRoom, UserLeftRoom, UserLoggedIn, UserLoggedOut,
+            BroadcastingEventBus, DomainEvent, EventBus, MessageWasSend, RecordingEventBus,
+            RoomWasCreated, RoomWasRemoved, ServerShuttingDown, SessionWasRevoked, UserJoinedRoom,
+            UserLeftRoom, UserLoggedIn, UserLoggedOut, UserWasMentioned,
         },
-        Context,
+        ui_assets_present, Context,
     };
 
     use super::create_app;
+    use crate::{
+        attachments::BlobStore,
+        events::MessageLinkPreviewAdded,
+        link_preview::{FetchError, LinkPreview, LinkPreviewFetcher},
+        markdown::MessageFormat,
+        rate_limit::{Limit, RateLimiter, SEND_MESSAGE},
+    };
 
     use poem::{
+        async_trait,
         http::{
             header::{self, SET_COOKIE},
             StatusCode,
         },
-        test::TestClient,
+        test::{TestClient, TestForm, TestFormField},
+        Endpoint,
     };
     use serde_json::json;
     use time::{format_description::well_known::Rfc3339, OffsetDateTime};
-    use tokio::sync::Mutex;
     use uuid::Uuid;
 
-    #[tokio::test]
-    async fn test_chat_api() {
-        let bus = Arc::new(RecordingEventBus::default());
-        let app = create_app(Context {
-            bus: bus.clone(),
-            messages_in_room: Arc::new(Mutex::new(HashMap::new())),
-            users_in_room: Arc::new(Mutex::new(HashMap::new())),
-            rooms: Arc::new(Mutex::new(Vec::new())),
-        })
-        .await
-        .unwrap();
-        let client = TestClient::new(app);
-
-        // Login as Jane Doe
-        let body = json!({ "username": "Jane" });
+    /// Log in and return the session cookie
+    async fn login<E: Endpoint>(client: &TestClient<E>, username: &str) -> String {
         let resp = client
             .post("/api/session")
             .header(header::CONTENT_TYPE, "application/json")
-            .body(body.to_string())
+            .body(json!({ "username": username }).to_string())
             .send()
             .await;
+        resp.assert_status_is_ok();
 
-        let cookie_jane = resp
-            .0
+        resp.0
             .headers()
             .get(SET_COOKIE)
             .and_then(|value| value.to_str().ok())
-            .expect("Failed to get session cookie");
+            .expect("Failed to get session cookie")
+            .to_string()
+    }
 
-        // Login as John Doe
-        let body = json!({ "username": "John" });
-        let resp = client
-            .post("/api/session")
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body.to_string())
-            .send()
-            .await;
+    #[tokio::test]
+    async fn test_chat_api() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
 
-        let cookie = resp
-            .0
-            .headers()
-            .get(SET_COOKIE)
-            .and_then(|value| value.to_str().ok())
-            .expect("Failed to get session cookie");
+        // Login as Jane Doe
+        let cookie_jane = login(&client, "Jane").await;
+
+        // Login as John Doe
+        let cookie = login(&client, "John").await;
 
         // chat
         let room_id = Uuid::new_v4();
@@ -640,7 +1126,7 @@ mod test {
         let resp = client
             .post("/api/rooms")
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .body(body.to_string())
             .send()
             .await;
@@ -676,7 +1162,7 @@ mod test {
         let resp = client
             .post(format!("/api/rooms/{}/users", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie_jane)
+            .header(header::COOKIE, &cookie_jane)
             .body(
                 json!({
                 "joined_at": "2024-06-09T12:00:00Z"
@@ -721,7 +1207,7 @@ mod test {
         let resp = client
             .post(format!("/api/rooms/{}/users", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie_jane)
+            .header(header::COOKIE, &cookie_jane)
             .body(
                 json!({
                 "joined_at": "2024-06-09T12:00:00Z"
@@ -771,7 +1257,7 @@ mod test {
         let resp = client
             .post(format!("/api/rooms/{}/messages", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .body(body.to_string())
             .send()
             .await;
@@ -809,7 +1295,11 @@ mod test {
                     room_id,
                     username: "John".to_string(),
                     message: "Hoi".to_string(),
+                    format: MessageFormat::Plain,
+                    rendered_html: "Hoi".to_string(),
                     send_at: now,
+                    attachments: Vec::new(),
+                    bot: false,
                 }),
             ]
         );
@@ -817,7 +1307,7 @@ mod test {
         let resp = client
             .delete(format!("/api/rooms/{}/users", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .body(
                 json!({
                 "left_at": "2024-06-09T12:00:00Z"
@@ -860,7 +1350,11 @@ mod test {
                     room_id,
                     username: "John".to_string(),
                     message: "Hoi".to_string(),
+                    format: MessageFormat::Plain,
+                    rendered_html: "Hoi".to_string(),
                     send_at: now,
+                    attachments: Vec::new(),
+                    bot: false,
                 }),
                 DomainEvent::UserLeftRoom(UserLeftRoom {
                     room_id,
@@ -874,7 +1368,7 @@ mod test {
         let resp = client
             .get("/api/rooms")
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .send()
             .await;
 
@@ -886,9 +1380,15 @@ mod test {
                 "last_message": {
                     "id": message_id,
                     "message": "Hoi",
+                    "format": "plain",
+                    "rendered_html": "Hoi",
                     "room_id": room_id,
                     "send_at": "2024-06-09T12:00:00Z",
-                    "username": "John"
+                    "username": "John",
+                    "mentions": [],
+                    "attachments": [],
+                    "link_previews": [],
+                    "bot": false
                 },
                 "name": "Lustrum Crash & Compile"
             }
@@ -899,7 +1399,7 @@ mod test {
         let resp = client
             .get(format!("/api/rooms/{}", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .send()
             .await;
 
@@ -908,13 +1408,20 @@ mod test {
             {
                 "id": room_id,
                 "name": "Lustrum Crash & Compile",
-                "users": ["John", "Jane"],
+                "topic": null,
+                "users": ["Jane"],
                 "messages": [{
                     "id": message_id,
                     "message": "Hoi",
+                    "format": "plain",
+                    "rendered_html": "Hoi",
                     "room_id": room_id,
                     "send_at": "2024-06-09T12:00:00Z",
-                    "username": "John"
+                    "username": "John",
+                    "mentions": [],
+                    "attachments": [],
+                    "link_previews": [],
+                    "bot": false
                 }],
             }
         ))
@@ -924,7 +1431,7 @@ mod test {
         let resp = client
             .get(format!("/api/rooms/{}/messages", room_id))
             .header(header::CONTENT_TYPE, "application/json")
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .send()
             .await;
 
@@ -934,9 +1441,15 @@ mod test {
                 {
                     "id": message_id,
                     "message": "Hoi",
+                    "format": "plain",
+                    "rendered_html": "Hoi",
                     "room_id": room_id,
                     "send_at": "2024-06-09T12:00:00Z",
-                    "username": "John"
+                    "username": "John",
+                    "mentions": [],
+                    "attachments": [],
+                    "link_previews": [],
+                    "bot": false
                 },
             ],
             "pagination": {
@@ -955,7 +1468,7 @@ mod test {
             .expect("Failed to parse date string");
         let resp = client
             .delete(format!("/api/rooms/{}", room_id))
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .header(header::CONTENT_TYPE, "application/json")
             .body(
                 json!({
@@ -970,14 +1483,14 @@ mod test {
 
         let resp = client
             .get(format!("/api/rooms/{}", room_id))
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .send()
             .await;
         resp.assert_status(StatusCode::NOT_FOUND);
 
         let resp = client
             .get(format!("/api/rooms/{}/messages", room_id))
-            .header(header::COOKIE, cookie)
+            .header(header::COOKIE, &cookie)
             .send()
             .await;
 
@@ -1026,7 +1539,11 @@ mod test {
                     room_id,
                     username: "John".to_string(),
                     message: "Hoi".to_string(),
+                    format: MessageFormat::Plain,
+                    rendered_html: "Hoi".to_string(),
                     send_at: now,
+                    attachments: Vec::new(),
+                    bot: false,
                 }),
                 DomainEvent::UserLeftRoom(UserLeftRoom {
                     room_id,
@@ -1044,14 +1561,7 @@ mod test {
     #[tokio::test]
     async fn test_login() {
         let bus = Arc::new(RecordingEventBus::default());
-        let app = create_app(Context {
-            bus: bus.clone(),
-            messages_in_room: Arc::new(Mutex::new(HashMap::new())),
-            users_in_room: Arc::new(Mutex::new(HashMap::new())),
-            rooms: Arc::new(Mutex::new(Vec::new())),
-        })
-        .await
-        .unwrap();
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
         let client = TestClient::new(app);
 
         let body = json!({ "username": "John" });
@@ -1099,9 +1609,9 @@ mod test {
         resp.assert_status_is_ok();
 
         let recorded_events = bus.recorded_events().await;
-        assert_eq!(recorded_events.len(), 2);
+        assert_eq!(recorded_events.len(), 3);
         assert_eq!(
-            recorded_events,
+            recorded_events[..2],
             vec![
                 DomainEvent::UserLoggedIn(UserLoggedIn {
                     username: "John".to_string()
@@ -1111,5 +1621,1906 @@ mod test {
                 }),
             ]
         );
+        assert!(matches!(
+            &recorded_events[2],
+            DomainEvent::SessionWasRevoked(SessionWasRevoked { username, session_id: Some(_) })
+                if username == "John"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let mut cookies = Vec::new();
+        for _ in 0..3 {
+            cookies.push(login(&client, "Jane").await);
+        }
+
+        let get_session = |cookie: String| {
+            client
+                .get("/api/session")
+                .header(header::COOKIE, cookie)
+                .send()
+        };
+
+        // The cookie is signed, so it can not be forged
+        let (name, value) = cookies[0].split_once('=').unwrap();
+        let forged = format!("{}=x{}", name, value);
+        get_session(forged)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // Logging out invalidates the session on the server, not only the cookie
+        client
+            .delete("/api/session")
+            .header(header::COOKIE, &cookies[0])
+            .send()
+            .await
+            .assert_status_is_ok();
+        get_session(cookies[0].clone())
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        get_session(cookies[1].clone()).await.assert_status_is_ok();
+
+        client
+            .delete("/api/sessions")
+            .header(header::COOKIE, &cookies[2])
+            .send()
+            .await
+            .assert_status_is_ok();
+        get_session(cookies[1].clone())
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        get_session(cookies[2].clone())
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_event_streams() {
+        let bus = Arc::new(BroadcastingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie = login(&client, "Jane").await;
+
+        let resp = client
+            .get("/api/events")
+            .header(header::COOKIE, cookie)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        let event = DomainEvent::ServerShuttingDown(ServerShuttingDown {
+            reconnect_after_ms: 1000,
+        });
+        bus.dispatch_event(event.clone()).await;
+
+        let body = tokio::time::timeout(Duration::from_secs(1), resp.0.into_body().into_string())
+            .await
+            .expect("The event stream should end after shutting down")
+            .unwrap();
+        let (retry, body) = split_retry(&body);
+        assert!((1000..=2000).contains(&retry));
+        assert_eq!(
+            body,
+            format!("event: ServerShuttingDown\ndata: {}\n\n", json!(event))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logout_closes_event_streams() {
+        let bus = Arc::new(BroadcastingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_phone = login(&client, "Jane").await;
+        let cookie_laptop = login(&client, "Jane").await;
+        let follow = |cookie: &str| {
+            client
+                .get("/api/events")
+                .header(header::COOKIE, cookie)
+                .send()
+        };
+        let ended = |resp: poem::test::TestResponse| async move {
+            tokio::time::timeout(Duration::from_secs(1), resp.0.into_body().into_string())
+                .await
+                .expect("The event stream should end after logging out")
+                .unwrap()
+        };
+
+        // Logging out ends the streams of that session only
+        let phone = follow(&cookie_phone).await;
+        let laptop = follow(&cookie_laptop).await;
+        client
+            .delete("/api/session")
+            .header(header::COOKIE, &cookie_phone)
+            .send()
+            .await
+            .assert_status_is_ok();
+        let body = ended(phone).await;
+        let last = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .next_back()
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(last).unwrap();
+        assert_eq!(event["type"], "SessionWasRevoked");
+        assert!(event["payload"]["session_id"].is_string());
+
+        // Logging out everywhere ends the others
+        client
+            .delete("/api/sessions")
+            .header(header::COOKIE, &cookie_laptop)
+            .send()
+            .await
+            .assert_status_is_ok();
+        let body = ended(laptop).await;
+        assert!(body.ends_with(&format!(
+            "event: SessionWasRevoked\ndata: {}\n\n",
+            json!(DomainEvent::SessionWasRevoked(SessionWasRevoked {
+                username: "Jane".to_string(),
+                session_id: None,
+            }))
+        )));
+    }
+
+    /// Take the reconnection time out of an event stream, as it is jittered
+    fn split_retry(body: &str) -> (u64, String) {
+        let start = body.find("retry: ").expect("The stream should set a retry");
+        let end = start + body[start..].find("\n\n").unwrap() + 2;
+        let retry = body[start + "retry: ".len()..end - 2].parse().unwrap();
+
+        (retry, format!("{}{}", &body[..start], &body[end..]))
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let events_of = |cookie: &str| {
+            client
+                .get("/api/events")
+                .header(header::COOKIE, cookie)
+                .send()
+        };
+        let stream_jane = events_of(&cookie_jane).await;
+        stream_jane.assert_status_is_ok();
+        let stream_john = events_of(&cookie_john).await;
+        stream_john.assert_status_is_ok();
+        let logged_in = bus.recorded_events().await.len();
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "message": "Hoi @John",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        bus.dispatch_event(DomainEvent::ServerShuttingDown(ServerShuttingDown {
+            reconnect_after_ms: 1000,
+        }))
+        .await;
+
+        let dispatched = bus.recorded_events().await.split_off(logged_in);
+        assert_eq!(dispatched.len(), 5);
+        for (resp, username) in [(stream_jane, "Jane"), (stream_john, "John")] {
+            let body =
+                tokio::time::timeout(Duration::from_secs(1), resp.0.into_body().into_string())
+                    .await
+                    .expect("The event stream should end after shutting down")
+                    .unwrap();
+            let (_, body) = split_retry(&body);
+
+            // The mention is only sent to John
+            let expected = dispatched
+                .iter()
+                .filter(|event| event.is_visible_to(Some(username)))
+                .map(|event| format!("event: {}\ndata: {}\n\n", event.name(), json!(event)))
+                .collect::<String>();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_schemas() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let resp = client.get("/api/events/schema").send().await;
+        resp.assert_status_is_ok();
+        let schemas = resp.json().await;
+        let message_was_send = schemas
+            .value()
+            .array()
+            .iter()
+            .find(|schema| schema.object().get("name").string() == "MessageWasSend")
+            .expect("MessageWasSend should be described");
+        let message_was_send = message_was_send.object();
+        message_was_send.get("version").assert_i64(4);
+        message_was_send
+            .get("description")
+            .assert_string("A message was sent to a room");
+        message_was_send
+            .get("schema")
+            .object()
+            .get("properties")
+            .object()
+            .get("type")
+            .object()
+            .get("const")
+            .assert_string("MessageWasSend");
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie = login(&client, "Jane").await;
+
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .get("/api/rooms")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let resp = client.get("/metrics").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("text/plain; version=0.0.4");
+        let body = resp.0.into_body().into_string().await.unwrap();
+
+        for line in [
+            r#"chat_http_requests_total{method="POST",operation_id="rooms_post",status="200"} 1"#,
+            r#"chat_http_requests_total{method="GET",operation_id="rooms_get",status="401"} 1"#,
+            r#"chat_http_request_duration_seconds_count{operation_id="session_post"} 1"#,
+            r#"chat_events_dispatched_total{event="RoomWasCreated"} 1"#,
+            r#"chat_events_dispatched_total{event="UserLoggedIn"} 1"#,
+            "chat_events_lagged_total 0",
+            "chat_rooms 1",
+            "chat_messages 0",
+            r#"chat_rate_limited_requests_total{operation_id="rooms_post",outcome="allowed"} 1"#,
+            r#"chat_rate_limited_requests_total{operation_id="rooms_post",outcome="rejected"} 0"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} not in {}", line, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut ctx = Context::new(bus.clone());
+        let dir = std::env::temp_dir().join(format!("blobs-{}", Uuid::new_v4()));
+        ctx.blobs = BlobStore::new(&dir);
+        let client = TestClient::new(create_app(ctx.clone()).await.unwrap());
+
+        let resp = client.get("/healthz").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_text("ok").await;
+
+        let resp = client.get("/version").send().await;
+        resp.assert_status_is_ok();
+        let info = resp.json().await;
+        info.value()
+            .object()
+            .get("version")
+            .assert_string(env!("CARGO_PKG_VERSION"));
+
+        let resp = client.get("/readyz").send().await;
+        let expected_status = if ui_assets_present() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        resp.assert_status(expected_status);
+        let readiness = resp.json().await;
+        let checks = readiness.value().object().get("checks").object();
+        checks.get("event_bus").assert_bool(true);
+        checks.get("store").assert_bool(true);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // A file where the blobs directory should be can not be written to
+        std::fs::write(&dir, b"").unwrap();
+        let resp = client.get("/readyz").send().await;
+        resp.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        let readiness = resp.json().await;
+        readiness.value().object().get("ready").assert_bool(false);
+        let checks = readiness.value().object().get("checks").object();
+        checks.get("store").assert_bool(false);
+        std::fs::remove_file(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mentions() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let app = create_app(Context::new(bus.clone())).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Mentions",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        let message_id = Uuid::new_v4();
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": message_id,
+                    "message": "Hoi @Jane, this is @John",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        // Sending the same message twice is rejected by the room
+        client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": message_id,
+                    "message": "Hoi @Jane, this is @John",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        let now = OffsetDateTime::parse("2024-06-09T12:00:00Z", &Rfc3339)
+            .expect("Failed to parse date string");
+        let recorded_events = bus.recorded_events().await;
+        assert_eq!(
+            recorded_events.last(),
+            Some(&DomainEvent::UserWasMentioned(UserWasMentioned {
+                message_id,
+                room_id,
+                username: "Jane".to_string(),
+                mentioned_by: "John".to_string(),
+                mentioned_at: now,
+            }))
+        );
+        // John mentioning himself should not produce an event
+        assert_eq!(
+            recorded_events
+                .iter()
+                .filter(|event| matches!(event, DomainEvent::UserWasMentioned(_)))
+                .count(),
+            1
+        );
+
+        let resp = client
+            .get(format!("/api/rooms/{}/messages", room_id))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("items")
+            .array()
+            .get(0)
+            .object()
+            .get("mentions")
+            .assert_string_array(&["Jane"]);
+
+        let resp = client
+            .get("/api/mentions")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([{
+            "message_id": message_id,
+            "room_id": room_id,
+            "mentioned_by": "John",
+            "mentioned_at": "2024-06-09T12:00:00Z"
+        }]))
+        .await;
+
+        let resp = client
+            .get("/api/mentions")
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([])).await;
+
+        // Reading a mention removes it from the unread mentions
+        let resp = client
+            .delete(format!("/api/mentions/{}", message_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        let resp = client
+            .get("/api/mentions")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_json(json!([])).await;
+
+        let resp = client
+            .delete(format!("/api/mentions/{}", message_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_attachments() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.limits.max_attachment_size = 64 * 1024;
+        let mut ctx = Context::from_config(bus.clone(), config);
+        let blob_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        ctx.blobs = BlobStore::new(&blob_dir);
+        let app = create_app(ctx).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        let resp = client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Screenshots",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(512, 256)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let upload = |cookie: &str, content_type: &str, data: Vec<u8>| {
+            client
+                .post(format!("/api/rooms/{}/attachments", room_id))
+                .header(header::COOKIE, cookie)
+                .multipart(
+                    TestForm::new().field(
+                        TestFormField::bytes(data)
+                            .name("file")
+                            .filename("screenshot.png")
+                            .content_type(content_type),
+                    ),
+                )
+                .send()
+        };
+
+        // Only members of a room can upload attachments
+        let resp = upload(&cookie_jane, "image/png", png.clone()).await;
+        resp.assert_status(StatusCode::FORBIDDEN);
+
+        let resp = upload(&cookie_john, "application/x-msdownload", png.clone()).await;
+        resp.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let resp = upload(&cookie_john, "image/png", b"not an image".to_vec()).await;
+        resp.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // Oversized bodies are rejected while they are being received
+        let resp = upload(&cookie_john, "text/plain", vec![b'a'; 1024 * 1024]).await;
+        resp.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = client
+            .post(format!("/api/rooms/{}/attachments", room_id))
+            .header(header::COOKIE, &cookie_john)
+            .header(header::CONTENT_LENGTH, 1024 * 1024 * 1024)
+            .content_type("multipart/form-data; boundary=x")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = upload(&cookie_john, "image/png", png.clone()).await;
+        resp.assert_status_is_ok();
+        let attachment = resp.json().await;
+        let attachment = attachment.value().object();
+        attachment.get("filename").assert_string("screenshot.png");
+        attachment.get("content_type").assert_string("image/png");
+        attachment.get("size").assert_i64(png.len() as i64);
+        let attachment_id = attachment.get("id").string().to_string();
+        let hash = attachment.get("hash").string().to_string();
+        let blob_path = blob_dir.join(&hash[..2]).join(&hash);
+        assert!(blob_path.exists());
+
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "message": "Look at this",
+                    "send_at": "2024-06-09T12:00:00Z",
+                    "attachments": [Uuid::new_v4()]
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "message": "Look at this",
+                    "send_at": "2024-06-09T12:00:00Z",
+                    "attachments": [attachment_id]
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        // Uploads are used up by the message they are sent with
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "message": "Look at this again",
+                    "send_at": "2024-06-09T12:00:00Z",
+                    "attachments": [attachment_id]
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+
+        let resp = client
+            .get(format!("/api/rooms/{}/messages", room_id))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.json()
+            .await
+            .value()
+            .object()
+            .get("items")
+            .array()
+            .get(0)
+            .object()
+            .get("attachments")
+            .array()
+            .get(0)
+            .object()
+            .get("id")
+            .assert_string(&attachment_id);
+
+        let resp = client
+            .get(format!(
+                "/api/rooms/{}/attachments/{}",
+                room_id, attachment_id
+            ))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("image/png");
+        resp.assert_header(
+            header::CONTENT_DISPOSITION,
+            "inline; filename=\"screenshot.png\"",
+        );
+        resp.assert_header("x-content-type-options", "nosniff");
+        resp.assert_bytes(png).await;
+
+        // Anything but images is downloaded instead of shown
+        let resp = upload(&cookie_john, "text/plain", b"<script></script>".to_vec()).await;
+        let text_id = resp
+            .json()
+            .await
+            .value()
+            .object()
+            .get("id")
+            .string()
+            .to_string();
+        let resp = client
+            .get(format!("/api/rooms/{}/attachments/{}", room_id, text_id))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_header(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"screenshot.png\"",
+        );
+        resp.assert_header("x-content-type-options", "nosniff");
+
+        let resp = client
+            .get(format!(
+                "/api/rooms/{}/attachments/{}/thumbnail",
+                room_id, attachment_id
+            ))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("image/png");
+        let thumbnail = image::load_from_memory(&resp.0.into_body().into_vec().await.unwrap())
+            .expect("Thumbnail should be a valid image");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+        // Jane can only download the attachment after joining the room
+        let resp = client
+            .get(format!(
+                "/api/rooms/{}/attachments/{}",
+                room_id, attachment_id
+            ))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::FORBIDDEN);
+
+        let resp = client
+            .post(format!("/api/rooms/{}/users", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(json!({ "joined_at": "2024-06-09T12:00:00Z" }).to_string())
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        let resp = client
+            .get(format!(
+                "/api/rooms/{}/attachments/{}",
+                room_id, attachment_id
+            ))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        // Blobs are removed together with their room
+        client
+            .delete(format!("/api/rooms/{}", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(json!({ "removed_at": "2024-06-09T14:00:00Z" }).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+        assert!(!blob_path.exists());
+    }
+
+    struct StubLinkPreviewFetcher;
+
+    #[async_trait]
+    impl LinkPreviewFetcher for StubLinkPreviewFetcher {
+        async fn fetch(&self, url: &url::Url) -> Result<LinkPreview, FetchError> {
+            match url.host_str() {
+                Some("example.com") => Ok(LinkPreview {
+                    url: url.to_string(),
+                    title: Some("Example Domain".to_string()),
+                    description: None,
+                    image: None,
+                    site_name: None,
+                }),
+                _ => Err(FetchError::NoMetadata),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_previews() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut ctx = Context::new(bus.clone());
+        ctx.link_preview_fetcher = Arc::new(StubLinkPreviewFetcher);
+        let app = create_app(ctx).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Links",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        let message_id = Uuid::new_v4();
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": message_id,
+                    "message": "See https://example.com and https://unknown.example.org",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+
+        // Previews are fetched in the background
+        let expected = DomainEvent::MessageLinkPreviewAdded(MessageLinkPreviewAdded {
+            message_id,
+            room_id,
+            preview: LinkPreview {
+                url: "https://example.com/".to_string(),
+                title: Some("Example Domain".to_string()),
+                description: None,
+                image: None,
+                site_name: None,
+            },
+        });
+        for _ in 0..100 {
+            if bus.recorded_events().await.contains(&expected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let recorded_events = bus.recorded_events().await;
+        assert_eq!(recorded_events.last(), Some(&expected));
+
+        let resp = client
+            .get(format!("/api/rooms/{}/messages", room_id))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let previews = json
+            .value()
+            .object()
+            .get("items")
+            .array()
+            .get(0)
+            .object()
+            .get("link_previews")
+            .array();
+        previews.assert_len(1);
+        previews
+            .get(0)
+            .object()
+            .get("title")
+            .assert_string("Example Domain");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_version_conflict() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.limits.command_attempts = 1;
+        config.limits.max_message_length = 65536;
+        let mut ctx = Context::from_config(bus.clone(), config);
+        ctx.rate_limiter = Arc::new(RateLimiter::new(HashMap::new()));
+        let app = Arc::new(create_app(ctx.clone()).await.unwrap());
+        let client = TestClient::new(app.clone());
+
+        let cookie = login(&client, "Jane").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Busy",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Messages sent at the same time are decided on the same version of
+        // the room, without retries only one of them can be stored
+        let mut sent = 0;
+        let mut conflicts = 0;
+        for _ in 0..50 {
+            let requests = (0..8)
+                .map(|_| {
+                    let (app, cookie) = (app.clone(), cookie.clone());
+                    tokio::spawn(async move {
+                        TestClient::new(app)
+                            .post(format!("/api/rooms/{}/messages", room_id))
+                            .header(header::CONTENT_TYPE, "application/json")
+                            .header(header::COOKIE, cookie)
+                            .body(
+                                json!({
+                                    "id": Uuid::new_v4(),
+                                    "message": "**Busy** ".repeat(4096),
+                                    "send_at": "2024-06-09T12:00:00Z"
+                                })
+                                .to_string(),
+                            )
+                            .send()
+                            .await
+                            .0
+                            .status()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for request in requests {
+                match request.await.unwrap() {
+                    StatusCode::OK => sent += 1,
+                    StatusCode::CONFLICT => conflicts += 1,
+                    status => panic!("Unexpected status {}", status),
+                }
+            }
+            if conflicts > 0 {
+                break;
+            }
+        }
+
+        assert!(conflicts > 0, "No request ran into a version conflict");
+        assert_eq!(ctx.messages_in_room.lock().await[&room_id].len(), sent);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_send_message() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut ctx = Context::new(bus.clone());
+        ctx.rate_limiter = Arc::new(RateLimiter::new(HashMap::from([(
+            SEND_MESSAGE.to_string(),
+            Limit::new(2, Duration::from_secs(60)),
+        )])));
+        let app = create_app(ctx).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_john)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Spam",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        let send_message = |cookie: &str| {
+            client
+                .post(format!("/api/rooms/{}/messages", room_id))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, cookie)
+                .body(
+                    json!({
+                        "id": Uuid::new_v4(),
+                        "message": "Spam",
+                        "send_at": "2024-06-09T12:00:00Z"
+                    })
+                    .to_string(),
+                )
+                .send()
+        };
+
+        send_message(&cookie_john).await.assert_status_is_ok();
+        send_message(&cookie_john).await.assert_status_is_ok();
+
+        let resp = send_message(&cookie_john).await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        resp.assert_header(header::RETRY_AFTER, "60");
+
+        // Limits are kept per user
+        send_message(&cookie_jane).await.assert_status_is_ok();
+
+        // Unauthenticated requests are rejected before they use up any tokens
+        let resp = client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body("{}")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::UNAUTHORIZED);
+
+        let recorded_events = bus.recorded_events().await;
+        assert_eq!(
+            recorded_events
+                .iter()
+                .filter(|event| matches!(event, DomainEvent::MessageWasSend(_)))
+                .count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        config.admin.store = StoreKind::File;
+        config.storage.data_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let app = create_app(Context::from_config(bus.clone(), config.clone()))
+            .await
+            .unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_admin = login(&client, "Admin").await;
+        let cookie_jane = login(&client, "Jane").await;
+
+        let room_id = Uuid::new_v4();
+        let message_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": message_id,
+                    "message": "Spam",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        client
+            .get("/api/admin/rooms")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let resp = client
+            .get("/api/admin/rooms")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([{
+            "id": room_id,
+            "name": "Lustrum Crash & Compile",
+            "users": ["Jane"],
+            "message_count": 1
+        }]))
+        .await;
+
+        client
+            .delete(format!(
+                "/api/admin/rooms/{}/messages/{}",
+                room_id, message_id
+            ))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_admin)
+            .body(json!({ "reason": "Spam" }).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+        let resp = client
+            .get(format!("/api/rooms/{}/messages", room_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        let messages = resp.json().await;
+        messages.value().object().get("items").array().assert_len(0);
+
+        client
+            .delete(format!("/api/admin/rooms/{}/users/Jane", room_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        client
+            .put("/api/admin/bans/Jane")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_admin)
+            .body(json!({}).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Banned users are logged out and can not log in again
+        client
+            .get("/api/session")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .post("/api/session")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "username": "Jane" }).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // Nor can they, or anyone else without a session, follow the events
+        client
+            .get("/api/events")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        client
+            .get(format!("/api/events/{}", room_id))
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // Bans survive a restart
+        let restarted = Context::from_config(bus.clone(), config.clone());
+        restarted.banned_users.restore().await.unwrap();
+        assert!(restarted.is_banned("Jane").await);
+
+        let resp = client
+            .get("/api/admin/audit")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        let audit = resp.json().await;
+        let actions = audit
+            .value()
+            .array()
+            .iter()
+            .map(|entry| entry.object().get("action").string().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(actions, vec!["delete_message", "kick_user", "ban_user"]);
+
+        client
+            .delete("/api/admin/bans/Jane")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status_is_ok();
+        login(&client, "Jane").await;
+
+        let recorded_events = bus.recorded_events().await;
+        assert!(matches!(
+            recorded_events.last(),
+            Some(DomainEvent::UserLoggedIn(UserLoggedIn { username })) if username == "Jane"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_projections() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        let ctx = Context::from_config(bus.clone(), config);
+        let app = create_app(ctx.clone()).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_admin = login(&client, "Admin").await;
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .post(format!("/api/rooms/{}/messages", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": Uuid::new_v4(),
+                    "message": "Hoi @John",
+                    "send_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .get("/api/generate-events")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Lose the read models, the events are still stored
+        ctx.rooms.lock().await.clear();
+        ctx.messages_in_room.lock().await.clear();
+        ctx.users_in_room.lock().await.clear();
+        ctx.mentions.lock().await.clear();
+
+        client
+            .post("/api/admin/projections/rebuild")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let resp = client
+            .post("/api/admin/projections/rebuild")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        // The generated rooms are stored too, each with a created and joined event
+        resp.assert_json(json!({ "events": 24 })).await;
+        assert_eq!(ctx.rooms.lock().await.len(), 11);
+
+        let resp = client
+            .get(format!("/api/rooms/{}", room_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let room = json.value().object();
+        room.get("users").assert_string_array(&["Jane"]);
+        let messages = room.get("messages").array();
+        messages.assert_len(1);
+        messages
+            .get(0)
+            .object()
+            .get("mentions")
+            .assert_string_array(&["John"]);
+        assert_eq!(ctx.mentions.lock().await["John"].len(), 1);
+
+        // Reading a mention is stored, so it stays read after a rebuild
+        let message_id = ctx.mentions.lock().await["John"][0].message_id;
+        client
+            .delete(format!("/api/mentions/{}", message_id))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .post("/api/admin/projections/rebuild")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let resp = client
+            .get("/api/mentions")
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([])).await;
+        client
+            .delete(format!("/api/mentions/{}", message_id))
+            .header(header::COOKIE, &cookie_john)
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        let app = create_app(Context::from_config(bus.clone(), config))
+            .await
+            .unwrap();
+        let client = TestClient::new(app);
+
+        let cookie = login(&client, "Admin").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .delete(format!("/api/rooms/{}", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .header("x-request-id", "delete-room-1")
+            .body(json!({ "removed_at": "2024-06-09T14:00:00Z" }).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let resp = client
+            .get("/api/admin/audit")
+            .query("action", &"delete_room")
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let audit = resp.json().await;
+        let entries = audit.value().array();
+        entries.assert_len(1);
+        let entry = entries.get(0).object();
+        entry.get("sequence").assert_i64(1);
+        entry.get("actor").assert_string("Admin");
+        entry
+            .get("target")
+            .assert_string(&format!("room:{}", room_id));
+        entry.get("request_id").assert_string("delete-room-1");
+        entry
+            .get("event")
+            .object()
+            .get("type")
+            .assert_string("RoomWasRemoved");
+
+        // Logging out everywhere ends the session used for the request, so
+        // the audit log is read with a new one
+        client
+            .delete("/api/sessions")
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await
+            .assert_status_is_ok();
+        let cookie = login(&client, "Admin").await;
+
+        let resp = client
+            .get("/api/admin/audit/export")
+            .query("actor", &"Admin")
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/x-ndjson");
+        let export = resp.0.into_body().into_string().await.unwrap();
+        let actions = export
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["action"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![json!("delete_room"), json!("logout_everywhere")]
+        );
+
+        let resp = client
+            .get("/api/admin/audit")
+            .query("actor", &"Jane")
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await;
+        resp.assert_json(json!([])).await;
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_failure() {
+        // The data directory is a file, so the audit log can't be appended to
+        let data_dir = std::env::temp_dir().join(format!("audit-failure-{}", Uuid::new_v4()));
+        std::fs::write(&data_dir, "").unwrap();
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        config.audit.store = StoreKind::File;
+        config.storage.data_dir = data_dir.clone();
+        let ctx = Context::from_config(Arc::new(RecordingEventBus::default()), config);
+        let app = create_app(ctx.clone()).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_admin = login(&client, "Admin").await;
+        let cookie_jane = login(&client, "Jane").await;
+
+        // Actions that can't be recorded are not taken
+        client
+            .put("/api/admin/bans/Jane")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_admin)
+            .body(json!({}).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!ctx.is_banned("Jane").await);
+        client
+            .delete("/api/sessions")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        client
+            .get("/api/session")
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Audited",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .delete(format!("/api/admin/rooms/{}/users/Jane", room_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        client
+            .delete(format!("/api/rooms/{}", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(json!({ "removed_at": "2024-06-09T12:00:00Z" }).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(ctx.event_store.load_since(room_id, 0).await.len(), 2);
+        assert_eq!(
+            ctx.users_in_room.lock().await[&room_id],
+            vec!["Jane".to_string()]
+        );
+
+        let _ = std::fs::remove_file(data_dir);
+    }
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        let app = create_app(Context::from_config(bus.clone(), config))
+            .await
+            .unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_admin = login(&client, "Admin").await;
+        let cookie_jane = login(&client, "Jane").await;
+
+        let register = |cookie: &str, body: serde_json::Value| {
+            client
+                .post("/api/webhooks")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, cookie)
+                .body(body.to_string())
+                .send()
+        };
+        let room_id = Uuid::new_v4();
+        let webhook = json!({
+            "url": "https://example.com/hooks/chat",
+            "event_types": ["MessageWasSend"],
+            "room_id": room_id
+        });
+
+        register(&cookie_jane, webhook.clone())
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        register(&cookie_admin, json!({ "url": "ftp://example.com" }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        register(
+            &cookie_admin,
+            json!({ "url": "https://example.com", "event_types": ["MessageWasSent"] }),
+        )
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+        let resp = register(&cookie_admin, webhook).await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let registered = json.value().object();
+        registered.get("created_by").assert_string("Admin");
+        assert_eq!(registered.get("secret").string().len(), 64);
+        let webhook_id = registered.get("id").string().to_string();
+
+        let resp = client
+            .get("/api/webhooks")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let webhooks = json.value().array();
+        webhooks.assert_len(1);
+        let webhook = webhooks.get(0).object();
+        webhook.get("id").assert_string(&webhook_id);
+        webhook
+            .get("event_types")
+            .assert_string_array(&["MessageWasSend"]);
+        webhook.get("room_id").assert_string(&room_id.to_string());
+        // The secret is only returned when registering
+        assert!(webhook.get_opt("secret").is_none());
+
+        let resp = client
+            .get(format!("/api/webhooks/{}/deliveries", webhook_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        resp.assert_json(json!([])).await;
+        let resp = client
+            .get("/api/webhooks/dead-letters")
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await;
+        resp.assert_json(json!([])).await;
+
+        client
+            .delete(format!("/api/webhooks/{}", webhook_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .delete(format!("/api/webhooks/{}", webhook_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        client
+            .get(format!("/api/webhooks/{}/deliveries", webhook_id))
+            .header(header::COOKIE, &cookie_admin)
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_webhooks() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let ctx = Context::new(bus.clone());
+        ctx.incoming_webhooks.start(ctx.bus.as_ref()).await;
+        let app = create_app(ctx.clone()).await.unwrap();
+        let client = TestClient::new(app);
+
+        let cookie_jane = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let create = |cookie: &str, name: &str| {
+            client
+                .post(format!("/api/rooms/{}/webhooks", room_id))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, cookie)
+                .body(json!({ "name": name }).to_string())
+                .send()
+        };
+        // Only members of the room can add webhooks
+        create(&cookie_john, "CI")
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        // Webhooks can't pretend to be a user
+        for name in ["John", "jane"] {
+            create(&cookie_jane, name)
+                .await
+                .assert_status(StatusCode::CONFLICT);
+        }
+
+        let resp = create(&cookie_jane, "CI").await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let created = json.value().object();
+        created.get("name").assert_string("CI");
+        created.get("created_by").assert_string("Jane");
+        let webhook_id = created.get("id").string().to_string();
+        let url = created.get("url").string().to_string();
+        // Nor share a name with another webhook
+        create(&cookie_jane, "ci")
+            .await
+            .assert_status(StatusCode::CONFLICT);
+        let path = url
+            .strip_prefix("http://localhost:3000")
+            .expect("Webhook url should be absolute")
+            .to_string();
+
+        let post = |body: serde_json::Value| {
+            client
+                .post(&path)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send()
+        };
+        let message_id = Uuid::new_v4();
+        let message = json!({
+            "id": message_id,
+            "message": "Build **passed**, thanks @Jane",
+            "format": "markdown"
+        });
+        let resp = post(message.clone()).await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({ "id": message_id })).await;
+
+        // Retrying is safe and the usual validation applies
+        post(message).await.assert_status(StatusCode::CONFLICT);
+        post(json!({ "message": "a".repeat(2000) }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+        client
+            .post("/api/hooks/not-a-token")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "message": "Hi" }).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let resp = client
+            .get(format!("/api/rooms/{}/messages", room_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let messages = json.value().object().get("items").array();
+        messages.assert_len(1);
+        let message = messages.get(0).object();
+        message.get("username").assert_string("CI");
+        message.get("bot").assert_bool(true);
+        message
+            .get("rendered_html")
+            .assert_string("<p>Build <strong>passed</strong>, thanks @Jane</p>\n");
+        assert_eq!(ctx.mentions.lock().await["Jane"][0].mentioned_by, "CI");
+
+        let resp = client
+            .get(format!("/api/rooms/{}/webhooks", room_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let webhooks = json.value().array();
+        webhooks.assert_len(1);
+        webhooks
+            .get(0)
+            .object()
+            .get("id")
+            .assert_string(&webhook_id);
+        // The token is only returned when creating the webhook
+        assert!(webhooks.get(0).object().get_opt("url").is_none());
+
+        client
+            .delete(format!("/api/rooms/{}/webhooks/{}", room_id, webhook_id))
+            .header(header::COOKIE, &cookie_jane)
+            .send()
+            .await
+            .assert_status_is_ok();
+        post(json!({ "message": "Hi" }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        // Nor can users pretend to be a webhook
+        let resp = create(&cookie_jane, "Deploy").await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let path = json
+            .value()
+            .object()
+            .get("url")
+            .string()
+            .strip_prefix("http://localhost:3000")
+            .unwrap()
+            .to_string();
+        client
+            .post("/api/session")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "username": "Deploy" }).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        // The webhook is revoked once its creator leaves
+        client
+            .delete(format!("/api/rooms/{}/users", room_id))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_jane)
+            .body(json!({ "left_at": "2024-06-09T13:00:00Z" }).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+        client
+            .post(&path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "message": "Hi" }).to_string())
+            .send()
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+        for _ in 0..100 {
+            if ctx.incoming_webhooks.in_room(room_id).await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(ctx.incoming_webhooks.in_room(room_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slash_commands() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let mut config = Config::default();
+        config.admin.usernames = vec!["Admin".to_string()];
+        let app = create_app(Context::from_config(bus.clone(), config))
+            .await
+            .unwrap();
+        let client = TestClient::new(app);
+
+        let cookie = login(&client, "Jane").await;
+        let cookie_john = login(&client, "John").await;
+        let cookie_admin = login(&client, "Admin").await;
+        client
+            .put("/api/admin/bans/Mark")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie_admin)
+            .body(json!({}).to_string())
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let room_id = Uuid::new_v4();
+        client
+            .post("/api/rooms")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::COOKIE, &cookie)
+            .body(
+                json!({
+                    "id": room_id,
+                    "name": "Lustrum Crash & Compile",
+                    "created_at": "2024-06-09T12:00:00Z"
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Only members of an existing room can run commands in it
+        for (room_id, message) in [
+            (room_id, "/dance"),
+            (room_id, "/topic Hijacked"),
+            (Uuid::new_v4(), "/roll"),
+        ] {
+            client
+                .post(format!("/api/rooms/{}/messages", room_id))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, &cookie_john)
+                .body(
+                    json!({
+                        "id": Uuid::new_v4(),
+                        "message": message,
+                        "send_at": "2024-06-09T12:00:00Z"
+                    })
+                    .to_string(),
+                )
+                .send()
+                .await
+                .assert_status(StatusCode::FORBIDDEN);
+        }
+        assert!(!bus
+            .recorded_events()
+            .await
+            .iter()
+            .any(|event| matches!(event, DomainEvent::SlashCommandReplied(_))));
+
+        for message in [
+            "/topic Release planning",
+            "/invite @John",
+            "/invite Mark",
+            "/invite Nobody",
+            "/me waves",
+            "/shrug fine",
+            "/roll 2d6",
+            "/dance",
+        ] {
+            client
+                .post(format!("/api/rooms/{}/messages", room_id))
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, &cookie)
+                .body(
+                    json!({
+                        "id": Uuid::new_v4(),
+                        "message": message,
+                        "send_at": "2024-06-09T12:00:00Z"
+                    })
+                    .to_string(),
+                )
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+
+        let resp = client
+            .get(format!("/api/rooms/{}", room_id))
+            .header(header::COOKIE, &cookie)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let room = json.value().object();
+        room.get("topic").assert_string("Release planning");
+        room.get("users").assert_string_array(&["Jane", "John"]);
+
+        let messages = room.get("messages").array();
+        messages.assert_len(3);
+        let me = messages.get(0).object();
+        me.get("message").assert_string("*Jane waves*");
+        me.get("rendered_html")
+            .assert_string("<p><em>Jane waves</em></p>\n");
+        messages
+            .get(1)
+            .object()
+            .get("message")
+            .assert_string("fine ¯\\_(ツ)_/¯");
+        let roll = messages.get(2).object().get("message").string().to_string();
+        assert!(roll.starts_with("Jane rolled 2d6: "), "{}", roll);
+
+        // Unknown commands are only answered to the sender
+        let replies = bus
+            .recorded_events()
+            .await
+            .into_iter()
+            .filter_map(|event| match event {
+                DomainEvent::SlashCommandReplied(reply) => Some(reply),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies
+                .iter()
+                .map(|reply| reply.reply.as_str())
+                .take(2)
+                .collect::<Vec<_>>(),
+            vec!["Mark is banned", "There is no user called Nobody"]
+        );
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[2].username, "Jane");
+        assert_eq!(replies[2].command, "/dance");
+        assert!(replies[2].reply.starts_with("Unknown command /dance"));
+        let reply = DomainEvent::SlashCommandReplied(replies[2].clone());
+        assert!(reply.is_visible_to(Some("Jane")));
+        assert!(!reply.is_visible_to(Some("John")));
     }
 }
@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+
+use poem_openapi::Enum;
+use pulldown_cmark::{html, Options, Parser};
+use serde::Serialize;
+
+#[derive(Debug, Enum, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+#[oai(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+/// Tags that may end up in a rendered message, anything else (including raw
+/// html inside of markdown) is removed. Images are deliberately missing so that
+/// messages can't be used to track who read them.
+const ALLOWED_TAGS: [&str; 25] = [
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+/// Render a message to html that is safe to be inserted into the page as is
+pub fn render(format: MessageFormat, message: &str) -> String {
+    match format {
+        MessageFormat::Plain => escape(message).replace('\n', "<br>"),
+        MessageFormat::Markdown => {
+            let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+
+            let mut unsafe_html = String::new();
+            html::push_html(&mut unsafe_html, Parser::new_ext(message, options));
+
+            sanitize(&unsafe_html)
+        }
+    }
+}
+
+fn sanitize(html: &str) -> String {
+    ammonia::Builder::empty()
+        .tags(HashSet::from(ALLOWED_TAGS))
+        .add_tag_attributes("a", ["href"])
+        .add_tag_attributes("code", ["class"])
+        .add_tag_attributes("th", ["style"])
+        .add_tag_attributes("td", ["style"])
+        .filter_style_properties(HashSet::from(["text-align"]))
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            // Code blocks are tagged with their language for syntax highlighting
+            ("code", "class") if is_language_class(value) => Some(value.into()),
+            ("code", "class") => None,
+            _ => Some(value.into()),
+        })
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(ammonia::UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .clean(html)
+        .to_string()
+}
+
+fn is_language_class(class: &str) -> bool {
+    class.strip_prefix("language-").is_some_and(|language| {
+        !language.is_empty()
+            && language
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '#'))
+    })
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, MessageFormat};
+
+    #[test]
+    fn test_render_plain() {
+        assert_eq!(
+            render(MessageFormat::Plain, "<b>Hoi</b>\n**there**"),
+            "&lt;b&gt;Hoi&lt;/b&gt;<br>**there**"
+        );
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        assert_eq!(
+            render(MessageFormat::Markdown, "Hoi **there**"),
+            "<p>Hoi <strong>there</strong></p>\n"
+        );
+        assert_eq!(
+            render(MessageFormat::Markdown, "```rust\nfn main() {}\n```"),
+            "<pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n"
+        );
+        assert_eq!(
+            render(MessageFormat::Markdown, "[site](https://example.com)"),
+            "<p><a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">site</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_markdown_removes_xss() {
+        let cases = [
+            "<script>alert(1)</script>",
+            "<img src=x onerror=alert(1)>",
+            "[click](javascript:alert(1))",
+            "![img](https://example.com/pixel.png)",
+            "<a href=\"https://example.com\" onclick=\"alert(1)\">x</a>",
+            "```\" onmouseover=\"alert(1)\n```",
+            "<iframe src=\"https://example.com\"></iframe>",
+            "<input autofocus onfocus=\"alert(1)\">",
+        ];
+
+        for case in cases {
+            let html = render(MessageFormat::Markdown, case);
+
+            for forbidden in [
+                "<script",
+                "onerror",
+                "javascript:",
+                "<img",
+                "onclick",
+                "onmouseover",
+                "<iframe",
+                "<input",
+                "onfocus",
+            ] {
+                assert!(!html.contains(forbidden), "{} rendered as {}", case, html);
+            }
+        }
+    }
+}
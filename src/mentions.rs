@@ -0,0 +1,49 @@
+/// Extract the usernames that are mentioned in a message using `@username`.
+///
+/// A mention has to start at the beginning of the message or after whitespace,
+/// so that e-mail addresses are not picked up as mentions. Trailing punctuation
+/// is not considered part of the username, and each user is only returned once.
+pub fn parse_mentions(message: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+
+    for word in message.split_whitespace() {
+        let Some(username) = word.strip_prefix('@') else {
+            continue;
+        };
+
+        let username = username
+            .split(|c: char| !is_username_char(c))
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches(['.', '-']);
+
+        if !username.is_empty() && !mentions.iter().any(|mention| mention == username) {
+            mentions.push(username.to_string());
+        }
+    }
+
+    mentions
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_mentions;
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(parse_mentions("Hoi"), Vec::<String>::new());
+        assert_eq!(parse_mentions("@Jane hoi"), vec!["Jane"]);
+        assert_eq!(
+            parse_mentions("Hoi @Jane, @john.doe. and @Jane!"),
+            vec!["Jane", "john.doe"]
+        );
+        assert_eq!(
+            parse_mentions("mail jane@example.com or @ me"),
+            Vec::<String>::new()
+        );
+    }
+}
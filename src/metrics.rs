@@ -0,0 +1,344 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use poem::{
+    async_trait, http::StatusCode, web::Data, Endpoint, IntoResponse, Middleware, Request,
+    Response, Result,
+};
+use poem_openapi::OperationId;
+use prometheus::{
+    proto::MetricFamily, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{
+    events::{DomainEvent, EventBus, ShareableEventBus},
+    subscriptions::{EventFilter, EventSubscription, Listener, SubscriptionHandle},
+    Context,
+};
+
+/// All metrics of the application, exposed in the Prometheus text format on
+/// `GET /metrics`
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    event_subscribers: IntGaugeVec,
+    events_dispatched: IntCounterVec,
+    events_lagged: IntCounter,
+    rooms: IntGauge,
+    messages: IntGauge,
+    users: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("chat".to_string()), None).unwrap();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Handled requests"),
+            &["operation_id", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time until the response was ready, for streams this excludes the stream itself",
+            ),
+            &["operation_id"],
+        )
+        .unwrap();
+        let event_subscribers = IntGaugeVec::new(
+            Opts::new("event_subscribers", "Open event streams"),
+            &["transport"],
+        )
+        .unwrap();
+        let events_dispatched = IntCounterVec::new(
+            Opts::new(
+                "events_dispatched_total",
+                "Events dispatched on the event bus",
+            ),
+            &["event"],
+        )
+        .unwrap();
+        let events_lagged = IntCounter::new(
+            "events_lagged_total",
+            "Events that were dropped for subscribers that could not keep up",
+        )
+        .unwrap();
+        let rooms = IntGauge::new("rooms", "Number of rooms").unwrap();
+        let messages = IntGauge::new("messages", "Number of messages in all rooms").unwrap();
+        let users = IntGauge::new("users", "Number of users that joined a room").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(event_subscribers.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(events_dispatched.clone()))
+            .unwrap();
+        registry.register(Box::new(events_lagged.clone())).unwrap();
+        registry.register(Box::new(rooms.clone())).unwrap();
+        registry.register(Box::new(messages.clone())).unwrap();
+        registry.register(Box::new(users.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            event_subscribers,
+            events_dispatched,
+            events_lagged,
+            rooms,
+            messages,
+            users,
+        }
+    }
+
+    /// Track an event stream that is sent to a client over the given transport
+    pub fn subscription(&self, transport: &str, events: EventSubscription) -> Subscription {
+        let subscribers = self.event_subscribers.with_label_values(&[transport]);
+        subscribers.inc();
+
+        Subscription {
+            events,
+            subscribers,
+            lagged: self.events_lagged.clone(),
+        }
+    }
+
+    fn observe_request(&self, operation_id: &str, method: &str, status: StatusCode, seconds: f64) {
+        self.requests
+            .with_label_values(&[operation_id, method, status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[operation_id])
+            .observe(seconds);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Receiving end of the event bus that counts the events it missed
+pub struct Subscription {
+    events: EventSubscription,
+    subscribers: IntGauge,
+    lagged: IntCounter,
+}
+
+impl Subscription {
+    /// The next event, skipping over events that were dropped because this
+    /// subscriber fell behind. Returns `None` once the bus is closed.
+    pub async fn recv(&mut self) -> Option<DomainEvent> {
+        let event = self.events.recv().await;
+        self.lagged.inc_by(self.events.take_lagged());
+
+        event
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.dec();
+    }
+}
+
+/// Counts the events that are dispatched before handing them to the actual bus
+pub struct MeteredEventBus {
+    bus: ShareableEventBus,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredEventBus {
+    pub fn new(bus: ShareableEventBus, metrics: Arc<Metrics>) -> MeteredEventBus {
+        MeteredEventBus { bus, metrics }
+    }
+}
+
+#[async_trait]
+impl EventBus for MeteredEventBus {
+    async fn dispatch_event(&self, event: DomainEvent) {
+        self.metrics
+            .events_dispatched
+            .with_label_values(&[event.name()])
+            .inc();
+
+        self.bus.dispatch_event(event).await
+    }
+
+    async fn subscribe(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle {
+        self.bus.subscribe(filter, listener).await
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.bus.is_ready().await
+    }
+}
+
+pub struct MetricsMiddleware {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsMiddleware {
+    pub fn new(metrics: Arc<Metrics>) -> MetricsMiddleware {
+        MetricsMiddleware { metrics }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for MetricsMiddleware {
+    type Output = MetricsMiddlewareEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        MetricsMiddlewareEndpoint {
+            ep,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+pub struct MetricsMiddlewareEndpoint<E> {
+    ep: E,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for MetricsMiddlewareEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let method = req.method().clone();
+        let started_at = Instant::now();
+        let resp = self.ep.get_response(req).await;
+
+        // Everything outside of the api, such as the UI, is grouped together
+        let operation_id = resp
+            .data::<OperationId>()
+            .map(|operation_id| operation_id.0)
+            .unwrap_or("none");
+        self.metrics.observe_request(
+            operation_id,
+            method.as_str(),
+            resp.status(),
+            started_at.elapsed().as_secs_f64(),
+        );
+
+        Ok(resp)
+    }
+}
+
+/// The rate limiter keeps its own counts, which are turned into counters on
+/// every scrape
+fn rate_limit_metrics(counts: HashMap<String, (u64, u64)>) -> Vec<MetricFamily> {
+    let registry = Registry::new_custom(Some("chat".to_string()), None).unwrap();
+    let requests = IntCounterVec::new(
+        Opts::new(
+            "rate_limited_requests_total",
+            "Requests to rate limited operations, by whether they were allowed",
+        ),
+        &["operation_id", "outcome"],
+    )
+    .unwrap();
+    registry.register(Box::new(requests.clone())).unwrap();
+
+    for (operation_id, (allowed, rejected)) in counts {
+        requests
+            .with_label_values(&[operation_id.as_str(), "allowed"])
+            .inc_by(allowed);
+        requests
+            .with_label_values(&[operation_id.as_str(), "rejected"])
+            .inc_by(rejected);
+    }
+
+    registry.gather()
+}
+
+#[poem::handler]
+pub async fn metrics_endpoint(ctx: Data<&Context>) -> Response {
+    let metrics = &ctx.metrics;
+
+    metrics.rooms.set(ctx.rooms.lock().await.len() as i64);
+    metrics.messages.set(
+        ctx.messages_in_room
+            .lock()
+            .await
+            .values()
+            .map(Vec::len)
+            .sum::<usize>() as i64,
+    );
+    metrics.users.set(
+        ctx.users_in_room
+            .lock()
+            .await
+            .values()
+            .flatten()
+            .collect::<HashSet<_>>()
+            .len() as i64,
+    );
+
+    let mut families = metrics.registry.gather();
+    families.extend(rate_limit_metrics(ctx.rate_limiter.counts()));
+
+    match TextEncoder::new().encode_to_string(&families) {
+        Ok(body) => body
+            .with_content_type("text/plain; version=0.0.4")
+            .into_response(),
+        Err(error) => {
+            tracing::error!(%error, "Failed to encode metrics");
+
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::{
+        events::{BroadcastingEventBus, DomainEvent, EventBus, UserLoggedIn},
+        subscriptions::{EventFilter, EventSubscription},
+    };
+
+    #[tokio::test]
+    async fn test_subscription_counts_lagged_events() {
+        let metrics = Metrics::new();
+        let bus = BroadcastingEventBus::default();
+        let events = EventSubscription::new(&bus, EventFilter::all(), 2).await;
+        let mut subscription = metrics.subscription("sse", events);
+        assert_eq!(
+            metrics.event_subscribers.with_label_values(&["sse"]).get(),
+            1
+        );
+
+        // Only two events fit in the buffer, the last one is dropped
+        for username in ["Jane", "John", "Joe"] {
+            bus.dispatch_event(DomainEvent::UserLoggedIn(UserLoggedIn {
+                username: username.to_string(),
+            }))
+            .await;
+        }
+
+        assert_eq!(
+            subscription.recv().await,
+            Some(DomainEvent::UserLoggedIn(UserLoggedIn {
+                username: "Jane".to_string()
+            }))
+        );
+        assert_eq!(metrics.events_lagged.get(), 1);
+
+        drop(subscription);
+        assert_eq!(
+            metrics.event_subscribers.with_label_values(&["sse"]).get(),
+            0
+        );
+    }
+}
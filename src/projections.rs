@@ -0,0 +1,402 @@
+use std::{collections::HashMap, sync::Arc};
+
+use poem::async_trait;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    event_store::EventStore,
+    events::{DomainEvent, EventBus, ShareableEventBus},
+    subscriptions::{EventFilter, Listener, SubscriptionHandle},
+    Mention, Message, Room,
+};
+
+/// Keeps a read model up to date with the events dispatched on the bus.
+///
+/// Projections are the only place where read models are changed, so that
+/// they can always be rebuilt by replaying the history of events.
+#[async_trait]
+pub trait Projection {
+    async fn apply(&self, event: &DomainEvent);
+
+    /// Forget everything, called before the history is replayed
+    async fn reset(&self);
+}
+
+pub type ShareableProjection = Arc<dyn Projection + Sync + Send + 'static>;
+
+/// Rooms and the users that joined them
+pub struct RoomProjection {
+    pub rooms: Arc<Mutex<Vec<Room>>>,
+    pub users_in_room: Arc<Mutex<HashMap<Uuid, Vec<String>>>>,
+}
+
+#[async_trait]
+impl Projection for RoomProjection {
+    async fn apply(&self, event: &DomainEvent) {
+        match event {
+            DomainEvent::RoomWasCreated(event) => self.rooms.lock().await.push(Room {
+                id: event.id,
+                name: event.name.clone(),
+                topic: None,
+            }),
+            DomainEvent::RoomWasRemoved(event) => {
+                self.rooms.lock().await.retain(|room| room.id != event.id);
+                self.users_in_room.lock().await.remove(&event.id);
+            }
+            DomainEvent::RoomTopicWasChanged(event) => {
+                if let Some(room) = self
+                    .rooms
+                    .lock()
+                    .await
+                    .iter_mut()
+                    .find(|room| room.id == event.room_id)
+                {
+                    room.topic = Some(event.topic.clone()).filter(|topic| !topic.is_empty());
+                }
+            }
+            DomainEvent::UserJoinedRoom(event) => {
+                let mut users_in_room = self.users_in_room.lock().await;
+                let users = users_in_room.entry(event.room_id).or_default();
+                if !users.contains(&event.username) {
+                    users.push(event.username.clone());
+                }
+            }
+            DomainEvent::UserLeftRoom(event) => {
+                if let Some(users) = self.users_in_room.lock().await.get_mut(&event.room_id) {
+                    users.retain(|user| *user != event.username);
+                }
+            }
+            DomainEvent::UserWasKicked(event) => {
+                if let Some(users) = self.users_in_room.lock().await.get_mut(&event.room_id) {
+                    users.retain(|user| *user != event.username);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn reset(&self) {
+        self.rooms.lock().await.clear();
+        self.users_in_room.lock().await.clear();
+    }
+}
+
+/// Messages per room, together with who they mention and their link previews
+pub struct MessageProjection {
+    pub messages_in_room: Arc<Mutex<HashMap<Uuid, Vec<Message>>>>,
+}
+
+impl MessageProjection {
+    async fn update(&self, room_id: Uuid, message_id: Uuid, update: impl FnOnce(&mut Message)) {
+        if let Some(message) = self
+            .messages_in_room
+            .lock()
+            .await
+            .get_mut(&room_id)
+            .and_then(|messages| messages.iter_mut().find(|message| message.id == message_id))
+        {
+            update(message);
+        }
+    }
+}
+
+#[async_trait]
+impl Projection for MessageProjection {
+    async fn apply(&self, event: &DomainEvent) {
+        match event {
+            DomainEvent::MessageWasSend(event) => self
+                .messages_in_room
+                .lock()
+                .await
+                .entry(event.room_id)
+                .or_default()
+                .push(Message {
+                    id: event.id,
+                    room_id: event.room_id,
+                    username: event.username.clone(),
+                    message: event.message.clone(),
+                    format: event.format,
+                    rendered_html: event.rendered_html.clone(),
+                    send_at: event.send_at,
+                    mentions: Vec::new(),
+                    attachments: event.attachments.clone(),
+                    link_previews: Vec::new(),
+                    bot: event.bot,
+                }),
+            DomainEvent::UserWasMentioned(event) => {
+                self.update(event.room_id, event.message_id, |message| {
+                    message.mentions.push(event.username.clone())
+                })
+                .await
+            }
+            DomainEvent::MessageLinkPreviewAdded(event) => {
+                self.update(event.room_id, event.message_id, |message| {
+                    message.link_previews.push(event.preview.clone())
+                })
+                .await
+            }
+            DomainEvent::MessageWasModerated(event) => {
+                if let Some(messages) = self.messages_in_room.lock().await.get_mut(&event.room_id) {
+                    messages.retain(|message| message.id != event.message_id);
+                }
+            }
+            DomainEvent::RoomWasRemoved(event) => {
+                self.messages_in_room.lock().await.remove(&event.id);
+            }
+            _ => {}
+        }
+    }
+
+    async fn reset(&self) {
+        self.messages_in_room.lock().await.clear();
+    }
+}
+
+/// Unread mentions per username
+pub struct MentionProjection {
+    pub mentions: Arc<Mutex<HashMap<String, Vec<Mention>>>>,
+}
+
+#[async_trait]
+impl Projection for MentionProjection {
+    async fn apply(&self, event: &DomainEvent) {
+        match event {
+            DomainEvent::UserWasMentioned(event) => self
+                .mentions
+                .lock()
+                .await
+                .entry(event.username.clone())
+                .or_default()
+                .push(Mention {
+                    message_id: event.message_id,
+                    room_id: event.room_id,
+                    mentioned_by: event.mentioned_by.clone(),
+                    mentioned_at: event.mentioned_at,
+                }),
+            DomainEvent::MentionWasRead(event) => {
+                if let Some(mentions) = self.mentions.lock().await.get_mut(&event.username) {
+                    mentions.retain(|mention| mention.message_id != event.message_id);
+                }
+            }
+            DomainEvent::MessageWasModerated(event) => {
+                for mentions in self.mentions.lock().await.values_mut() {
+                    mentions.retain(|mention| mention.message_id != event.message_id);
+                }
+            }
+            DomainEvent::RoomWasRemoved(event) => {
+                for mentions in self.mentions.lock().await.values_mut() {
+                    mentions.retain(|mention| mention.room_id != event.id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn reset(&self) {
+        self.mentions.lock().await.clear();
+    }
+}
+
+struct ProjectionsState {
+    projections: Vec<ShareableProjection>,
+    /// Number of stored events that were applied to the projections
+    position: usize,
+}
+
+/// All projections of the application, fed from the event store.
+///
+/// Appending an event and dispatching it are separate steps, so instead of
+/// applying dispatched events the projections catch up with the store. Every
+/// stored event is applied exactly once, also when a rebuild happens between
+/// the append and the dispatch of an event.
+#[derive(Clone)]
+pub struct Projections {
+    state: Arc<Mutex<ProjectionsState>>,
+    event_store: EventStore,
+}
+
+impl Projections {
+    pub fn new(projections: Vec<ShareableProjection>, event_store: EventStore) -> Projections {
+        Projections {
+            state: Arc::new(Mutex::new(ProjectionsState {
+                projections,
+                position: 0,
+            })),
+            event_store,
+        }
+    }
+
+    /// Apply the events that were stored since the last time, returning the
+    /// number of events that were applied
+    pub async fn catch_up(&self) -> usize {
+        let mut state = self.state.lock().await;
+        self.apply_stored(&mut state).await
+    }
+
+    async fn apply_stored(&self, state: &mut ProjectionsState) -> usize {
+        let envelopes = self.event_store.since(state.position).await;
+        for envelope in envelopes.iter() {
+            for projection in state.projections.iter() {
+                projection.apply(&envelope.event).await;
+            }
+        }
+        state.position += envelopes.len();
+
+        envelopes.len()
+    }
+
+    /// Reset every projection and replay all stored events, returning the
+    /// number of events that were replayed
+    #[tracing::instrument(skip_all)]
+    pub async fn rebuild(&self) -> usize {
+        let mut state = self.state.lock().await;
+        for projection in state.projections.iter() {
+            projection.reset().await;
+        }
+        state.position = 0;
+        let events = self.apply_stored(&mut state).await;
+
+        tracing::info!(events, "Rebuilt projections");
+        events
+    }
+}
+
+/// Brings the projections up to date before passing events on, so that read
+/// models are up to date by the time subscribers receive an event
+pub struct ProjectingEventBus {
+    bus: ShareableEventBus,
+    projections: Projections,
+}
+
+impl ProjectingEventBus {
+    pub fn new(bus: ShareableEventBus, projections: Projections) -> ProjectingEventBus {
+        ProjectingEventBus { bus, projections }
+    }
+}
+
+#[async_trait]
+impl EventBus for ProjectingEventBus {
+    async fn dispatch_event(&self, event: DomainEvent) {
+        // The event itself is applied when it is stored, events that are never
+        // stored do not change the read models
+        self.projections.catch_up().await;
+        self.bus.dispatch_event(event).await
+    }
+
+    async fn subscribe(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle {
+        self.bus.subscribe(filter, listener).await
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.bus.is_ready().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use time::OffsetDateTime;
+    use tokio::sync::Mutex;
+    use uuid::Uuid;
+
+    use super::{MessageProjection, Projection, Projections, RoomProjection};
+    use crate::{
+        event_store::EventStore,
+        events::{
+            DomainEvent, MessageWasModerated, MessageWasSend, RoomWasCreated, UserJoinedRoom,
+            UserLeftRoom,
+        },
+        markdown::MessageFormat,
+    };
+
+    #[tokio::test]
+    async fn test_rebuild_from_history() {
+        let now = OffsetDateTime::now_utc();
+        let room_id = Uuid::new_v4();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let message = |id| {
+            DomainEvent::MessageWasSend(MessageWasSend {
+                id,
+                room_id,
+                username: "Jane".to_string(),
+                message: "Hi".to_string(),
+                format: MessageFormat::Plain,
+                rendered_html: "Hi".to_string(),
+                send_at: now,
+                attachments: Vec::new(),
+                bot: false,
+            })
+        };
+        let history = vec![
+            DomainEvent::RoomWasCreated(RoomWasCreated {
+                id: room_id,
+                name: "Lustrum".to_string(),
+                created_at: now,
+            }),
+            DomainEvent::UserJoinedRoom(UserJoinedRoom {
+                room_id,
+                username: "Jane".to_string(),
+                joined_at: now,
+            }),
+            DomainEvent::UserJoinedRoom(UserJoinedRoom {
+                room_id,
+                username: "John".to_string(),
+                joined_at: now,
+            }),
+            message(first),
+            message(second),
+            DomainEvent::UserLeftRoom(UserLeftRoom {
+                room_id,
+                username: "John".to_string(),
+                left_at: now,
+            }),
+            DomainEvent::MessageWasModerated(MessageWasModerated {
+                message_id: first,
+                room_id,
+                moderated_by: "Admin".to_string(),
+                reason: None,
+                moderated_at: now,
+            }),
+        ];
+
+        let store = EventStore::default();
+        store.append(room_id, 0, history.clone()).await.unwrap();
+
+        let rooms = RoomProjection {
+            rooms: Arc::new(Mutex::new(Vec::new())),
+            users_in_room: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let messages = MessageProjection {
+            messages_in_room: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        // State that does not follow from the history is dropped
+        messages.apply(&message(Uuid::new_v4())).await;
+
+        let (users_in_room, messages_in_room) = (
+            rooms.users_in_room.clone(),
+            messages.messages_in_room.clone(),
+        );
+        let projections = Projections::new(vec![Arc::new(rooms), Arc::new(messages)], store);
+        assert_eq!(projections.rebuild().await, history.len());
+
+        assert_eq!(users_in_room.lock().await[&room_id], vec!["Jane"]);
+        assert_eq!(
+            messages_in_room.lock().await[&room_id]
+                .iter()
+                .map(|message| message.id)
+                .collect::<Vec<_>>(),
+            vec![second]
+        );
+
+        // Applying the same history again leads to the same state
+        assert_eq!(projections.rebuild().await, history.len());
+        assert_eq!(messages_in_room.lock().await[&room_id].len(), 1);
+
+        // Events that were replayed are not applied again once dispatched
+        assert_eq!(projections.catch_up().await, 0);
+        assert_eq!(messages_in_room.lock().await[&room_id].len(), 1);
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use poem::{
+    async_trait,
+    http::{header::RETRY_AFTER, StatusCode},
+    web::RemoteAddr,
+    Endpoint, EndpointExt, IntoResponse, Middleware, Request, Response, Result,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{auth::protect, auth::AuthData, Context};
+
+/// Buckets are swept once we track this many, so there are never more
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// A sweep leaves at most this many buckets, so that sweeping only happens
+/// once every thousand new buckets instead of on every request
+const SWEPT_BUCKETS: usize = MAX_TRACKED_BUCKETS * 9 / 10;
+
+/// A token bucket limit, allowing bursts of `capacity` requests after which
+/// one request per `refill_interval` is allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub refill_interval: Duration,
+}
+
+impl Limit {
+    pub fn new(capacity: u32, refill_interval: Duration) -> Limit {
+        Limit {
+            capacity,
+            refill_interval,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Default)]
+struct Counters {
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Keeps track of token buckets per operation id and user (or ip address)
+pub struct RateLimiter {
+    limits: HashMap<String, Limit>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+    counters: HashMap<String, Counters>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<String, Limit>) -> RateLimiter {
+        let counters = limits
+            .keys()
+            .map(|operation_id| (operation_id.clone(), Counters::default()))
+            .collect();
+
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+            counters,
+        }
+    }
+
+    /// Take a token for the given operation, returning how long the caller
+    /// should wait if there are none left
+    pub async fn check(&self, operation_id: &str, key: &str) -> Result<(), Duration> {
+        let Some(limit) = self.limits.get(operation_id) else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+
+        let bucket_key = (operation_id.to_string(), key.to_string());
+        if buckets.len() >= MAX_TRACKED_BUCKETS && !buckets.contains_key(&bucket_key) {
+            self.sweep(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(bucket_key).or_insert(Bucket {
+            tokens: f64::from(limit.capacity),
+            updated_at: now,
+        });
+
+        let tokens = refill(bucket, limit, now);
+        let counters = &self.counters[operation_id];
+
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            bucket.updated_at = now;
+            counters.allowed.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(());
+        }
+
+        counters.rejected.fetch_add(1, Ordering::Relaxed);
+
+        Err(limit.refill_interval.mul_f64(1.0 - tokens))
+    }
+
+    /// Forget the buckets that are full again, and the least recently used
+    /// ones when that doesn't free up enough room. Their callers start over
+    /// with a full bucket, which only happens when there are unusually many.
+    fn sweep(&self, buckets: &mut HashMap<(String, String), Bucket>, now: Instant) {
+        buckets.retain(
+            |(operation_id, _), bucket| match self.limits.get(operation_id) {
+                Some(limit) => refill(bucket, limit, now) < f64::from(limit.capacity),
+                None => false,
+            },
+        );
+
+        if buckets.len() > SWEPT_BUCKETS {
+            let mut by_use = buckets
+                .iter()
+                .map(|(key, bucket)| (bucket.updated_at, key.clone()))
+                .collect::<Vec<_>>();
+            by_use.sort_unstable();
+
+            let excess = buckets.len() - SWEPT_BUCKETS;
+            for (_, key) in by_use.into_iter().take(excess) {
+                buckets.remove(&key);
+            }
+        }
+    }
+
+    /// Number of (allowed, rejected) requests per rate limited operation id
+    pub fn counts(&self) -> HashMap<String, (u64, u64)> {
+        self.counters
+            .iter()
+            .map(|(operation_id, counters)| {
+                (
+                    operation_id.clone(),
+                    (
+                        counters.allowed.load(Ordering::Relaxed),
+                        counters.rejected.load(Ordering::Relaxed),
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(default_limits())
+    }
+}
+
+pub fn default_limits() -> HashMap<String, Limit> {
+    HashMap::from([
+        (
+            SEND_MESSAGE.to_string(),
+            Limit::new(10, Duration::from_secs(1)),
+        ),
+        (
+            CREATE_ROOM.to_string(),
+            Limit::new(5, Duration::from_secs(10)),
+        ),
+        (
+            GENERATE_EVENTS.to_string(),
+            Limit::new(1, Duration::from_secs(60)),
+        ),
+        (TYPING.to_string(), Limit::new(5, Duration::from_secs(2))),
+    ])
+}
+
+fn refill(bucket: &Bucket, limit: &Limit, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated_at);
+    let refilled = elapsed.as_secs_f64() / limit.refill_interval.as_secs_f64();
+
+    (bucket.tokens + refilled).min(f64::from(limit.capacity))
+}
+
+pub const SEND_MESSAGE: &str = "rooms_room_messages_post";
+pub const CREATE_ROOM: &str = "rooms_post";
+pub const GENERATE_EVENTS: &str = "generate_events";
+/// Typing notifications sent over a websocket, which has no operation id
+pub const TYPING: &str = "ws_typing";
+
+pub struct RateLimitMiddleware {
+    operation_id: &'static str,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(operation_id: &'static str) -> RateLimitMiddleware {
+        RateLimitMiddleware { operation_id }
+    }
+}
+
+#[async_trait]
+impl<E: Endpoint> Middleware<E> for RateLimitMiddleware {
+    type Output = RateLimitMiddlewareEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitMiddlewareEndpoint {
+            ep,
+            operation_id: self.operation_id,
+        }
+    }
+}
+
+pub struct RateLimitMiddlewareEndpoint<E> {
+    ep: E,
+    operation_id: &'static str,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for RateLimitMiddlewareEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let ctx = req.data::<Context>().cloned().unwrap();
+
+        // Authenticated users are limited individually, anyone else by ip address
+        let key = match req.extensions().get::<AuthData>() {
+            Some(auth_data) => format!("user:{}", auth_data.username),
+            None => address_key(req.remote_addr()),
+        };
+
+        match ctx.rate_limiter.check(self.operation_id, &key).await {
+            Ok(()) => Ok(self.ep.call(req).await?.into_response()),
+            Err(retry_after) => {
+                tracing::info!(operation_id = self.operation_id, key, "Rate limited");
+
+                Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, retry_after.as_secs_f64().ceil() as u64)
+                    .finish())
+            }
+        }
+    }
+}
+
+/// Anonymous callers are limited by ip address, without the port, as every
+/// connection would get its own bucket otherwise
+fn address_key(remote_addr: &RemoteAddr) -> String {
+    match remote_addr.as_socket_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => format!("ip:{}", remote_addr),
+    }
+}
+
+pub fn limit_send_message(ep: impl Endpoint) -> impl Endpoint {
+    protect(ep.with(RateLimitMiddleware::new(SEND_MESSAGE)))
+}
+
+/// Incoming webhooks share the limit of sending messages, keyed by the
+/// address of the tool posting to them
+pub fn limit_incoming_webhook(ep: impl Endpoint) -> impl Endpoint {
+    ep.with(RateLimitMiddleware::new(SEND_MESSAGE))
+}
+
+pub fn limit_create_room(ep: impl Endpoint) -> impl Endpoint {
+    protect(ep.with(RateLimitMiddleware::new(CREATE_ROOM)))
+}
+
+pub fn limit_generate_events(ep: impl Endpoint) -> impl Endpoint {
+    ep.with(RateLimitMiddleware::new(GENERATE_EVENTS))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use poem::{web::RemoteAddr, Addr};
+
+    use super::{address_key, Limit, RateLimiter, MAX_TRACKED_BUCKETS, SWEPT_BUCKETS};
+
+    #[tokio::test]
+    async fn test_sweep_buckets() {
+        let limiter = RateLimiter::new(HashMap::from([(
+            "op".to_string(),
+            Limit::new(2, Duration::from_secs(3600)),
+        )]));
+
+        // None of the buckets fill up again, yet their number is capped
+        for key in 0..MAX_TRACKED_BUCKETS {
+            limiter.check("op", &key.to_string()).await.unwrap();
+        }
+        assert_eq!(limiter.buckets.lock().await.len(), MAX_TRACKED_BUCKETS);
+
+        limiter.check("op", "new").await.unwrap();
+        assert_eq!(limiter.buckets.lock().await.len(), SWEPT_BUCKETS + 1);
+
+        // The most recently used buckets are kept
+        limiter.check("op", "new").await.unwrap();
+        assert!(limiter.check("op", "new").await.is_err());
+    }
+
+    #[test]
+    fn test_address_key() {
+        let addr = |addr: &str| RemoteAddr(Addr::SocketAddr(addr.parse().unwrap()));
+
+        assert_eq!(address_key(&addr("10.0.0.1:51234")), "ip:10.0.0.1");
+        assert_eq!(
+            address_key(&addr("10.0.0.1:51234")),
+            address_key(&addr("10.0.0.1:51235"))
+        );
+        assert_eq!(address_key(&addr("[::1]:8080")), "ip:::1");
+    }
+}
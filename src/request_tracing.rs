@@ -0,0 +1,191 @@
+use std::time::Instant;
+
+use poem::{
+    async_trait,
+    http::{HeaderName, HeaderValue},
+    Endpoint, Middleware, Request, Response, Result,
+};
+use poem_openapi::OperationId;
+use tracing::{field::Empty, Instrument, Span};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Request ids given by clients longer than this are replaced by our own
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Identifies a request in the logs, taken from the `X-Request-Id` header when
+/// a proxy in front of us already assigned one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Runs every request in a span carrying its request id, operation id, the
+/// logged in user and the room it concerns, and logs a line once it finished.
+///
+/// Handlers and the events they dispatch are logged within this span, so every
+/// log line can be traced back to the request that caused it.
+pub struct RequestTracing;
+
+impl<E: Endpoint> Middleware<E> for RequestTracing {
+    type Output = RequestTracingEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RequestTracingEndpoint { ep }
+    }
+}
+
+pub struct RequestTracingEndpoint<E> {
+    ep: E,
+}
+
+#[async_trait]
+impl<E: Endpoint> Endpoint for RequestTracingEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let request_id = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| is_valid_request_id(value))
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %redacted_path(req.uri().path()),
+            operation_id = Empty,
+            username = Empty,
+            room_id = Empty,
+        );
+        if let Some(room_id) = room_id_from_path(req.uri().path()) {
+            span.record("room_id", tracing::field::display(room_id));
+        }
+
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+
+        async move {
+            let started_at = Instant::now();
+            let mut resp = self.ep.get_response(req).await;
+
+            if let Some(operation_id) = resp.data::<OperationId>() {
+                Span::current().record("operation_id", operation_id.0);
+            }
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            tracing::info!(
+                status = resp.status().as_u16(),
+                latency_ms = started_at.elapsed().as_secs_f64() * 1000.0,
+                "finished request"
+            );
+
+            Ok(resp)
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Record the user that made the request on the request span
+pub fn record_username(username: &str) {
+    Span::current().record("username", username);
+}
+
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Incoming webhooks are authenticated by the token in their path
+/// (`/api/hooks/:token`), which must not end up in the logs
+fn redacted_path(path: &str) -> String {
+    let mut segments = path.split('/').collect::<Vec<_>>();
+    if let Some(position) = segments.iter().position(|segment| *segment == "hooks") {
+        if let Some(token) = segments.get_mut(position + 1) {
+            if !token.is_empty() {
+                *token = "[redacted]";
+            }
+        }
+    }
+
+    segments.join("/")
+}
+
+/// Rooms are addressed as `/api/rooms/:room_id/...` and `/api/events/:room_id`
+fn room_id_from_path(path: &str) -> Option<Uuid> {
+    let segments = path.split('/').collect::<Vec<_>>();
+
+    segments
+        .windows(2)
+        .find(|pair| matches!(pair[0], "rooms" | "events"))
+        .and_then(|pair| pair[1].parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use poem::{handler, test::TestClient, web::Data, EndpointExt};
+    use uuid::Uuid;
+
+    use super::{redacted_path, room_id_from_path, RequestId, RequestTracing, REQUEST_ID_HEADER};
+
+    #[handler]
+    fn index(request_id: Data<&RequestId>) -> String {
+        request_id.0 .0.clone()
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let client = TestClient::new(index.with(RequestTracing));
+
+        let resp = client
+            .get("/")
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .send()
+            .await;
+        resp.assert_header(REQUEST_ID_HEADER, "abc-123");
+        resp.assert_text("abc-123").await;
+
+        // Ids that could mess up our logs are replaced
+        let resp = client
+            .get("/")
+            .header(REQUEST_ID_HEADER, "abc\"123")
+            .send()
+            .await;
+        let request_id = resp.0.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        assert!(Uuid::parse_str(request_id.to_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_room_id_from_path() {
+        let room_id = Uuid::new_v4();
+
+        assert_eq!(
+            room_id_from_path(&format!("/api/rooms/{}/messages", room_id)),
+            Some(room_id)
+        );
+        assert_eq!(
+            room_id_from_path(&format!("/api/events/{}", room_id)),
+            Some(room_id)
+        );
+        assert_eq!(room_id_from_path("/api/rooms"), None);
+        assert_eq!(room_id_from_path("/api/mentions"), None);
+    }
+
+    #[test]
+    fn test_redacted_path() {
+        assert_eq!(redacted_path("/api/hooks/secret"), "/api/hooks/[redacted]");
+        assert_eq!(
+            redacted_path("/api/hooks/secret/"),
+            "/api/hooks/[redacted]/"
+        );
+        assert_eq!(redacted_path("/api/hooks"), "/api/hooks");
+        assert_eq!(redacted_path("/api/rooms/abc"), "/api/rooms/abc");
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+
+use poem::{http::StatusCode, Error};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    events::{
+        DomainEvent, Envelope, MentionWasRead, MessageLinkPreviewAdded, MessageWasModerated,
+        MessageWasSend, RoomTopicWasChanged, RoomWasCreated, RoomWasRemoved, UserJoinedRoom,
+        UserLeftRoom, UserWasKicked, UserWasMentioned,
+    },
+    link_preview::LinkPreview,
+};
+
+/// Why a command was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound,
+    AlreadyExists,
+    MessageNotFound,
+    MentionNotFound,
+    DuplicateMessage,
+    NotAMember,
+}
+
+impl From<RoomError> for Error {
+    fn from(error: RoomError) -> Error {
+        let status = match error {
+            RoomError::NotFound
+            | RoomError::MessageNotFound
+            | RoomError::MentionNotFound
+            | RoomError::NotAMember => StatusCode::NOT_FOUND,
+            RoomError::AlreadyExists | RoomError::DuplicateMessage => StatusCode::CONFLICT,
+        };
+
+        Error::from_status(status)
+    }
+}
+
+type Result<T> = std::result::Result<T, RoomError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RoomState {
+    New,
+    Open {
+        members: Vec<String>,
+        messages: HashSet<Uuid>,
+        /// Message ids and the users they mention, until the users read them
+        unread_mentions: HashSet<(Uuid, String)>,
+    },
+    Removed,
+}
+
+/// A room as rebuilt from its events, used to decide whether a command is
+/// allowed and which events it results in. Nothing is changed by the
+/// commands themselves, the resulting events have to be appended at
+/// [`RoomAggregate::version`] to take effect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomAggregate {
+    id: Uuid,
+    version: i64,
+    state: RoomState,
+}
+
+impl RoomAggregate {
+    pub fn from_history(id: Uuid, history: &[Envelope]) -> RoomAggregate {
+        let mut room = RoomAggregate {
+            id,
+            version: 0,
+            state: RoomState::New,
+        };
+        room.catch_up(history);
+
+        room
+    }
+
+    /// Apply the events that were appended since the room was loaded
+    pub fn catch_up(&mut self, history: &[Envelope]) {
+        for envelope in history {
+            self.apply(&envelope.event);
+            self.version = envelope.version;
+        }
+    }
+
+    pub fn version(&self) -> i64 {
+        self.version
+    }
+
+    fn apply(&mut self, event: &DomainEvent) {
+        match (&mut self.state, event) {
+            (RoomState::New, DomainEvent::RoomWasCreated(_)) => {
+                self.state = RoomState::Open {
+                    members: Vec::new(),
+                    messages: HashSet::new(),
+                    unread_mentions: HashSet::new(),
+                }
+            }
+            (_, DomainEvent::RoomWasRemoved(_)) => self.state = RoomState::Removed,
+            (RoomState::Open { members, .. }, DomainEvent::UserJoinedRoom(event)) => {
+                members.push(event.username.clone())
+            }
+            (RoomState::Open { members, .. }, DomainEvent::UserLeftRoom(event)) => {
+                members.retain(|member| *member != event.username)
+            }
+            (RoomState::Open { members, .. }, DomainEvent::UserWasKicked(event)) => {
+                members.retain(|member| *member != event.username)
+            }
+            (RoomState::Open { messages, .. }, DomainEvent::MessageWasSend(event)) => {
+                messages.insert(event.id);
+            }
+            (
+                RoomState::Open {
+                    messages,
+                    unread_mentions,
+                    ..
+                },
+                DomainEvent::MessageWasModerated(event),
+            ) => {
+                messages.remove(&event.message_id);
+                unread_mentions.retain(|(message_id, _)| *message_id != event.message_id);
+            }
+            (
+                RoomState::Open {
+                    unread_mentions, ..
+                },
+                DomainEvent::UserWasMentioned(event),
+            ) => {
+                unread_mentions.insert((event.message_id, event.username.clone()));
+            }
+            (
+                RoomState::Open {
+                    unread_mentions, ..
+                },
+                DomainEvent::MentionWasRead(event),
+            ) => {
+                unread_mentions.remove(&(event.message_id, event.username.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    fn open(&self) -> Result<(&Vec<String>, &HashSet<Uuid>)> {
+        match &self.state {
+            RoomState::Open {
+                members, messages, ..
+            } => Ok((members, messages)),
+            RoomState::New | RoomState::Removed => Err(RoomError::NotFound),
+        }
+    }
+
+    /// Create the room with its creator as first member
+    pub fn create(
+        &self,
+        name: String,
+        username: String,
+        created_at: OffsetDateTime,
+    ) -> Result<Vec<DomainEvent>> {
+        if self.state != RoomState::New {
+            return Err(RoomError::AlreadyExists);
+        }
+
+        Ok(vec![
+            DomainEvent::RoomWasCreated(RoomWasCreated {
+                id: self.id,
+                name,
+                created_at,
+            }),
+            DomainEvent::UserJoinedRoom(UserJoinedRoom {
+                room_id: self.id,
+                username,
+                joined_at: created_at,
+            }),
+        ])
+    }
+
+    pub fn remove(&self, removed_at: OffsetDateTime) -> Result<Vec<DomainEvent>> {
+        self.open()?;
+
+        Ok(vec![DomainEvent::RoomWasRemoved(RoomWasRemoved {
+            id: self.id,
+            removed_at,
+        })])
+    }
+
+    /// Joining a room that the user already is a member of has no effect
+    pub fn join(&self, username: String, joined_at: OffsetDateTime) -> Result<Vec<DomainEvent>> {
+        let (members, _) = self.open()?;
+        if members.contains(&username) {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![DomainEvent::UserJoinedRoom(UserJoinedRoom {
+            room_id: self.id,
+            username,
+            joined_at,
+        })])
+    }
+
+    /// Leaving a room that the user is not a member of has no effect
+    pub fn leave(&self, username: String, left_at: OffsetDateTime) -> Result<Vec<DomainEvent>> {
+        let (members, _) = self.open()?;
+        if !members.contains(&username) {
+            return Ok(Vec::new());
+        }
+
+        Ok(vec![DomainEvent::UserLeftRoom(UserLeftRoom {
+            room_id: self.id,
+            username,
+            left_at,
+        })])
+    }
+
+    pub fn kick(
+        &self,
+        username: String,
+        kicked_by: String,
+        kicked_at: OffsetDateTime,
+    ) -> Result<Vec<DomainEvent>> {
+        let (members, _) = self.open()?;
+        if !members.contains(&username) {
+            return Err(RoomError::NotAMember);
+        }
+
+        Ok(vec![DomainEvent::UserWasKicked(UserWasKicked {
+            room_id: self.id,
+            username,
+            kicked_by,
+            kicked_at,
+        })])
+    }
+
+    /// Only members of the room can change its topic
+    pub fn change_topic(
+        &self,
+        topic: String,
+        changed_by: String,
+        changed_at: OffsetDateTime,
+    ) -> Result<Vec<DomainEvent>> {
+        let (members, _) = self.open()?;
+        if !members.contains(&changed_by) {
+            return Err(RoomError::NotAMember);
+        }
+
+        Ok(vec![DomainEvent::RoomTopicWasChanged(
+            RoomTopicWasChanged {
+                room_id: self.id,
+                topic,
+                changed_by,
+                changed_at,
+            },
+        )])
+    }
+
+    /// Send a message together with the mentions in it, message ids have to be
+    /// unique within the room
+    pub fn send_message(
+        &self,
+        message: MessageWasSend,
+        mentions: Vec<UserWasMentioned>,
+    ) -> Result<Vec<DomainEvent>> {
+        let (_, messages) = self.open()?;
+        if messages.contains(&message.id) {
+            return Err(RoomError::DuplicateMessage);
+        }
+
+        Ok(std::iter::once(DomainEvent::MessageWasSend(message))
+            .chain(mentions.into_iter().map(DomainEvent::UserWasMentioned))
+            .collect())
+    }
+
+    pub fn moderate_message(
+        &self,
+        message_id: Uuid,
+        moderated_by: String,
+        reason: Option<String>,
+        moderated_at: OffsetDateTime,
+    ) -> Result<Vec<DomainEvent>> {
+        let (_, messages) = self.open()?;
+        if !messages.contains(&message_id) {
+            return Err(RoomError::MessageNotFound);
+        }
+
+        Ok(vec![DomainEvent::MessageWasModerated(
+            MessageWasModerated {
+                message_id,
+                room_id: self.id,
+                moderated_by,
+                reason,
+                moderated_at,
+            },
+        )])
+    }
+
+    /// Only unread mentions of the user can be read
+    pub fn read_mention(
+        &self,
+        message_id: Uuid,
+        username: String,
+        read_at: OffsetDateTime,
+    ) -> Result<Vec<DomainEvent>> {
+        let RoomState::Open {
+            unread_mentions, ..
+        } = &self.state
+        else {
+            return Err(RoomError::NotFound);
+        };
+        if !unread_mentions.contains(&(message_id, username.clone())) {
+            return Err(RoomError::MentionNotFound);
+        }
+
+        Ok(vec![DomainEvent::MentionWasRead(MentionWasRead {
+            message_id,
+            room_id: self.id,
+            username,
+            read_at,
+        })])
+    }
+
+    pub fn add_link_preview(
+        &self,
+        message_id: Uuid,
+        preview: LinkPreview,
+    ) -> Result<Vec<DomainEvent>> {
+        let (_, messages) = self.open()?;
+        if !messages.contains(&message_id) {
+            return Err(RoomError::MessageNotFound);
+        }
+
+        Ok(vec![DomainEvent::MessageLinkPreviewAdded(
+            MessageLinkPreviewAdded {
+                message_id,
+                room_id: self.id,
+                preview,
+            },
+        )])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{RoomAggregate, RoomError};
+    use crate::{
+        event_store::EventStore,
+        events::{DomainEvent, MessageWasSend},
+        markdown::MessageFormat,
+    };
+
+    #[tokio::test]
+    async fn test_room_aggregate() {
+        let store = EventStore::default();
+        let id = Uuid::new_v4();
+        let now = OffsetDateTime::now_utc();
+        let load = || async { RoomAggregate::from_history(id, &store.load_since(id, 0).await) };
+
+        let room = load().await;
+        assert_eq!(room.join("Jane".to_string(), now), Err(RoomError::NotFound));
+
+        let events = room
+            .create("Lustrum".to_string(), "Jane".to_string(), now)
+            .unwrap();
+        store.append(id, room.version(), events).await.unwrap();
+
+        let room = load().await;
+        assert_eq!(room.version(), 2);
+        assert!(room.join("Jane".to_string(), now).unwrap().is_empty());
+        assert!(room.leave("John".to_string(), now).unwrap().is_empty());
+        assert_eq!(
+            room.create("Lustrum".to_string(), "John".to_string(), now),
+            Err(RoomError::AlreadyExists)
+        );
+
+        let message = MessageWasSend {
+            id: Uuid::new_v4(),
+            room_id: id,
+            username: "Jane".to_string(),
+            message: "Hi".to_string(),
+            format: MessageFormat::Plain,
+            rendered_html: "Hi".to_string(),
+            send_at: now,
+            attachments: Vec::new(),
+            bot: false,
+        };
+        let events = room.send_message(message.clone(), Vec::new()).unwrap();
+        assert_eq!(events, vec![DomainEvent::MessageWasSend(message.clone())]);
+        store.append(id, room.version(), events).await.unwrap();
+
+        let room = load().await;
+        assert_eq!(
+            room.send_message(message.clone(), Vec::new()),
+            Err(RoomError::DuplicateMessage)
+        );
+        assert_eq!(
+            room.change_topic("Planning".to_string(), "John".to_string(), now),
+            Err(RoomError::NotAMember)
+        );
+        assert_eq!(
+            room.change_topic("Planning".to_string(), "Jane".to_string(), now)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let events = room.remove(now).unwrap();
+        store.append(id, room.version(), events).await.unwrap();
+
+        // A room loaded before catches up with the events appended since
+        let mut stale = room.clone();
+        stale.catch_up(&store.load_since(id, stale.version()).await);
+        let room = load().await;
+        assert_eq!(stale, room);
+        assert_eq!(
+            room.moderate_message(message.id, "Admin".to_string(), None, now),
+            Err(RoomError::NotFound)
+        );
+    }
+}
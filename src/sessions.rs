@@ -0,0 +1,289 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use poem::{
+    async_trait,
+    http::StatusCode,
+    session::{Session, SessionStorage},
+    Error, Result,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Session entry that identifies the session in events, unlike the cookie it
+/// is not a secret
+const SESSION_ID: &str = "session_id";
+
+/// Give the session a new id, when a user logs in
+pub fn assign_session_id(session: &Session) {
+    session.set(SESSION_ID, Uuid::new_v4());
+}
+
+/// The id of the session, sessions that don't have one yet are given one
+pub fn session_id(session: &Session) -> Uuid {
+    session.get(SESSION_ID).unwrap_or_else(|| {
+        let session_id = Uuid::new_v4();
+        session.set(SESSION_ID, session_id);
+
+        session_id
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredSession {
+    entries: BTreeMap<String, Value>,
+    created_at: OffsetDateTime,
+    last_seen_at: OffsetDateTime,
+}
+
+impl StoredSession {
+    fn username(&self) -> Option<&str> {
+        self.entries.get("username").and_then(Value::as_str)
+    }
+}
+
+/// Server side storage for the sessions referenced by the session cookie.
+///
+/// Sessions expire when they have not been used for `idle_timeout` or when
+/// they are older than `absolute_timeout`, whichever comes first. When a file
+/// is given every change is written to it so that sessions survive a restart.
+#[derive(Clone)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<String, StoredSession>>>,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+    file: Option<PathBuf>,
+}
+
+impl SessionStore {
+    pub fn new(idle_timeout: Duration, absolute_timeout: Duration, file: Option<PathBuf>) -> Self {
+        SessionStore {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            idle_timeout,
+            absolute_timeout,
+            file,
+        }
+    }
+
+    /// Load the sessions that were persisted by a previous run
+    pub async fn restore(&self) -> std::io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let contents = match tokio::fs::read(file).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let stored: HashMap<String, StoredSession> = serde_json::from_slice(&contents)?;
+
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = self.sessions.lock().await;
+        *sessions = stored
+            .into_iter()
+            .filter(|(_, session)| !self.is_expired(session, now))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Invalidate every session of the given user, returning how many there were
+    pub async fn revoke_user(&self, username: &str) -> std::io::Result<usize> {
+        let mut sessions = self.sessions.lock().await;
+        let count = sessions.len();
+        sessions.retain(|_, session| session.username() != Some(username));
+        let revoked = count - sessions.len();
+
+        if revoked > 0 {
+            self.persist(&sessions).await?;
+        }
+
+        Ok(revoked)
+    }
+
+    /// Whether the session with the given id still exists, for connections
+    /// that outlive the request that checked the session
+    pub async fn is_active(&self, session_id: Uuid) -> bool {
+        let session_id = Value::String(session_id.to_string());
+        let now = OffsetDateTime::now_utc();
+
+        self.sessions.lock().await.values().any(|session| {
+            session.entries.get(SESSION_ID) == Some(&session_id) && !self.is_expired(session, now)
+        })
+    }
+
+    /// Whether a user matching the predicate has a session
+    pub async fn has_user(&self, predicate: impl Fn(&str) -> bool) -> bool {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .filter_map(StoredSession::username)
+            .any(predicate)
+    }
+
+    /// Write the sessions to disk, including when they were last used
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let sessions = self.sessions.lock().await;
+
+        self.persist(&sessions).await
+    }
+
+    fn is_expired(&self, session: &StoredSession, now: OffsetDateTime) -> bool {
+        now - session.last_seen_at >= self.idle_timeout
+            || now - session.created_at >= self.absolute_timeout
+    }
+
+    async fn load(&self, session_id: &str, now: OffsetDateTime) -> Option<BTreeMap<String, Value>> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(session_id)?;
+
+        if self.is_expired(session, now) {
+            sessions.remove(session_id);
+            return None;
+        }
+
+        // Only changes are persisted, so after a restart a session may expire
+        // a little earlier than it would have otherwise
+        session.last_seen_at = now;
+
+        Some(session.entries.clone())
+    }
+
+    async fn update(
+        &self,
+        session_id: &str,
+        entries: &BTreeMap<String, Value>,
+        now: OffsetDateTime,
+    ) -> std::io::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        sessions.retain(|_, session| !self.is_expired(session, now));
+        sessions
+            .entry(session_id.to_string())
+            .and_modify(|session| {
+                session.entries = entries.clone();
+                session.last_seen_at = now;
+            })
+            .or_insert_with(|| StoredSession {
+                entries: entries.clone(),
+                created_at: now,
+                last_seen_at: now,
+            });
+
+        self.persist(&sessions).await
+    }
+
+    async fn remove(&self, session_id: &str) -> std::io::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.remove(session_id).is_some() {
+            self.persist(&sessions).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn persist(&self, sessions: &HashMap<String, StoredSession>) -> std::io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        if let Some(parent) = file.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp = file.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(sessions)?).await?;
+        tokio::fs::rename(&tmp, file).await
+    }
+}
+
+fn storage_error(error: std::io::Error) -> Error {
+    tracing::error!(%error, "Failed to store sessions");
+
+    Error::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[async_trait]
+impl SessionStorage for SessionStore {
+    async fn load_session(&self, session_id: &str) -> Result<Option<BTreeMap<String, Value>>> {
+        Ok(self.load(session_id, OffsetDateTime::now_utc()).await)
+    }
+
+    /// The expiry is ignored in favour of our own idle and absolute timeouts
+    async fn update_session(
+        &self,
+        session_id: &str,
+        entries: &BTreeMap<String, Value>,
+        _expires: Option<Duration>,
+    ) -> Result<()> {
+        self.update(session_id, entries, OffsetDateTime::now_utc())
+            .await
+            .map_err(storage_error)
+    }
+
+    async fn remove_session(&self, session_id: &str) -> Result<()> {
+        self.remove(session_id).await.map_err(storage_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use serde_json::json;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::SessionStore;
+
+    fn entries(username: &str) -> BTreeMap<String, serde_json::Value> {
+        BTreeMap::from([("username".to_string(), json!(username))])
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let store = SessionStore::new(Duration::from_secs(60), Duration::from_secs(300), None);
+        let now = OffsetDateTime::now_utc();
+
+        store.update("a", &entries("Jane"), now).await.unwrap();
+        store.update("b", &entries("Jane"), now).await.unwrap();
+
+        // Using a session keeps it alive, until it reaches the absolute timeout
+        for seconds in (50..300).step_by(50) {
+            let at = now + Duration::from_secs(seconds);
+            assert_eq!(store.load("a", at).await, Some(entries("Jane")));
+        }
+        assert_eq!(store.load("a", now + Duration::from_secs(300)).await, None);
+
+        assert_eq!(store.load("b", now + Duration::from_secs(60)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_user_and_restore() {
+        let file = std::env::temp_dir().join(format!("sessions-{}.json", Uuid::new_v4()));
+        let timeout = Duration::from_secs(60);
+        let store = SessionStore::new(timeout, timeout, Some(file.clone()));
+        let now = OffsetDateTime::now_utc();
+
+        store.update("a", &entries("Jane"), now).await.unwrap();
+        store.update("b", &entries("Jane"), now).await.unwrap();
+        store.update("c", &entries("John"), now).await.unwrap();
+
+        assert_eq!(store.revoke_user("Jane").await.unwrap(), 2);
+
+        let restored = SessionStore::new(timeout, timeout, Some(file.clone()));
+        restored.restore().await.unwrap();
+        assert_eq!(restored.load("a", now).await, None);
+        assert_eq!(restored.load("c", now).await, Some(entries("John")));
+
+        std::fs::remove_file(file).unwrap();
+    }
+}
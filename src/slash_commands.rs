@@ -0,0 +1,469 @@
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use poem::{async_trait, Result};
+use rand::Rng;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    commands::{ChangeTopic, JoinRoom, SendMessage},
+    events::{DomainEvent, SlashCommandReplied},
+    markdown::MessageFormat,
+    Context,
+};
+
+/// A message starting with `/name`, sent by a user to a room
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub room_id: Uuid,
+    /// Id of the message that contained the command, commands that post a
+    /// message reuse it so that retries are still detected
+    pub message_id: Uuid,
+    pub username: String,
+    /// Lowercase name of the command, without the slash
+    pub name: String,
+    /// Everything after the name, trimmed
+    pub args: String,
+    pub message: String,
+    pub send_at: OffsetDateTime,
+}
+
+impl Invocation {
+    /// `None` for messages that are not a slash command, such as `/` on its
+    /// own or paths like `//server/share`
+    pub fn parse(command: &SendMessage) -> Option<Invocation> {
+        let rest = command.message.strip_prefix('/')?;
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let is_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !is_name {
+            return None;
+        }
+
+        Some(Invocation {
+            room_id: command.room_id,
+            message_id: command.id,
+            username: command.username.clone(),
+            name: name.to_ascii_lowercase(),
+            args: args.trim().to_string(),
+            message: command.message.clone(),
+            send_at: command.send_at,
+        })
+    }
+
+    /// A message to the room on behalf of the user that used the command
+    fn message(&self, message: String, format: MessageFormat) -> SendMessage {
+        SendMessage {
+            room_id: self.room_id,
+            id: self.message_id,
+            username: self.username.clone(),
+            message,
+            format,
+            send_at: self.send_at,
+            attachments: Vec::new(),
+            bot: false,
+        }
+    }
+}
+
+/// Handles one slash command. Anything a command changes should go through
+/// the usual commands, so that it results in normal domain events.
+#[async_trait]
+pub trait SlashCommand {
+    /// Name of the command without the slash, in lowercase
+    fn name(&self) -> &'static str;
+
+    /// Arguments of the command, as shown in the list of commands
+    fn usage(&self) -> &'static str;
+
+    /// Returns a reply that is only shown to the user that used the command,
+    /// for instance when the arguments are invalid
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>>;
+}
+
+pub type ShareableSlashCommand = Arc<dyn SlashCommand + Sync + Send + 'static>;
+
+/// The slash commands users can use, by name. Only builtin commands exist for
+/// now: handing commands to third parties through outgoing webhooks would
+/// need a way to reply to them, and webhooks never receive the private
+/// [`SlashCommandReplied`] events, so unknown commands are answered here.
+#[derive(Clone, Default)]
+pub struct SlashCommands {
+    commands: BTreeMap<&'static str, ShareableSlashCommand>,
+}
+
+impl SlashCommands {
+    pub fn builtin() -> SlashCommands {
+        let mut commands = SlashCommands::default();
+        commands.register(Arc::new(Topic));
+        commands.register(Arc::new(Invite));
+        commands.register(Arc::new(Me));
+        commands.register(Arc::new(Shrug));
+        commands.register(Arc::new(Roll));
+        commands
+    }
+
+    /// Adds a command, replacing any command with the same name
+    pub fn register(&mut self, command: ShareableSlashCommand) {
+        self.commands.insert(command.name(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<ShareableSlashCommand> {
+        self.commands.get(name).cloned()
+    }
+
+    /// Every command with its usage, in alphabetical order
+    fn help(&self) -> String {
+        self.commands
+            .values()
+            .map(|command| format!("/{} {}", command.name(), command.usage()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Context {
+    /// Whether the user is an admin, is logged in or is a member of a room,
+    /// the server doesn't keep track of users otherwise
+    async fn is_known_user(&self, username: &str) -> bool {
+        self.config
+            .admin
+            .usernames
+            .iter()
+            .any(|admin| admin == username)
+            || self.sessions.has_user(|user| user == username).await
+            || self
+                .users_in_room
+                .lock()
+                .await
+                .values()
+                .flatten()
+                .any(|user| user == username)
+    }
+
+    /// Like plain messages, slash commands can only be sent to rooms the user
+    /// is a member of
+    pub(crate) async fn run_slash_command(&self, invocation: Invocation) -> Result<()> {
+        self.ensure_member(invocation.room_id, &invocation.username)
+            .await?;
+
+        let reply = match self.slash_commands.get(&invocation.name) {
+            Some(command) => command.run(self, &invocation).await?,
+            None => Some(format!(
+                "Unknown command /{}, available commands are {}",
+                invocation.name,
+                self.slash_commands.help()
+            )),
+        };
+        tracing::info!(command = invocation.name, "Ran slash command");
+
+        if let Some(reply) = reply {
+            self.bus
+                .dispatch_event(DomainEvent::SlashCommandReplied(SlashCommandReplied {
+                    room_id: invocation.room_id,
+                    username: invocation.username,
+                    command: invocation.message,
+                    reply,
+                    replied_at: OffsetDateTime::now_utc(),
+                }))
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets the topic of the room, without a topic it is cleared
+struct Topic;
+
+#[async_trait]
+impl SlashCommand for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[topic]"
+    }
+
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>> {
+        if invocation.args.chars().count() > 256 {
+            return Ok(Some("Topics can be at most 256 characters".to_string()));
+        }
+
+        ctx.execute_and_dispatch(ChangeTopic {
+            room_id: invocation.room_id,
+            topic: invocation.args.clone(),
+            changed_by: invocation.username.clone(),
+            changed_at: invocation.send_at,
+        })
+        .await?;
+
+        Ok(None)
+    }
+}
+
+/// Adds another user to the room, only users that are known to the server
+/// and aren't banned can be invited
+struct Invite;
+
+#[async_trait]
+impl SlashCommand for Invite {
+    fn name(&self) -> &'static str {
+        "invite"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<username>"
+    }
+
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>> {
+        let username = invocation.args.trim_start_matches('@');
+        if username.is_empty() || username.contains(char::is_whitespace) {
+            return Ok(Some(format!("Usage: /invite {}", self.usage())));
+        }
+
+        if ctx.is_banned(username).await {
+            return Ok(Some(format!("{} is banned", username)));
+        }
+        if !ctx.is_known_user(username).await {
+            return Ok(Some(format!("There is no user called {}", username)));
+        }
+
+        ctx.execute_and_dispatch(JoinRoom {
+            room_id: invocation.room_id,
+            username: username.to_string(),
+            joined_at: invocation.send_at,
+        })
+        .await?;
+
+        Ok(None)
+    }
+}
+
+/// Describes what the user is doing, in the third person
+struct Me;
+
+#[async_trait]
+impl SlashCommand for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<action>"
+    }
+
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>> {
+        if invocation.args.is_empty() {
+            return Ok(Some(format!("Usage: /me {}", self.usage())));
+        }
+
+        let message = format!("*{} {}*", invocation.username, invocation.args);
+        ctx.post_message(invocation.message(message, MessageFormat::Markdown))
+            .await?;
+
+        Ok(None)
+    }
+}
+
+/// Appends a shrug to the message
+struct Shrug;
+
+#[async_trait]
+impl SlashCommand for Shrug {
+    fn name(&self) -> &'static str {
+        "shrug"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[message]"
+    }
+
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>> {
+        let message = format!("{} ¯\\_(ツ)_/¯", invocation.args);
+        ctx.post_message(
+            invocation.message(message.trim_start().to_string(), MessageFormat::Plain),
+        )
+        .await?;
+
+        Ok(None)
+    }
+}
+
+/// Dice in the `NdM` notation, `2d6` rolls two six sided dice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Dice {
+    count: u32,
+    sides: u32,
+}
+
+impl Dice {
+    const MAX_COUNT: u32 = 100;
+    const MAX_SIDES: u32 = 1000;
+
+    /// A single six sided die when no dice are given
+    fn parse(notation: &str) -> Option<Dice> {
+        if notation.is_empty() {
+            return Some(Dice { count: 1, sides: 6 });
+        }
+
+        let (count, sides) =
+            notation
+                .to_ascii_lowercase()
+                .split_once('d')
+                .map(|(count, sides)| {
+                    // `d20` is short for `1d20`
+                    let count = if count.is_empty() {
+                        Some(1)
+                    } else {
+                        count.parse().ok()
+                    };
+                    (count, sides.parse().ok())
+                })?;
+        let dice = Dice {
+            count: count?,
+            sides: sides?,
+        };
+
+        let is_valid = (1..=Dice::MAX_COUNT).contains(&dice.count)
+            && (2..=Dice::MAX_SIDES).contains(&dice.sides);
+        is_valid.then_some(dice)
+    }
+
+    fn roll(&self, rng: &mut impl Rng) -> Vec<u32> {
+        (0..self.count)
+            .map(|_| rng.gen_range(1..=self.sides))
+            .collect()
+    }
+}
+
+impl fmt::Display for Dice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)
+    }
+}
+
+/// Rolls dice and posts the outcome to the room
+struct Roll;
+
+#[async_trait]
+impl SlashCommand for Roll {
+    fn name(&self) -> &'static str {
+        "roll"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[NdM]"
+    }
+
+    async fn run(&self, ctx: &Context, invocation: &Invocation) -> Result<Option<String>> {
+        let Some(dice) = Dice::parse(&invocation.args) else {
+            return Ok(Some(format!(
+                "Usage: /roll {}, with at most {} dice of up to {} sides",
+                self.usage(),
+                Dice::MAX_COUNT,
+                Dice::MAX_SIDES
+            )));
+        };
+
+        let rolls = dice.roll(&mut rand::thread_rng());
+        let message = format!(
+            "{} rolled {}: {}",
+            invocation.username,
+            dice,
+            describe_rolls(&rolls)
+        );
+        ctx.post_message(invocation.message(message, MessageFormat::Plain))
+            .await?;
+
+        Ok(None)
+    }
+}
+
+/// `4` for a single die, `3 + 5 = 8` for several
+fn describe_rolls(rolls: &[u32]) -> String {
+    let total = rolls.iter().sum::<u32>();
+    if rolls.len() == 1 {
+        return total.to_string();
+    }
+
+    let rolls = rolls
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" + ");
+    format!("{} = {}", rolls, total)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, SeedableRng};
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{describe_rolls, Dice, Invocation, SlashCommands};
+    use crate::{commands::SendMessage, markdown::MessageFormat};
+
+    fn send(message: &str) -> SendMessage {
+        SendMessage {
+            room_id: Uuid::new_v4(),
+            id: Uuid::new_v4(),
+            username: "Jane".to_string(),
+            message: message.to_string(),
+            format: MessageFormat::Plain,
+            send_at: OffsetDateTime::UNIX_EPOCH,
+            attachments: Vec::new(),
+            bot: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_invocation() {
+        let invocation = Invocation::parse(&send("/Topic  Release  planning ")).unwrap();
+        assert_eq!(invocation.name, "topic");
+        assert_eq!(invocation.args, "Release  planning");
+
+        let invocation = Invocation::parse(&send("/shrug")).unwrap();
+        assert_eq!(invocation.name, "shrug");
+        assert_eq!(invocation.args, "");
+
+        for message in ["Hi /me", "/", "/ hi", "//server/share", "/etc/hosts"] {
+            assert_eq!(Invocation::parse(&send(message)), None, "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_dice() {
+        assert_eq!(Dice::parse(""), Some(Dice { count: 1, sides: 6 }));
+        assert_eq!(Dice::parse("2d6"), Some(Dice { count: 2, sides: 6 }));
+        assert_eq!(
+            Dice::parse("D20"),
+            Some(Dice {
+                count: 1,
+                sides: 20
+            })
+        );
+        for notation in ["0d6", "2d1", "101d6", "1d1001", "2x6", "d", "six"] {
+            assert_eq!(Dice::parse(notation), None, "{}", notation);
+        }
+
+        let dice = Dice { count: 3, sides: 4 };
+        let rolls = dice.roll(&mut StdRng::seed_from_u64(7));
+        assert_eq!(rolls.len(), 3);
+        assert!(rolls.iter().all(|roll| (1..=4).contains(roll)));
+
+        assert_eq!(describe_rolls(&[4]), "4");
+        assert_eq!(describe_rolls(&[3, 5]), "3 + 5 = 8");
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(
+            SlashCommands::builtin().help(),
+            "/invite <username>, /me <action>, /roll [NdM], /shrug [message], /topic [topic]"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, Weak,
+};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+use crate::events::{DomainEvent, EventBus, EventPayload};
+
+/// Called for every dispatched event that matches the filter it was
+/// subscribed with. Listeners are called while the event is being dispatched,
+/// so they should hand the event off rather than do any work themselves.
+pub type Listener = Arc<dyn Fn(&DomainEvent) + Send + Sync>;
+
+/// Selects the events a listener is interested in, every event by default
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    event_types: Vec<String>,
+    room_id: Option<Uuid>,
+}
+
+impl EventFilter {
+    pub fn all() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Only events of type `T`, can be repeated to select several types
+    pub fn of<T: EventPayload>(mut self) -> EventFilter {
+        self.event_types.push(T::name().into_owned());
+        self
+    }
+
+    /// Only events with the given type name, for types that are only known at
+    /// runtime
+    pub fn of_name(mut self, event_type: impl Into<String>) -> EventFilter {
+        self.event_types.push(event_type.into());
+        self
+    }
+
+    /// Only events about the given room, which leaves out events that are not
+    /// about any room at all
+    pub fn in_room(mut self, room_id: Uuid) -> EventFilter {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    pub fn matches(&self, event: &DomainEvent) -> bool {
+        let type_matches = self.event_types.is_empty()
+            || self
+                .event_types
+                .iter()
+                .any(|event_type| event_type == event.name());
+        let room_matches = self
+            .room_id
+            .is_none_or(|room_id| event.room_id() == Some(room_id));
+
+        type_matches && room_matches
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    listeners: Vec<(u64, EventFilter, Listener)>,
+}
+
+/// The listeners subscribed to a bus, in the order they subscribed
+#[derive(Clone, Default)]
+pub struct Listeners {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Listeners {
+    pub fn add(&self, filter: EventFilter, listener: Listener) -> SubscriptionHandle {
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.listeners.push((id, filter, listener));
+
+        SubscriptionHandle {
+            registry: Arc::downgrade(&self.registry),
+            id,
+        }
+    }
+
+    /// Call every listener whose filter matches the event
+    pub fn notify(&self, event: &DomainEvent) {
+        // Listeners are called without holding the lock, so that they are
+        // free to subscribe or unsubscribe
+        let listeners = self
+            .registry
+            .lock()
+            .unwrap()
+            .listeners
+            .iter()
+            .filter(|(_, filter, _)| filter.matches(event))
+            .map(|(_, _, listener)| listener.clone())
+            .collect::<Vec<_>>();
+
+        for listener in listeners {
+            listener(event);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.lock().unwrap().listeners.len()
+    }
+}
+
+/// Keeps a listener subscribed, dropping the handle unsubscribes it
+#[must_use = "the listener is unsubscribed as soon as the handle is dropped"]
+pub struct SubscriptionHandle {
+    registry: Weak<Mutex<Registry>>,
+    id: u64,
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry
+                .lock()
+                .unwrap()
+                .listeners
+                .retain(|(id, _, _)| *id != self.id);
+        }
+    }
+}
+
+/// Buffers the events of a listener so that they can be received one at a
+/// time. Once `capacity` events are waiting, newer events are dropped and
+/// counted as lagged instead of holding up the bus. Events that end streams
+/// are never dropped, so that lagging streams are still closed.
+pub struct EventSubscription {
+    rx: mpsc::Receiver<DomainEvent>,
+    // Events that end streams wait here when the buffer is full
+    overflow: mpsc::UnboundedReceiver<DomainEvent>,
+    lagged: Arc<AtomicU64>,
+    _handle: SubscriptionHandle,
+}
+
+impl EventSubscription {
+    pub async fn new(
+        bus: &(dyn EventBus + Send + Sync),
+        filter: EventFilter,
+        capacity: usize,
+    ) -> EventSubscription {
+        let (tx, rx) = mpsc::channel(capacity);
+        let (overflow_tx, overflow) = mpsc::unbounded_channel();
+        let lagged = Arc::new(AtomicU64::new(0));
+
+        let dropped = lagged.clone();
+        let listener: Listener =
+            Arc::new(
+                move |event: &DomainEvent| match tx.try_send(event.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(event)) if event.ends_streams() => {
+                        let _ = overflow_tx.send(event);
+                    }
+                    Err(_) => {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+            );
+        let handle = bus.subscribe(filter, listener).await;
+
+        EventSubscription {
+            rx,
+            overflow,
+            lagged,
+            _handle: handle,
+        }
+    }
+
+    /// The next event, `None` once the bus is gone. The buffered events are
+    /// received before the ones that didn't fit, as they are older.
+    pub async fn recv(&mut self) -> Option<DomainEvent> {
+        tokio::select! {
+            biased;
+            Some(event) = self.rx.recv() => Some(event),
+            Some(event) = self.overflow.recv() => Some(event),
+            else => None,
+        }
+    }
+
+    /// Number of events that were dropped since the last call
+    pub fn take_lagged(&self) -> u64 {
+        self.lagged.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{EventFilter, EventSubscription, Listeners};
+    use crate::events::{
+        DomainEvent, EventBus, RecordingEventBus, RoomWasCreated, ServerShuttingDown,
+        UserJoinedRoom, UserLoggedIn,
+    };
+
+    fn joined(room_id: Uuid) -> DomainEvent {
+        DomainEvent::UserJoinedRoom(UserJoinedRoom {
+            room_id,
+            username: "Jane".to_string(),
+            joined_at: OffsetDateTime::UNIX_EPOCH,
+        })
+    }
+
+    #[test]
+    fn test_filter() {
+        let room_id = Uuid::new_v4();
+        let created = DomainEvent::RoomWasCreated(RoomWasCreated {
+            id: room_id,
+            name: "Lustrum".to_string(),
+            created_at: OffsetDateTime::now_utc(),
+        });
+        let logged_in = DomainEvent::UserLoggedIn(UserLoggedIn {
+            username: "Jane".to_string(),
+        });
+
+        assert!(EventFilter::all().matches(&logged_in));
+
+        let filter = EventFilter::all()
+            .of::<RoomWasCreated>()
+            .of::<UserJoinedRoom>();
+        assert!(filter.matches(&created));
+        assert!(filter.matches(&joined(room_id)));
+        assert!(!filter.matches(&logged_in));
+
+        let filter = EventFilter::all().in_room(room_id);
+        assert!(filter.matches(&created));
+        assert!(!filter.matches(&joined(Uuid::new_v4())));
+        assert!(!filter.matches(&logged_in));
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let listeners = Listeners::default();
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = received.clone();
+        let handle = listeners.add(
+            EventFilter::all(),
+            Arc::new(move |event| sink.lock().unwrap().push(event.clone())),
+        );
+        assert_eq!(listeners.len(), 1);
+
+        let room_id = Uuid::new_v4();
+        listeners.notify(&joined(room_id));
+        drop(handle);
+        listeners.notify(&joined(room_id));
+
+        assert_eq!(listeners.len(), 0);
+        assert_eq!(*received.lock().unwrap(), vec![joined(room_id)]);
+    }
+
+    #[tokio::test]
+    async fn test_recording_bus_subscription() {
+        let bus = RecordingEventBus::default();
+        let room_id = Uuid::new_v4();
+        let mut subscription =
+            EventSubscription::new(&bus, EventFilter::all().in_room(room_id), 1).await;
+
+        bus.dispatch_event(joined(Uuid::new_v4())).await;
+        bus.dispatch_event(joined(room_id)).await;
+        // The buffer only holds a single event
+        bus.dispatch_event(joined(room_id)).await;
+
+        assert_eq!(subscription.recv().await, Some(joined(room_id)));
+        assert_eq!(subscription.take_lagged(), 1);
+        assert_eq!(bus.recorded_events().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stream_ending_events_are_never_dropped() {
+        let bus = RecordingEventBus::default();
+        let room_id = Uuid::new_v4();
+        let mut subscription = EventSubscription::new(&bus, EventFilter::all(), 1).await;
+        let shutdown = DomainEvent::ServerShuttingDown(ServerShuttingDown {
+            reconnect_after_ms: 1000,
+        });
+
+        bus.dispatch_event(joined(room_id)).await;
+        bus.dispatch_event(joined(room_id)).await;
+        bus.dispatch_event(shutdown.clone()).await;
+
+        assert_eq!(subscription.recv().await, Some(joined(room_id)));
+        assert_eq!(subscription.recv().await, Some(shutdown));
+        assert_eq!(subscription.take_lagged(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::marker::PhantomData;
+
+use poem::{
+    async_trait,
+    http::{header, Method, StatusCode},
+    Endpoint, Request, Response, Result,
+};
+use rust_embed::RustEmbed;
+
+/// The built UI, embedded into the binary at compile time
+#[cfg(feature = "embed-ui")]
+#[derive(RustEmbed)]
+#[folder = "ui/dist"]
+#[exclude = "*.map"]
+pub struct UiAssets;
+
+/// Vite puts a content hash in the file names of everything in this directory,
+/// so these files can be cached forever
+const IMMUTABLE_PREFIX: &str = "assets/";
+
+/// Serves a single page application from embedded files.
+///
+/// Unknown paths fall back to `index.html` so that client side routing works,
+/// responses get an `ETag` based on the file contents and if the client accepts
+/// it a precompressed `.br` or `.gz` version of the file is served instead.
+pub struct EmbeddedUiEndpoint<E> {
+    _embed: PhantomData<E>,
+}
+
+impl<E: RustEmbed> EmbeddedUiEndpoint<E> {
+    pub fn new() -> Self {
+        EmbeddedUiEndpoint {
+            _embed: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<E: RustEmbed + Send + Sync> Endpoint for EmbeddedUiEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Ok(StatusCode::METHOD_NOT_ALLOWED.into());
+        }
+
+        let path = req.uri().path().trim_start_matches('/');
+        let (path, file) = match E::get(path) {
+            Some(file) => (path, file),
+            // A missing asset should not be answered with html
+            None if path.starts_with(IMMUTABLE_PREFIX) => {
+                return Ok(StatusCode::NOT_FOUND.into());
+            }
+            None => match E::get("index.html") {
+                Some(file) => ("index.html", file),
+                None => return Ok(StatusCode::NOT_FOUND.into()),
+            },
+        };
+
+        let etag = format!(
+            "\"{}\"",
+            file.metadata
+                .sha256_hash()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        );
+        let cache_control = if path.starts_with(IMMUTABLE_PREFIX) {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        };
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+
+        let response = Response::builder()
+            .header(header::ETAG, etag.as_str())
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::VARY, "Accept-Encoding");
+
+        let is_fresh = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+        if is_fresh {
+            return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+        }
+
+        let accept_encoding = req
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let precompressed = [("br", "br"), ("gzip", "gz")]
+            .into_iter()
+            .filter(|(encoding, _)| accepts_encoding(accept_encoding, encoding))
+            .find_map(|(encoding, extension)| {
+                E::get(&format!("{}.{}", path, extension)).map(|file| (encoding, file))
+            });
+
+        let response = response.header(header::CONTENT_TYPE, mime.as_ref());
+        let response = match precompressed {
+            Some((encoding, compressed)) => response
+                .header(header::CONTENT_ENCODING, encoding)
+                .body(compressed.data.into_owned()),
+            None => response.body(file.data.into_owned()),
+        };
+
+        Ok(response)
+    }
+}
+
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|accepted| {
+        let mut parts = accepted.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let rejected = parts.any(|parameter| {
+            parameter
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+
+        name.eq_ignore_ascii_case(encoding) && !rejected
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use poem::{
+        http::{header, StatusCode},
+        test::TestClient,
+    };
+    use rust_embed::RustEmbed;
+
+    use super::EmbeddedUiEndpoint;
+
+    #[derive(RustEmbed)]
+    #[folder = "src/fixtures/ui"]
+    struct Fixtures;
+
+    #[tokio::test]
+    async fn test_embedded_ui() {
+        let client = TestClient::new(EmbeddedUiEndpoint::<Fixtures>::new());
+
+        let resp = client.get("/").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("text/html");
+        resp.assert_header(header::CACHE_CONTROL, "no-cache");
+        let etag = resp
+            .0
+            .headers()
+            .get(header::ETAG)
+            .expect("Missing etag")
+            .clone();
+        resp.assert_text("<div id=\"root\"></div>\n").await;
+
+        // Client side routes are handled by the index
+        let resp = client.get("/rooms/123").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header(header::ETAG, etag.clone());
+
+        let resp = client
+            .get("/")
+            .header(header::IF_NONE_MATCH, etag)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_MODIFIED);
+
+        let resp = client.get("/assets/app.js").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header(header::CACHE_CONTROL, "public, max-age=31536000, immutable");
+        resp.assert_header_is_not_exist(header::CONTENT_ENCODING);
+        resp.assert_text("console.log(\"Hoi\");\n").await;
+
+        let resp = client.get("/assets/missing.js").send().await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+
+        let resp = client
+            .get("/assets/app.js")
+            .header(header::ACCEPT_ENCODING, "br;q=0, gzip, deflate")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/javascript");
+        resp.assert_header(header::CONTENT_ENCODING, "gzip");
+        resp.assert_bytes(Fixtures::get("assets/app.js.gz").unwrap().data.to_vec())
+            .await;
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use poem_openapi::types::ToJSON;
+use serde_json::Value;
+
+use crate::markdown::{render, MessageFormat};
+
+/// Transforms the payload of an event from one schema version to the next
+pub type Upcast = fn(Value) -> Value;
+
+/// Brings events that were stored with an older schema up to date when they
+/// are read, so that the rest of the code only deals with the current shape.
+///
+/// Whenever a payload changes in an incompatible way, bump the `VERSION` of
+/// its `EventPayload` implementation and register an upcaster from the
+/// previous version.
+pub struct UpcasterRegistry {
+    upcasters: HashMap<(String, u32), Upcast>,
+}
+
+impl UpcasterRegistry {
+    /// A registry without any upcasters
+    pub fn empty() -> UpcasterRegistry {
+        UpcasterRegistry {
+            upcasters: HashMap::new(),
+        }
+    }
+
+    /// Register how to turn version `from_version` of `event_type` into
+    /// version `from_version + 1`
+    pub fn register(&mut self, event_type: &str, from_version: u32, upcast: Upcast) {
+        self.upcasters
+            .insert((event_type.to_string(), from_version), upcast);
+    }
+
+    /// Apply every upcaster starting at `version`, returning the version the
+    /// payload ended up at
+    pub fn upcast(&self, event_type: &str, mut version: u32, mut payload: Value) -> (u32, Value) {
+        while let Some(upcast) = self.upcasters.get(&(event_type.to_string(), version)) {
+            payload = upcast(payload);
+            version += 1;
+        }
+
+        (version, payload)
+    }
+}
+
+impl Default for UpcasterRegistry {
+    fn default() -> Self {
+        let mut registry = UpcasterRegistry::empty();
+        registry.register("MessageWasSend", 1, message_was_send_v1);
+        registry.register("MessageWasSend", 2, message_was_send_v2);
+        registry.register("MessageWasSend", 3, message_was_send_v3);
+        registry
+    }
+}
+
+/// Messages were plain text before markdown support added `format` and
+/// `rendered_html`
+fn message_was_send_v1(mut payload: Value) -> Value {
+    if let Some(object) = payload.as_object_mut() {
+        let message = object
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let rendered_html = render(MessageFormat::Plain, message);
+
+        object.insert(
+            "format".to_string(),
+            MessageFormat::Plain.to_json().unwrap(),
+        );
+        object.insert("rendered_html".to_string(), Value::String(rendered_html));
+    }
+
+    payload
+}
+
+/// Attachments used to only be kept with the read model of a message
+fn message_was_send_v2(mut payload: Value) -> Value {
+    if let Some(object) = payload.as_object_mut() {
+        object.insert("attachments".to_string(), Value::Array(Vec::new()));
+    }
+
+    payload
+}
+
+/// Every message was sent by a user before incoming webhooks
+fn message_was_send_v3(mut payload: Value) -> Value {
+    if let Some(object) = payload.as_object_mut() {
+        object.insert("bot".to_string(), Value::Bool(false));
+    }
+
+    payload
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+    use time::OffsetDateTime;
+    use uuid::uuid;
+
+    use super::UpcasterRegistry;
+    use crate::{
+        events::{DecodeError, DomainEvent, Envelope, MessageWasSend, RoomWasCreated},
+        markdown::MessageFormat,
+    };
+
+    /// 2024-06-09T12:00:00Z, as used in the fixtures
+    fn noon() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1717934400).unwrap()
+    }
+
+    fn fixture(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_upcast_message_was_send_v1() {
+        let envelope = Envelope::from_json(
+            fixture(include_str!("../fixtures/events/message_was_send.v1.json")),
+            &UpcasterRegistry::default(),
+        )
+        .unwrap();
+
+        assert_eq!(envelope.version, 3);
+        assert_eq!(envelope.schema_version, 4);
+        assert_eq!(
+            envelope.event,
+            DomainEvent::MessageWasSend(MessageWasSend {
+                id: uuid!("0f6c5c0e-8a4b-4f4e-9a43-0f1fd3c8a0a1"),
+                room_id: uuid!("6d1b1f3c-3c1e-4a53-8a6a-4c1c5e1fbd52"),
+                username: "Jane".to_string(),
+                message: "Hi <b>all</b>\nwelcome".to_string(),
+                format: MessageFormat::Plain,
+                rendered_html: "Hi &lt;b&gt;all&lt;/b&gt;<br>welcome".to_string(),
+                send_at: noon(),
+                attachments: Vec::new(),
+                bot: false,
+            })
+        );
+
+        // Without the upcaster the old payload misses fields
+        let error = Envelope::from_json(
+            fixture(include_str!("../fixtures/events/message_was_send.v1.json")),
+            &UpcasterRegistry::empty(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            DecodeError::UnsupportedVersion { version: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_read_current_version() {
+        let envelope = Envelope::from_json(
+            fixture(include_str!("../fixtures/events/room_was_created.v1.json")),
+            &UpcasterRegistry::default(),
+        )
+        .unwrap();
+
+        assert_eq!(envelope.schema_version, 1);
+        assert_eq!(
+            envelope.event,
+            DomainEvent::RoomWasCreated(RoomWasCreated {
+                id: uuid!("6d1b1f3c-3c1e-4a53-8a6a-4c1c5e1fbd52"),
+                name: "Lustrum Crash & Compile".to_string(),
+                created_at: noon(),
+            })
+        );
+
+        let round_trip =
+            Envelope::from_json(envelope.to_json(), &UpcasterRegistry::default()).unwrap();
+        assert_eq!(round_trip, envelope);
+    }
+
+    #[test]
+    fn test_reject_unknown_events() {
+        let registry = UpcasterRegistry::default();
+        let mut stored = fixture(include_str!("../fixtures/events/room_was_created.v1.json"));
+
+        stored["schema_version"] = json!(2);
+        assert!(matches!(
+            Envelope::from_json(stored.clone(), &registry),
+            Err(DecodeError::UnsupportedVersion { version: 2, .. })
+        ));
+
+        stored["type"] = json!("RoomWasPainted");
+        assert!(matches!(
+            Envelope::from_json(stored, &registry),
+            Err(DecodeError::UnknownEventType(event_type)) if event_type == "RoomWasPainted"
+        ));
+    }
+}
@@ -0,0 +1,760 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use hmac::{Hmac, Mac};
+use poem::{http::StatusCode, web::Data, Error, Result};
+use poem_openapi::{param::Path, payload::Json, Object, OpenApi};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tracing::Instrument;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    admin::protect_admin,
+    auth::AuthData,
+    config::WebhooksConfig,
+    events::{DomainEvent, EventBus},
+    link_preview::{is_allowed_url, PublicAddressResolver},
+    subscriptions::{EventFilter, Listener},
+    Context,
+};
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    id: Uuid,
+    url: String,
+    /// Only events of these types are delivered, every event when empty
+    event_types: Vec<String>,
+    /// Only events about this room are delivered
+    room_id: Option<Uuid>,
+    created_by: String,
+    created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct RegisterWebhookRequest {
+    #[oai(validator(max_length = 2048))]
+    url: String,
+    #[oai(default)]
+    event_types: Vec<String>,
+    room_id: Option<Uuid>,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+struct RegisteredWebhook {
+    #[oai(flatten)]
+    webhook: Webhook,
+    /// Key of the signatures of every delivery, only shown when registering
+    secret: String,
+}
+
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+pub struct DeliveryAttempt {
+    delivery_id: Uuid,
+    webhook_id: Uuid,
+    event_type: String,
+    /// Starts at 1 for the first attempt of a delivery
+    attempt: u32,
+    /// Status code of the response, missing when no response was received
+    status: Option<u16>,
+    error: Option<String>,
+    attempted_at: OffsetDateTime,
+}
+
+/// A delivery that kept failing after every attempt
+#[derive(Debug, Object, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    delivery_id: Uuid,
+    webhook_id: Uuid,
+    attempts: u32,
+    error: String,
+    failed_at: OffsetDateTime,
+    /// The event as it was sent in the request body
+    event: Value,
+}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    Request(reqwest::Error),
+    /// The webhook responded with a status other than 2xx
+    Status(u16),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Request(error) => write!(f, "request failed: {}", error),
+            DeliveryError::Status(status) => write!(f, "webhook responded with {}", status),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+struct Registration {
+    webhook: Webhook,
+    secret: String,
+    filter: EventFilter,
+}
+
+/// Signature of a delivery, a hex encoded HMAC-SHA256 of `{timestamp}.{body}`
+/// keyed with the secret of the webhook
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+
+    let signature = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    format!("sha256={}", signature)
+}
+
+/// Posts events to the registered webhooks. Every delivery is retried with
+/// exponential backoff, deliveries that keep failing are kept as dead letters.
+///
+/// Like link previews, webhooks can't be used to reach private addresses.
+#[derive(Clone)]
+pub struct Webhooks {
+    registrations: Arc<Mutex<Vec<Registration>>>,
+    deliveries: Arc<Mutex<HashMap<Uuid, VecDeque<DeliveryAttempt>>>>,
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+    /// Permits for the deliveries that run at the same time
+    delivery_slots: Arc<Semaphore>,
+    client: reqwest::Client,
+    config: WebhooksConfig,
+    allow_private_addresses: bool,
+}
+
+fn build_client(config: &WebhooksConfig, allow_private_addresses: bool) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .timeout(config.timeout())
+        .redirect(reqwest::redirect::Policy::none())
+        // A proxy would resolve the host for us, bypassing our guarded resolver
+        .no_proxy();
+    if !allow_private_addresses {
+        builder = builder.dns_resolver(Arc::new(PublicAddressResolver));
+    }
+
+    builder.build().expect("Failed to build http client")
+}
+
+impl Webhooks {
+    pub fn new(config: WebhooksConfig) -> Webhooks {
+        Webhooks {
+            registrations: Arc::new(Mutex::new(Vec::new())),
+            deliveries: Arc::new(Mutex::new(HashMap::new())),
+            dead_letters: Arc::new(Mutex::new(VecDeque::new())),
+            delivery_slots: Arc::new(Semaphore::new(config.max_concurrent_deliveries)),
+            client: build_client(&config, false),
+            config,
+            allow_private_addresses: false,
+        }
+    }
+
+    /// Disable the SSRF guard, only meant for testing against a local server
+    #[allow(dead_code)]
+    pub fn allow_private_addresses(self) -> Webhooks {
+        Webhooks {
+            client: build_client(&self.config, true),
+            allow_private_addresses: true,
+            ..self
+        }
+    }
+
+    /// Subscribe to the bus and deliver its events in the background
+    pub async fn start(&self, bus: &(dyn EventBus + Send + Sync)) {
+        // Bounded, so that slow webhooks can't make us run out of memory.
+        // Events are dropped once the queue is full.
+        let (tx, mut rx) = mpsc::channel(self.config.queue_size);
+        let listener: Listener = Arc::new(move |event: &DomainEvent| {
+            if tx.try_send(event.clone()).is_err() {
+                tracing::warn!(
+                    event = event.name(),
+                    "Webhook queue is full, dropping event"
+                );
+            }
+        });
+        let handle = bus.subscribe(EventFilter::all(), listener).await;
+
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let _handle = handle;
+            while let Some(event) = rx.recv().await {
+                webhooks.dispatch(event).await;
+            }
+        });
+    }
+
+    /// Start delivering an event to every webhook interested in it, waiting
+    /// for a free delivery slot for each of them
+    async fn dispatch(&self, event: DomainEvent) {
+        // Events meant for a single user are never shared with webhooks
+        if !event.is_visible_to(None) {
+            return;
+        }
+
+        let targets = self
+            .registrations
+            .lock()
+            .await
+            .iter()
+            .filter(|registration| registration.filter.matches(&event))
+            .map(|registration| (registration.webhook.clone(), registration.secret.clone()))
+            .collect::<Vec<_>>();
+
+        for (webhook, secret) in targets {
+            let span = tracing::info_span!(
+                "webhook_delivery",
+                webhook_id = %webhook.id,
+                event = event.name()
+            );
+            let slot = self
+                .delivery_slots
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("The delivery slots are never closed");
+            let delivery = self.clone().deliver(webhook, secret, event.clone());
+            tokio::spawn(
+                async move {
+                    delivery.await;
+                    drop(slot);
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    async fn deliver(self, webhook: Webhook, secret: String, event: DomainEvent) {
+        let delivery_id = Uuid::new_v4();
+        let event_json = json!(event);
+        let body = event_json.to_string();
+        let mut last_error = None;
+
+        for attempt in 1..=self.config.max_attempts {
+            let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+            let result = self
+                .post(
+                    &webhook,
+                    &secret,
+                    delivery_id,
+                    event.name(),
+                    timestamp,
+                    &body,
+                )
+                .await;
+
+            let (status, error) = match &result {
+                Ok(status) => (Some(*status), None),
+                Err(DeliveryError::Status(status)) => (Some(*status), result.as_ref().err()),
+                Err(error) => (None, Some(error)),
+            };
+            self.log(DeliveryAttempt {
+                delivery_id,
+                webhook_id: webhook.id,
+                event_type: event.name().to_string(),
+                attempt,
+                status,
+                error: error.map(ToString::to_string),
+                attempted_at: OffsetDateTime::now_utc(),
+            })
+            .await;
+
+            match result {
+                Ok(_) => return,
+                Err(error) => {
+                    tracing::warn!(attempt, %error, "Webhook delivery failed");
+                    last_error = Some(error);
+                }
+            }
+
+            if attempt < self.config.max_attempts {
+                tokio::time::sleep(self.config.backoff(attempt)).await;
+            }
+        }
+
+        tracing::error!("Giving up on webhook delivery");
+        let mut dead_letters = self.dead_letters.lock().await;
+        dead_letters.push_back(DeadLetter {
+            delivery_id,
+            webhook_id: webhook.id,
+            attempts: self.config.max_attempts,
+            error: last_error
+                .map(|error| error.to_string())
+                .unwrap_or_default(),
+            failed_at: OffsetDateTime::now_utc(),
+            event: event_json,
+        });
+        while dead_letters.len() > self.config.max_dead_letters {
+            dead_letters.pop_front();
+        }
+    }
+
+    async fn post(
+        &self,
+        webhook: &Webhook,
+        secret: &str,
+        delivery_id: Uuid,
+        event_type: &str,
+        timestamp: i64,
+        body: &str,
+    ) -> Result<u16, DeliveryError> {
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event_type)
+            .header(DELIVERY_HEADER, delivery_id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign(secret, timestamp, body))
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(DeliveryError::Request)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(DeliveryError::Status(status.as_u16()));
+        }
+
+        Ok(status.as_u16())
+    }
+
+    async fn log(&self, attempt: DeliveryAttempt) {
+        let mut deliveries = self.deliveries.lock().await;
+        let log = deliveries.entry(attempt.webhook_id).or_default();
+
+        log.push_back(attempt);
+        while log.len() > self.config.delivery_log_size {
+            log.pop_front();
+        }
+    }
+
+    async fn register(
+        &self,
+        request: RegisterWebhookRequest,
+        created_by: String,
+    ) -> Result<RegisteredWebhook> {
+        let is_allowed = Url::parse(&request.url).is_ok_and(|url| {
+            matches!(url.scheme(), "http" | "https")
+                && url.host().is_some()
+                && (self.allow_private_addresses || is_allowed_url(&url))
+        });
+        if !is_allowed {
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+
+        let known_types = DomainEvent::schemas()
+            .into_iter()
+            .map(|schema| schema.name().to_string())
+            .collect::<Vec<_>>();
+        if request
+            .event_types
+            .iter()
+            .any(|event_type| !known_types.contains(event_type))
+        {
+            return Err(Error::from_status(StatusCode::BAD_REQUEST));
+        }
+
+        let mut filter = EventFilter::all();
+        for event_type in request.event_types.iter() {
+            filter = filter.of_name(event_type.clone());
+        }
+        if let Some(room_id) = request.room_id {
+            filter = filter.in_room(room_id);
+        }
+
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url: request.url,
+            event_types: request.event_types,
+            room_id: request.room_id,
+            created_by,
+            created_at: OffsetDateTime::now_utc(),
+        };
+        let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        self.registrations.lock().await.push(Registration {
+            webhook: webhook.clone(),
+            secret: secret.clone(),
+            filter,
+        });
+        tracing::info!(webhook_id = %webhook.id, url = %webhook.url, "Registered webhook");
+
+        Ok(RegisteredWebhook { webhook, secret })
+    }
+}
+
+#[derive(Default)]
+pub struct Api;
+
+/// Deliveries are POSTed as the same JSON that is sent on the event streams.
+/// The `X-Webhook-Signature` header holds `sha256=` followed by the hex
+/// encoded HMAC-SHA256 of `{X-Webhook-Timestamp}.{body}`, keyed with the
+/// secret that was returned when registering the webhook.
+#[OpenApi]
+impl Api {
+    #[oai(
+        path = "/webhooks",
+        method = "post",
+        transform = "protect_admin",
+        operation_id = "webhooks_post"
+    )]
+    async fn register_webhook(
+        &self,
+        ctx: Data<&Context>,
+        request: Json<RegisterWebhookRequest>,
+        auth_data: Data<&AuthData>,
+    ) -> Result<Json<RegisteredWebhook>> {
+        let registered = ctx
+            .webhooks
+            .register(request.0, auth_data.username.clone())
+            .await?;
+
+        Ok(Json(registered))
+    }
+
+    #[oai(
+        path = "/webhooks",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "webhooks_get"
+    )]
+    async fn get_webhooks(&self, ctx: Data<&Context>) -> Result<Json<Vec<Webhook>>> {
+        let webhooks = ctx
+            .webhooks
+            .registrations
+            .lock()
+            .await
+            .iter()
+            .map(|registration| registration.webhook.clone())
+            .collect();
+
+        Ok(Json(webhooks))
+    }
+
+    /// Deliveries that are being retried are finished, but no new events are
+    /// delivered to the webhook
+    #[oai(
+        path = "/webhooks/:webhook_id",
+        method = "delete",
+        transform = "protect_admin",
+        operation_id = "webhooks_webhook_delete"
+    )]
+    async fn delete_webhook(&self, ctx: Data<&Context>, webhook_id: Path<Uuid>) -> Result<()> {
+        let mut registrations = ctx.webhooks.registrations.lock().await;
+        let Some(position) = registrations
+            .iter()
+            .position(|registration| registration.webhook.id == webhook_id.0)
+        else {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        };
+
+        registrations.remove(position);
+        ctx.webhooks.deliveries.lock().await.remove(&webhook_id.0);
+
+        Ok(())
+    }
+
+    /// The most recent delivery attempts of a webhook, oldest first
+    #[oai(
+        path = "/webhooks/:webhook_id/deliveries",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "webhooks_webhook_deliveries_get"
+    )]
+    async fn get_deliveries(
+        &self,
+        ctx: Data<&Context>,
+        webhook_id: Path<Uuid>,
+    ) -> Result<Json<Vec<DeliveryAttempt>>> {
+        let is_registered = ctx
+            .webhooks
+            .registrations
+            .lock()
+            .await
+            .iter()
+            .any(|registration| registration.webhook.id == webhook_id.0);
+        if !is_registered {
+            return Err(Error::from_status(StatusCode::NOT_FOUND));
+        }
+
+        let deliveries = ctx
+            .webhooks
+            .deliveries
+            .lock()
+            .await
+            .get(&webhook_id.0)
+            .map(|log| log.iter().cloned().collect())
+            .unwrap_or_default();
+
+        Ok(Json(deliveries))
+    }
+
+    #[oai(
+        path = "/webhooks/dead-letters",
+        method = "get",
+        transform = "protect_admin",
+        operation_id = "webhooks_dead_letters_get"
+    )]
+    async fn get_dead_letters(&self, ctx: Data<&Context>) -> Result<Json<Vec<DeadLetter>>> {
+        Ok(Json(
+            ctx.webhooks
+                .dead_letters
+                .lock()
+                .await
+                .iter()
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use poem::{
+        handler,
+        http::{HeaderMap, StatusCode},
+        listener::{Acceptor, Listener, TcpListener},
+        web::Data,
+        EndpointExt, Route, Server,
+    };
+    use serde_json::json;
+    use tokio::sync::Mutex;
+    use uuid::Uuid;
+
+    use super::{
+        sign, RegisterWebhookRequest, Webhooks, EVENT_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::{
+        config::WebhooksConfig,
+        events::{
+            BroadcastingEventBus, DomainEvent, EventBus, RoomWasCreated, UserLoggedIn,
+            UserWasMentioned,
+        },
+    };
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// Stands in for the receiving end, failing the first `failures` requests
+    async fn serve(failures: usize) -> (String, Received) {
+        #[handler]
+        async fn receive(
+            headers: &HeaderMap,
+            body: String,
+            received: Data<&Received>,
+            failures: Data<&usize>,
+        ) -> StatusCode {
+            let mut received = received.lock().await;
+            received.push((headers.clone(), body));
+
+            if received.len() <= *failures.0 {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::NO_CONTENT
+            }
+        }
+
+        let received = Received::default();
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let addr = acceptor.local_addr()[0].as_socket_addr().cloned().unwrap();
+        tokio::spawn(
+            Server::new_with_acceptor(acceptor).run(
+                Route::new()
+                    .at("/hook", receive)
+                    .data(received.clone())
+                    .data(failures),
+            ),
+        );
+
+        (format!("http://{}/hook", addr), received)
+    }
+
+    fn config() -> WebhooksConfig {
+        WebhooksConfig {
+            timeout_secs: 1,
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            delivery_log_size: 10,
+            queue_size: 16,
+            max_concurrent_deliveries: 2,
+            max_dead_letters: 1,
+        }
+    }
+
+    async fn eventually(condition: impl AsyncFn() -> bool) {
+        for _ in 0..200 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Condition was not met in time");
+    }
+
+    #[tokio::test]
+    async fn test_deliver_with_retries() {
+        let (url, received) = serve(1).await;
+        let bus = BroadcastingEventBus::default();
+        let webhooks = Webhooks::new(config()).allow_private_addresses();
+        webhooks.start(&bus).await;
+
+        let room_id = Uuid::new_v4();
+        let registered = webhooks
+            .register(
+                RegisterWebhookRequest {
+                    url,
+                    event_types: vec!["RoomWasCreated".to_string()],
+                    room_id: Some(room_id),
+                },
+                "Admin".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let created = DomainEvent::RoomWasCreated(RoomWasCreated {
+            id: room_id,
+            name: "Lustrum".to_string(),
+            created_at: time::OffsetDateTime::now_utc(),
+        });
+        // Neither of these match the filters of the webhook
+        bus.dispatch_event(DomainEvent::UserLoggedIn(UserLoggedIn {
+            username: "Jane".to_string(),
+        }))
+        .await;
+        bus.dispatch_event(DomainEvent::RoomWasCreated(RoomWasCreated {
+            id: Uuid::new_v4(),
+            name: "Borrel".to_string(),
+            created_at: time::OffsetDateTime::now_utc(),
+        }))
+        .await;
+        bus.dispatch_event(created.clone()).await;
+
+        eventually(async || received.lock().await.len() == 2).await;
+        let deliveries = webhooks.deliveries.lock().await[&registered.webhook.id].clone();
+        assert_eq!(
+            deliveries
+                .iter()
+                .map(|attempt| (attempt.attempt, attempt.status))
+                .collect::<Vec<_>>(),
+            vec![(1, Some(500)), (2, Some(204))]
+        );
+        assert!(webhooks.dead_letters.lock().await.is_empty());
+
+        let (headers, body) = received.lock().await[1].clone();
+        assert_eq!(body, json!(created).to_string());
+        assert_eq!(headers[EVENT_HEADER], "RoomWasCreated");
+        let timestamp = headers[TIMESTAMP_HEADER]
+            .to_str()
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+        assert_eq!(
+            headers[SIGNATURE_HEADER],
+            sign(&registered.secret, timestamp, &body).as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let (url, received) = serve(usize::MAX).await;
+        let bus = BroadcastingEventBus::default();
+        let webhooks = Webhooks::new(config()).allow_private_addresses();
+        webhooks.start(&bus).await;
+        let registered = webhooks
+            .register(
+                RegisterWebhookRequest {
+                    url,
+                    event_types: Vec::new(),
+                    room_id: None,
+                },
+                "Admin".to_string(),
+            )
+            .await
+            .unwrap();
+
+        // Mentions are only meant for the mentioned user
+        bus.dispatch_event(DomainEvent::UserWasMentioned(UserWasMentioned {
+            message_id: Uuid::new_v4(),
+            room_id: Uuid::new_v4(),
+            username: "Jane".to_string(),
+            mentioned_by: "John".to_string(),
+            mentioned_at: time::OffsetDateTime::now_utc(),
+        }))
+        .await;
+        let event = DomainEvent::UserLoggedIn(UserLoggedIn {
+            username: "Jane".to_string(),
+        });
+        bus.dispatch_event(event).await;
+
+        eventually(async || !webhooks.dead_letters.lock().await.is_empty()).await;
+        assert_eq!(received.lock().await.len(), 3);
+
+        // Only the most recent dead letters are kept
+        let john = DomainEvent::UserLoggedIn(UserLoggedIn {
+            username: "John".to_string(),
+        });
+        bus.dispatch_event(john.clone()).await;
+        eventually(async || webhooks.dead_letters.lock().await[0].event == json!(john)).await;
+        assert_eq!(webhooks.dead_letters.lock().await.len(), 1);
+        assert_eq!(received.lock().await.len(), 6);
+
+        let dead_letter = webhooks.dead_letters.lock().await[0].clone();
+        assert_eq!(dead_letter.webhook_id, registered.webhook.id);
+        assert_eq!(dead_letter.attempts, 3);
+        assert_eq!(dead_letter.event, json!(john));
+        assert_eq!(dead_letter.error, "webhook responded with 500");
+    }
+
+    #[tokio::test]
+    async fn test_register_private_address() {
+        let webhooks = Webhooks::new(config());
+        for url in [
+            "http://127.0.0.1:3000/hook",
+            "http://[::1]/hook",
+            "ftp://example.com",
+        ] {
+            let error = webhooks
+                .register(
+                    RegisterWebhookRequest {
+                        url: url.to_string(),
+                        event_types: Vec::new(),
+                        room_id: None,
+                    },
+                    "Admin".to_string(),
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("secret", 1717934400, "{}"),
+            "sha256=6a15ded7057526ecb7413537780e819618612c8a105ac964f6ca563e81a2f4c7"
+        );
+    }
+}
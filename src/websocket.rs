@@ -0,0 +1,368 @@
+use futures_util::{SinkExt, StreamExt};
+use poem::{
+    session::Session,
+    web::{
+        websocket::{BoxWebSocketUpgraded, CloseCode, Message, WebSocket, WebSocketStream},
+        Data,
+    },
+    Error,
+};
+use poem_openapi::{types::ParseFromJSON, Object, OpenApi, Union};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tracing::{Instrument, Span};
+use uuid::Uuid;
+
+use crate::{
+    auth::{protect, AuthData},
+    events::{DomainEvent, UserStartedTyping},
+    metrics::Subscription,
+    rate_limit::{SEND_MESSAGE, TYPING},
+    sessions::session_id,
+    subscriptions::EventFilter,
+    Context, JoinRoomRequest, LeaveRoomRequest, SendMessageRequest,
+};
+
+#[derive(Debug, Object)]
+struct SendMessageCommand {
+    room_id: Uuid,
+    #[oai(flatten)]
+    request: SendMessageRequest,
+}
+
+#[derive(Debug, Object)]
+struct JoinRoomCommand {
+    room_id: Uuid,
+    #[oai(flatten)]
+    request: JoinRoomRequest,
+}
+
+#[derive(Debug, Object)]
+struct LeaveRoomCommand {
+    room_id: Uuid,
+    #[oai(flatten)]
+    request: LeaveRoomRequest,
+}
+
+#[derive(Debug, Object)]
+struct TypingCommand {
+    room_id: Uuid,
+}
+
+/// Commands that clients can send over the websocket, these mirror the
+/// corresponding REST endpoints
+#[derive(Debug, Union)]
+#[oai(discriminator_name = "type")]
+enum Command {
+    #[oai(mapping = "SendMessage")]
+    SendMessage(SendMessageCommand),
+    #[oai(mapping = "JoinRoom")]
+    JoinRoom(JoinRoomCommand),
+    #[oai(mapping = "LeaveRoom")]
+    LeaveRoom(LeaveRoomCommand),
+    #[oai(mapping = "Typing")]
+    Typing(TypingCommand),
+}
+
+/// Parse and execute a command received from a client, returning a message
+/// for the client if the command was rejected
+async fn handle_command(
+    ctx: &Context,
+    username: &str,
+    session_id: Uuid,
+    text: &str,
+) -> Option<Value> {
+    // The socket outlives the request that checked the session
+    if !ctx.sessions.is_active(session_id).await {
+        return Some(rejected(401, "The session has ended"));
+    }
+
+    let command = match Command::parse_from_json_string(text) {
+        Ok(command) => command,
+        Err(error) => return Some(rejected(400, &error.into_message())),
+    };
+
+    let username = username.to_string();
+    let result = match command {
+        Command::SendMessage(command) => {
+            // Sockets share their limit with the REST endpoint
+            let key = format!("user:{}", username);
+            if ctx.rate_limiter.check(SEND_MESSAGE, &key).await.is_err() {
+                return Some(rejected(429, "Too many messages"));
+            }
+
+            match command
+                .request
+                .into_command(ctx, command.room_id, username)
+                .await
+            {
+                Ok(command) => ctx.send_message(command).await,
+                Err(error) => Err(error),
+            }
+        }
+        Command::JoinRoom(command) => {
+            ctx.execute_and_dispatch(command.request.into_command(command.room_id, username))
+                .await
+        }
+        Command::LeaveRoom(command) => {
+            ctx.execute_and_dispatch(command.request.into_command(command.room_id, username))
+                .await
+        }
+        Command::Typing(command) => {
+            // Typing is announced to everyone following the room
+            if let Err(error) = ctx.ensure_member(command.room_id, &username).await {
+                return Some(rejected(error.status().as_u16(), &error.to_string()));
+            }
+
+            let key = format!("user:{}", username);
+            if ctx.rate_limiter.check(TYPING, &key).await.is_err() {
+                return Some(rejected(429, "Too many typing notifications"));
+            }
+
+            ctx.bus
+                .dispatch_event(DomainEvent::UserStartedTyping(UserStartedTyping {
+                    room_id: command.room_id,
+                    username,
+                    started_at: OffsetDateTime::now_utc(),
+                }))
+                .await;
+
+            Ok(())
+        }
+    };
+
+    result
+        .err()
+        .map(|error: Error| rejected(error.status().as_u16(), &error.to_string()))
+}
+
+fn rejected(status: u16, reason: &str) -> Value {
+    json!({
+        "type": "CommandRejected",
+        "payload": {
+            "status": status,
+            "reason": reason,
+        }
+    })
+}
+
+#[derive(Default)]
+pub struct Api;
+
+#[OpenApi]
+impl Api {
+    /// Bidirectional alternative to the `/events` stream, the socket receives
+    /// the same events and accepts commands such as sending a message
+    #[oai(
+        path = "/ws",
+        method = "get",
+        transform = "protect",
+        operation_id = "ws_get"
+    )]
+    async fn ws(
+        &self,
+        ctx: Data<&Context>,
+        auth_data: Data<&AuthData>,
+        session: &Session,
+        websocket: WebSocket,
+    ) -> BoxWebSocketUpgraded {
+        let ctx = ctx.clone();
+        let username = auth_data.username.clone();
+        let session_id = session_id(session);
+        let subscription = ctx.subscribe("websocket", EventFilter::all()).await;
+        // Commands are handled after this request finished, keep them linked to it
+        let span = Span::current();
+
+        websocket
+            .on_upgrade(move |socket| {
+                serve_socket(ctx, username, session_id, subscription, socket).instrument(span)
+            })
+            .boxed()
+    }
+}
+
+/// Forward events to the socket and handle the commands it sends until either
+/// side closes the connection
+async fn serve_socket(
+    ctx: Context,
+    username: String,
+    session_id: Uuid,
+    mut subscription: Subscription,
+    socket: WebSocketStream,
+) {
+    let (mut sink, mut stream) = socket.split();
+
+    loop {
+        tokio::select! {
+            event = subscription.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+
+                if !event.is_visible_to(Some(&username)) {
+                    continue;
+                }
+
+                if sink.send(Message::Text(json!(event).to_string())).await.is_err() {
+                    break;
+                }
+
+                if event.ends_stream_of(Some(&username), session_id) {
+                    let code = match event {
+                        DomainEvent::ServerShuttingDown(_) => CloseCode::Away,
+                        DomainEvent::SessionWasRevoked(_) => CloseCode::Normal,
+                        _ => CloseCode::Policy,
+                    };
+                    let close = Message::Close(Some((code, String::new())));
+                    let _ = sink.send(close).await;
+                    break;
+                }
+            }
+            message = stream.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                if let Some(reply) = handle_command(&ctx, &username, session_id, &text).await {
+                    if sink.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use poem::session::SessionStorage;
+    use serde_json::json;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::handle_command;
+    use crate::{
+        commands::CreateRoom,
+        events::{DomainEvent, RecordingEventBus, UserJoinedRoom},
+        Context,
+    };
+
+    #[tokio::test]
+    async fn test_handle_command() {
+        let bus = Arc::new(RecordingEventBus::default());
+        let ctx = Context::new(bus.clone());
+        let room_id = Uuid::new_v4();
+        ctx.execute(CreateRoom {
+            room_id,
+            name: "Lustrum".to_string(),
+            username: "John".to_string(),
+            created_at: OffsetDateTime::now_utc(),
+        })
+        .await
+        .unwrap();
+
+        let session_id = Uuid::new_v4();
+        let entries = BTreeMap::from([
+            ("username".to_string(), json!("Jane")),
+            ("session_id".to_string(), json!(session_id)),
+        ]);
+        ctx.sessions
+            .update_session("cookie", &entries, None)
+            .await
+            .unwrap();
+
+        let reply = handle_command(
+            &ctx,
+            "Jane",
+            session_id,
+            &json!({
+                "type": "JoinRoom",
+                "room_id": room_id,
+                "joined_at": "2024-06-09T12:00:00Z"
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(reply, None);
+
+        let reply = handle_command(
+            &ctx,
+            "Jane",
+            session_id,
+            &json!({
+                "type": "SendMessage",
+                "room_id": room_id,
+                "id": Uuid::new_v4(),
+                "message": "",
+                "send_at": "2024-06-09T12:00:00Z"
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(
+            reply.as_ref().map(|reply| &reply["payload"]["status"]),
+            Some(&json!(400))
+        );
+
+        let reply = handle_command(&ctx, "Jane", session_id, r#"{"type": "Dance"}"#).await;
+        assert_eq!(
+            reply.map(|reply| reply["type"].clone()),
+            Some(json!("CommandRejected"))
+        );
+
+        // Typing in a room Jane isn't a member of doesn't use up her limit
+        let elsewhere = json!({ "type": "Typing", "room_id": Uuid::new_v4() }).to_string();
+        for _ in 0..6 {
+            let reply = handle_command(&ctx, "Jane", session_id, &elsewhere).await;
+            assert_eq!(
+                reply.as_ref().map(|reply| &reply["payload"]["status"]),
+                Some(&json!(403))
+            );
+        }
+        let typing = json!({ "type": "Typing", "room_id": room_id }).to_string();
+        for _ in 0..5 {
+            assert_eq!(
+                handle_command(&ctx, "Jane", session_id, &typing).await,
+                None
+            );
+        }
+        let reply = handle_command(&ctx, "Jane", session_id, &typing).await;
+        assert_eq!(
+            reply.as_ref().map(|reply| &reply["payload"]["status"]),
+            Some(&json!(429))
+        );
+
+        // Commands of revoked sessions are rejected, even before the socket
+        // is closed
+        ctx.sessions.revoke_user("Jane").await.unwrap();
+        let reply = handle_command(
+            &ctx,
+            "Jane",
+            session_id,
+            &json!({
+                "type": "SendMessage",
+                "room_id": room_id,
+                "id": Uuid::new_v4(),
+                "message": "Still here",
+                "send_at": "2024-06-09T12:00:00Z"
+            })
+            .to_string(),
+        )
+        .await;
+        assert_eq!(
+            reply.as_ref().map(|reply| &reply["payload"]["status"]),
+            Some(&json!(401))
+        );
+
+        let recorded_events = bus.recorded_events().await;
+        assert_eq!(recorded_events.len(), 6);
+        assert!(matches!(
+            &recorded_events[0],
+            DomainEvent::UserJoinedRoom(UserJoinedRoom { username, .. }) if username == "Jane"
+        ));
+    }
+}
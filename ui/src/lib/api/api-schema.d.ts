@@ -10,6 +10,13 @@ export interface paths {
     post: operations["session_post"];
     delete: operations["session_delete"];
   };
+  "/sessions": {
+    /**
+     * @description Log out every session of the current user, for instance after a
+     * cookie was leaked
+     */
+    delete: operations["sessions_delete"];
+  };
   "/rooms": {
     get: operations["rooms_get"];
     post: operations["rooms_post"];
@@ -26,12 +33,19 @@ export interface paths {
     get: operations["rooms_room_messages_get"];
     post: operations["rooms_room_messages_post"];
   };
+  "/mentions": {
+    get: operations["mentions_get"];
+  };
+  "/mentions/{message_id}": {
+    delete: operations["mentions_mention_delete"];
+  };
   "/events": {
+    /** @description Requires a session, so banned users can't keep reading along */
     get: {
       responses: {
         200: {
           content: {
-            "text/event-stream": unknown[];
+            "text/event-stream": components["schemas"]["DomainEvent"][];
           };
         };
       };
@@ -42,31 +56,107 @@ export interface paths {
       responses: {
         200: {
           content: {
-            "text/event-stream": unknown[];
+            "text/event-stream": components["schemas"]["DomainEvent"][];
           };
         };
       };
     };
   };
   "/generate-events": {
-    get: {
-      responses: {
-        200: {
-          content: never;
-        };
-      };
-    };
+    get: operations["generate_events"];
   };
-  "/get-event-types": {
-    get: {
-      responses: {
-        200: {
-          content: {
-            "application/json; charset=utf-8": components["schemas"]["DomainEvent"];
-          };
-        };
-      };
-    };
+  "/events/schema": {
+    /**
+     * @description Every event type that can be received from the event streams, with a
+     * JSON Schema of the event. Events are sent with their type as name, so
+     * browsers can listen to a single type with `addEventListener`.
+     */
+    get: operations["events_schema_get"];
+  };
+  "/rooms/{room_id}/attachments": {
+    post: operations["rooms_room_attachments_post"];
+  };
+  "/rooms/{room_id}/attachments/{attachment_id}": {
+    get: operations["rooms_room_attachments_attachment_get"];
+  };
+  "/rooms/{room_id}/attachments/{attachment_id}/thumbnail": {
+    get: operations["rooms_room_attachments_attachment_thumbnail_get"];
+  };
+  "/ws": {
+    /**
+     * @description Bidirectional alternative to the `/events` stream, the socket receives
+     * the same events and accepts commands such as sending a message
+     */
+    get: operations["ws_get"];
+  };
+  "/admin/rooms": {
+    get: operations["admin_rooms_get"];
+  };
+  "/admin/rooms/{room_id}/messages/{message_id}": {
+    delete: operations["admin_rooms_room_messages_message_delete"];
+  };
+  "/admin/rooms/{room_id}/users/{username}": {
+    /** @description Remove a user from a room, they are able to join again unless banned */
+    delete: operations["admin_rooms_room_users_user_delete"];
+  };
+  "/admin/bans": {
+    get: operations["admin_bans_get"];
+  };
+  "/admin/bans/{username}": {
+    /**
+     * @description Ban a user, logging them out everywhere. Banning a user that already
+     * is banned has no effect.
+     */
+    put: operations["admin_bans_ban_put"];
+    delete: operations["admin_bans_ban_delete"];
+  };
+  "/admin/projections/rebuild": {
+    /** @description Throw away the read models and rebuild them from the stored events */
+    post: operations["admin_projections_rebuild_post"];
+  };
+  "/admin/audit": {
+    /**
+     * @description Privileged actions, oldest first. `since` is inclusive, `until` is
+     * exclusive.
+     */
+    get: operations["admin_audit_get"];
+  };
+  "/admin/audit/export": {
+    /** @description The same entries as `/admin/audit` as JSON lines, one entry per line */
+    get: operations["admin_audit_export_get"];
+  };
+  "/webhooks": {
+    get: operations["webhooks_get"];
+    post: operations["webhooks_post"];
+  };
+  "/webhooks/{webhook_id}": {
+    /**
+     * @description Deliveries that are being retried are finished, but no new events are
+     * delivered to the webhook
+     */
+    delete: operations["webhooks_webhook_delete"];
+  };
+  "/webhooks/{webhook_id}/deliveries": {
+    /** @description The most recent delivery attempts of a webhook, oldest first */
+    get: operations["webhooks_webhook_deliveries_get"];
+  };
+  "/webhooks/dead-letters": {
+    get: operations["webhooks_dead_letters_get"];
+  };
+  "/rooms/{room_id}/webhooks": {
+    get: operations["rooms_room_webhooks_get"];
+    post: operations["rooms_room_webhooks_post"];
+  };
+  "/rooms/{room_id}/webhooks/{webhook_id}": {
+    delete: operations["rooms_room_webhooks_webhook_delete"];
+  };
+  "/hooks/{token}": {
+    /**
+     * @description Post a message as the webhook, authenticated by the token in the url
+     * instead of a session. The message is validated like any other message,
+     * but is never treated as a slash command.
+     */
+    post: operations["hooks_post"];
   };
 }
 
@@ -74,13 +164,77 @@ export type webhooks = Record<string, never>;
 
 export interface components {
   schemas: {
+    /** @description Includes every room together with its members, for moderation purposes */
+    AdminRoom: {
+      /** Format: uuid */
+      id: string;
+      name: string;
+      users: string[];
+      /** Format: uint64 */
+      message_count: number;
+    };
+    Attachment: {
+      /** Format: uuid */
+      id: string;
+      /** Format: uuid */
+      room_id: string;
+      uploaded_by: string;
+      filename: string;
+      content_type: string;
+      /** Format: uint64 */
+      size: number;
+      /** @description Sha256 hash of the contents, used to locate the blob */
+      hash: string;
+      /** @description Hash of a png thumbnail, only available for images */
+      thumbnail?: string;
+    };
+    /**
+     * @description The privileged actions that are recorded. Roles can't be changed at
+     * runtime, admins are configured with `admin.usernames` and the audit log
+     * can't tell who edited the configuration.
+     */
+    AuditAction: "delete_room" | "delete_message" | "kick_user" | "ban_user" | "unban_user" | "logout_everywhere";
+    /** @description A privileged action, who took it and the event that resulted from it */
+    AuditEntry: {
+      /** Format: uuid */
+      id: string;
+      /**
+       * Format: int64
+       * @description Position in the audit log, starting at 1
+       */
+      sequence: number;
+      /** Format: date-time */
+      recorded_at: string;
+      action: components["schemas"]["AuditAction"];
+      actor: string;
+      /** @description What the action was taken on, such as `room:<id>` or `user:<username>` */
+      target: string;
+      request_id?: string;
+      client_ip?: string;
+      /** @description Serialized the same way as on the event stream */
+      event: unknown;
+    };
     AuthData: {
       username: string;
     };
+    /**
+     * @description Banned users can not log in and their sessions and event streams are
+     * ended when they are banned
+     */
+    Ban: {
+      username: string;
+      banned_by: string;
+      reason?: string;
+      /** Format: date-time */
+      banned_at: string;
+    };
     "CollectionResponse<Message>": {
       items: components["schemas"]["Message"][];
       pagination: components["schemas"]["Pagination"];
     };
+    CreateIncomingWebhookRequest: {
+      name: string;
+    };
     CreateRoomRequest: {
       /** Format: uuid */
       id: string;
@@ -88,14 +242,110 @@ export interface components {
       /** Format: date-time */
       created_at: string;
     };
+    CreatedIncomingWebhook: {
+      /** Format: uuid */
+      id: string;
+      /** Format: uuid */
+      room_id: string;
+      /** @description Username of the messages posted through the webhook */
+      name: string;
+      created_by: string;
+      /** Format: date-time */
+      created_at: string;
+      /**
+       * @description Where to post messages, the url contains the secret token of the
+       * webhook and is only shown once
+       */
+      url: string;
+    };
+    /** @description A delivery that kept failing after every attempt */
+    DeadLetter: {
+      /** Format: uuid */
+      delivery_id: string;
+      /** Format: uuid */
+      webhook_id: string;
+      /** Format: uint32 */
+      attempts: number;
+      error: string;
+      /** Format: date-time */
+      failed_at: string;
+      /** @description The event as it was sent in the request body */
+      event: unknown;
+    };
+    DeliveryAttempt: {
+      /** Format: uuid */
+      delivery_id: string;
+      /** Format: uuid */
+      webhook_id: string;
+      event_type: string;
+      /**
+       * Format: uint32
+       * @description Starts at 1 for the first attempt of a delivery
+       */
+      attempt: number;
+      /**
+       * Format: uint16
+       * @description Status code of the response, missing when no response was received
+       */
+      status?: number;
+      error?: string;
+      /** Format: date-time */
+      attempted_at: string;
+    };
     DetailedRoom: {
       /** Format: uuid */
       id: string;
       name: string;
+      topic?: string;
       messages: components["schemas"]["Message"][];
       users: string[];
     };
-    DomainEvent: components["schemas"]["UserLoggedIn"] | components["schemas"]["UserLoggedOut"] | components["schemas"]["RoomWasCreated"] | components["schemas"]["RoomWasRemoved"] | components["schemas"]["UserJoinedRoom"] | components["schemas"]["UserLeftRoom"] | components["schemas"]["MessageWasSend"];
+    DomainEvent: components["schemas"]["UserLoggedIn"] | components["schemas"]["UserLoggedOut"] | components["schemas"]["RoomWasCreated"] | components["schemas"]["RoomWasRemoved"] | components["schemas"]["UserJoinedRoom"] | components["schemas"]["UserLeftRoom"] | components["schemas"]["RoomTopicWasChanged"] | components["schemas"]["MessageWasSend"] | components["schemas"]["UserWasMentioned"] | components["schemas"]["MentionWasRead"] | components["schemas"]["MessageLinkPreviewAdded"] | components["schemas"]["UserStartedTyping"] | components["schemas"]["SlashCommandReplied"] | components["schemas"]["UserWasBanned"] | components["schemas"]["UserWasUnbanned"] | components["schemas"]["UserWasKicked"] | components["schemas"]["MessageWasModerated"] | components["schemas"]["SessionWasRevoked"] | components["schemas"]["ServerShuttingDown"];
+    /**
+     * @description One kind of event sent on the event streams, also used as the name of the
+     * server sent event
+     */
+    EventSchema: {
+      name: string;
+      /** Format: uint32 */
+      version: number;
+      description?: string;
+      /** @description JSON Schema of the event as sent on the event streams */
+      schema: unknown;
+    };
+    IncomingMessageRequest: {
+      /**
+       * Format: uuid
+       * @description Makes retries safe, a random id is used when missing
+       */
+      id?: string;
+      message: string;
+      /** @default plain */
+      format?: components["schemas"]["MessageFormat"];
+    };
+    IncomingMessageResponse: {
+      /** Format: uuid */
+      id: string;
+    };
+    /**
+     * @description Lets a tool post messages into a room without logging in, the messages
+     * are sent under the name of the webhook. Names can't be shared with users,
+     * so a webhook can't pass itself off as one.
+     *
+     * A webhook is revoked once its creator leaves the room, is kicked from it
+     * or is banned, and when the room is removed.
+     */
+    IncomingWebhook: {
+      /** Format: uuid */
+      id: string;
+      /** Format: uuid */
+      room_id: string;
+      /** @description Username of the messages posted through the webhook */
+      name: string;
+      created_by: string;
+      /** Format: date-time */
+      created_at: string;
+    };
     IndexRoom: {
       /** Format: uuid */
       id: string;
@@ -111,9 +361,35 @@ export interface components {
       /** Format: date-time */
       left_at: string;
     };
+    LinkPreview: {
+      url: string;
+      title?: string;
+      description?: string;
+      image?: string;
+      site_name?: string;
+    };
     LoginRequest: {
       username: string;
     };
+    Mention: {
+      /** Format: uuid */
+      message_id: string;
+      /** Format: uuid */
+      room_id: string;
+      mentioned_by: string;
+      /** Format: date-time */
+      mentioned_at: string;
+    };
+    /** @description A user read a mention, only sent to that user */
+    MentionWasRead: {
+      /** Format: uuid */
+      message_id: string;
+      /** Format: uuid */
+      room_id: string;
+      username: string;
+      /** Format: date-time */
+      read_at: string;
+    };
     Message: {
       /** Format: uuid */
       id: string;
@@ -121,9 +397,38 @@ export interface components {
       room_id: string;
       username: string;
       message: string;
+      format: components["schemas"]["MessageFormat"];
+      rendered_html: string;
       /** Format: date-time */
       send_at: string;
+      mentions: string[];
+      attachments: components["schemas"]["Attachment"][];
+      /** @description Previews are added asynchronously after the message was sent */
+      link_previews: components["schemas"]["LinkPreview"][];
+      /** @description Posted by a tool through an incoming webhook instead of by a user */
+      bot: boolean;
+    };
+    MessageFormat: "plain" | "markdown";
+    /** @description A preview was fetched for the first link in a message */
+    MessageLinkPreviewAdded: {
+      /** Format: uuid */
+      message_id: string;
+      /** Format: uuid */
+      room_id: string;
+      preview: components["schemas"]["LinkPreview"];
+    };
+    /** @description A message was removed by an admin */
+    MessageWasModerated: {
+      /** Format: uuid */
+      message_id: string;
+      /** Format: uuid */
+      room_id: string;
+      moderated_by: string;
+      reason?: string;
+      /** Format: date-time */
+      moderated_at: string;
     };
+    /** @description A message was sent to a room */
     MessageWasSend: {
       /** Format: uuid */
       id: string;
@@ -131,8 +436,22 @@ export interface components {
       room_id: string;
       username: string;
       message: string;
+      format: components["schemas"]["MessageFormat"];
+      /** @description Sanitised html version of the message, safe to insert into the page */
+      rendered_html: string;
       /** Format: date-time */
       send_at: string;
+      /** @description Files that were uploaded to the room before sending the message */
+      attachments: components["schemas"]["Attachment"][];
+      /**
+       * @description Posted through an incoming webhook, the username is the name of the
+       * webhook rather than a user
+       */
+      bot: boolean;
+    };
+    ModerationRequest: {
+      /** @description Shown to other users and kept in the audit log */
+      reason?: string;
     };
     Pagination: {
       /** Format: uint64 */
@@ -148,6 +467,37 @@ export interface components {
       /** Format: uint64 */
       previous_page?: number;
     };
+    ProjectionRebuild: {
+      /**
+       * Format: uint64
+       * @description Number of stored events that were replayed
+       */
+      events: number;
+    };
+    RegisterWebhookRequest: {
+      url: string;
+      /** @default [] */
+      event_types?: string[];
+      /** Format: uuid */
+      room_id?: string;
+    };
+    RegisteredWebhook: {
+      /** Format: uuid */
+      id: string;
+      url: string;
+      /** @description Only events of these types are delivered, every event when empty */
+      event_types: string[];
+      /**
+       * Format: uuid
+       * @description Only events about this room are delivered
+       */
+      room_id?: string;
+      created_by: string;
+      /** Format: date-time */
+      created_at: string;
+      /** @description Key of the signatures of every delivery, only shown when registering */
+      secret: string;
+    };
     RemoveRoomRequest: {
       /** Format: date-time */
       removed_at: string;
@@ -156,7 +506,19 @@ export interface components {
       /** Format: uuid */
       id: string;
       name: string;
+      /** @description Set by members with the `/topic` command */
+      topic?: string;
     };
+    /** @description The topic of a room was changed, an empty topic clears it */
+    RoomTopicWasChanged: {
+      /** Format: uuid */
+      room_id: string;
+      topic: string;
+      changed_by: string;
+      /** Format: date-time */
+      changed_at: string;
+    };
+    /** @description A new room that users can join */
     RoomWasCreated: {
       /** Format: uuid */
       id: string;
@@ -164,6 +526,7 @@ export interface components {
       /** Format: date-time */
       created_at: string;
     };
+    /** @description A room was deleted together with its messages */
     RoomWasRemoved: {
       /** Format: uuid */
       id: string;
@@ -173,10 +536,57 @@ export interface components {
     SendMessageRequest: {
       /** Format: uuid */
       id: string;
+      /**
+       * @description The configured maximum length is checked when sending, this is the
+       * upper bound of that setting (`config::MAX_MESSAGE_LENGTH`)
+       */
       message: string;
+      /** @default plain */
+      format?: components["schemas"]["MessageFormat"];
       /** Format: date-time */
       send_at: string;
+      /**
+       * @description Ids of attachments that were uploaded to the room beforehand
+       * @default []
+       */
+      attachments?: string[];
+    };
+    /** @description Sent right before the server stops, after which event streams are closed */
+    ServerShuttingDown: {
+      /**
+       * Format: uint64
+       * @description Clients should wait at least this long before reconnecting, plus some
+       * random jitter so that they do not all reconnect at once
+       */
+      reconnect_after_ms: number;
     };
+    /**
+     * @description Sessions of a user were revoked by logging out, ending their event
+     * streams. Only sent to the user.
+     */
+    SessionWasRevoked: {
+      username: string;
+      /**
+       * Format: uuid
+       * @description The revoked session, every session of the user was revoked when empty
+       */
+      session_id?: string;
+    };
+    /**
+     * @description Answer to a slash command, only sent to the user that used the command and
+     * never stored
+     */
+    SlashCommandReplied: {
+      /** Format: uuid */
+      room_id: string;
+      username: string;
+      /** @description The message that contained the command */
+      command: string;
+      reply: string;
+      /** Format: date-time */
+      replied_at: string;
+    };
+    /** @description A user became a member of a room */
     UserJoinedRoom: {
       /** Format: uuid */
       room_id: string;
@@ -184,6 +594,7 @@ export interface components {
       /** Format: date-time */
       joined_at: string;
     };
+    /** @description A user is no longer a member of a room */
     UserLeftRoom: {
       /** Format: uuid */
       room_id: string;
@@ -191,12 +602,75 @@ export interface components {
       /** Format: date-time */
       left_at: string;
     };
+    /** @description A user started a session */
     UserLoggedIn: {
       username: string;
     };
+    /** @description A user ended their session, or all of their sessions were revoked */
     UserLoggedOut: {
       username: string;
     };
+    /**
+     * @description A user is typing in a room, clients should hide the indicator again after
+     * a few seconds
+     */
+    UserStartedTyping: {
+      /** Format: uuid */
+      room_id: string;
+      username: string;
+      /** Format: date-time */
+      started_at: string;
+    };
+    /** @description A user was banned by an admin, their sessions and event streams are ended */
+    UserWasBanned: {
+      username: string;
+      banned_by: string;
+      reason?: string;
+      /** Format: date-time */
+      banned_at: string;
+    };
+    /** @description An admin removed a user from a room */
+    UserWasKicked: {
+      /** Format: uuid */
+      room_id: string;
+      username: string;
+      kicked_by: string;
+      /** Format: date-time */
+      kicked_at: string;
+    };
+    /** @description A message mentioned a user, only sent to the mentioned user */
+    UserWasMentioned: {
+      /** Format: uuid */
+      message_id: string;
+      /** Format: uuid */
+      room_id: string;
+      username: string;
+      mentioned_by: string;
+      /** Format: date-time */
+      mentioned_at: string;
+    };
+    /** @description A ban was lifted by an admin */
+    UserWasUnbanned: {
+      username: string;
+      unbanned_by: string;
+      /** Format: date-time */
+      unbanned_at: string;
+    };
+    Webhook: {
+      /** Format: uuid */
+      id: string;
+      url: string;
+      /** @description Only events of these types are delivered, every event when empty */
+      event_types: string[];
+      /**
+       * Format: uuid
+       * @description Only events about this room are delivered
+       */
+      room_id?: string;
+      created_by: string;
+      /** Format: date-time */
+      created_at: string;
+    };
   };
   responses: never;
   parameters: never;
@@ -239,6 +713,17 @@ export interface operations {
       };
     };
   };
+  /**
+   * @description Log out every session of the current user, for instance after a
+   * cookie was leaked
+   */
+  sessions_delete: {
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
   rooms_get: {
     responses: {
       200: {
@@ -358,4 +843,390 @@ export interface operations {
       };
     };
   };
+  mentions_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["Mention"][];
+        };
+      };
+    };
+  };
+  mentions_mention_delete: {
+    parameters: {
+      path: {
+        message_id: string;
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  generate_events: {
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  /**
+   * @description Every event type that can be received from the event streams, with a
+   * JSON Schema of the event. Events are sent with their type as name, so
+   * browsers can listen to a single type with `addEventListener`.
+   */
+  events_schema_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["EventSchema"][];
+        };
+      };
+    };
+  };
+  rooms_room_attachments_post: {
+    parameters: {
+      path: {
+        room_id: string;
+      };
+    };
+    requestBody: {
+      content: {
+        "multipart/form-data": {
+          /** Format: binary */
+          file: string;
+        };
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["Attachment"];
+        };
+      };
+    };
+  };
+  rooms_room_attachments_attachment_get: {
+    parameters: {
+      path: {
+        room_id: string;
+        attachment_id: string;
+      };
+    };
+    responses: {
+      200: {
+        headers: {
+          "CONTENT-TYPE": string;
+          "CONTENT-DISPOSITION": string;
+          "X-CONTENT-TYPE-OPTIONS": string;
+        };
+        content: {
+          "application/octet-stream": string;
+        };
+      };
+    };
+  };
+  rooms_room_attachments_attachment_thumbnail_get: {
+    parameters: {
+      path: {
+        room_id: string;
+        attachment_id: string;
+      };
+    };
+    responses: {
+      200: {
+        headers: {
+          "CONTENT-TYPE": string;
+          "CONTENT-DISPOSITION": string;
+          "X-CONTENT-TYPE-OPTIONS": string;
+        };
+        content: {
+          "application/octet-stream": string;
+        };
+      };
+    };
+  };
+  /**
+   * @description Bidirectional alternative to the `/events` stream, the socket receives
+   * the same events and accepts commands such as sending a message
+   */
+  ws_get: {
+    responses: {
+      /** @description A websocket response */
+      101: {
+        content: never;
+      };
+    };
+  };
+  admin_rooms_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["AdminRoom"][];
+        };
+      };
+    };
+  };
+  admin_rooms_room_messages_message_delete: {
+    parameters: {
+      path: {
+        room_id: string;
+        message_id: string;
+      };
+    };
+    requestBody: {
+      content: {
+        "application/json; charset=utf-8": components["schemas"]["ModerationRequest"];
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  /** @description Remove a user from a room, they are able to join again unless banned */
+  admin_rooms_room_users_user_delete: {
+    parameters: {
+      path: {
+        room_id: string;
+        username: string;
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  admin_bans_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["Ban"][];
+        };
+      };
+    };
+  };
+  /**
+   * @description Ban a user, logging them out everywhere. Banning a user that already
+   * is banned has no effect.
+   */
+  admin_bans_ban_put: {
+    parameters: {
+      path: {
+        username: string;
+      };
+    };
+    requestBody: {
+      content: {
+        "application/json; charset=utf-8": components["schemas"]["ModerationRequest"];
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  admin_bans_ban_delete: {
+    parameters: {
+      path: {
+        username: string;
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  /** @description Throw away the read models and rebuild them from the stored events */
+  admin_projections_rebuild_post: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["ProjectionRebuild"];
+        };
+      };
+    };
+  };
+  /**
+   * @description Privileged actions, oldest first. `since` is inclusive, `until` is
+   * exclusive.
+   */
+  admin_audit_get: {
+    parameters: {
+      query?: {
+        actor?: string;
+        action?: components["schemas"]["AuditAction"];
+        target?: string;
+        since?: string;
+        until?: string;
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["AuditEntry"][];
+        };
+      };
+    };
+  };
+  /** @description The same entries as `/admin/audit` as JSON lines, one entry per line */
+  admin_audit_export_get: {
+    parameters: {
+      query?: {
+        actor?: string;
+        action?: components["schemas"]["AuditAction"];
+        target?: string;
+        since?: string;
+        until?: string;
+      };
+    };
+    responses: {
+      200: {
+        headers: {
+          "CONTENT-DISPOSITION": string;
+        };
+        content: {
+          "application/x-ndjson": string;
+        };
+      };
+    };
+  };
+  webhooks_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["Webhook"][];
+        };
+      };
+    };
+  };
+  webhooks_post: {
+    requestBody: {
+      content: {
+        "application/json; charset=utf-8": components["schemas"]["RegisterWebhookRequest"];
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["RegisteredWebhook"];
+        };
+      };
+    };
+  };
+  /**
+   * @description Deliveries that are being retried are finished, but no new events are
+   * delivered to the webhook
+   */
+  webhooks_webhook_delete: {
+    parameters: {
+      path: {
+        webhook_id: string;
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  /** @description The most recent delivery attempts of a webhook, oldest first */
+  webhooks_webhook_deliveries_get: {
+    parameters: {
+      path: {
+        webhook_id: string;
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["DeliveryAttempt"][];
+        };
+      };
+    };
+  };
+  webhooks_dead_letters_get: {
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["DeadLetter"][];
+        };
+      };
+    };
+  };
+  rooms_room_webhooks_get: {
+    parameters: {
+      path: {
+        room_id: string;
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["IncomingWebhook"][];
+        };
+      };
+    };
+  };
+  rooms_room_webhooks_post: {
+    parameters: {
+      path: {
+        room_id: string;
+      };
+    };
+    requestBody: {
+      content: {
+        "application/json; charset=utf-8": components["schemas"]["CreateIncomingWebhookRequest"];
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["CreatedIncomingWebhook"];
+        };
+      };
+    };
+  };
+  rooms_room_webhooks_webhook_delete: {
+    parameters: {
+      path: {
+        room_id: string;
+        webhook_id: string;
+      };
+    };
+    responses: {
+      200: {
+        content: never;
+      };
+    };
+  };
+  /**
+   * @description Post a message as the webhook, authenticated by the token in the url
+   * instead of a session. The message is validated like any other message,
+   * but is never treated as a slash command.
+   */
+  hooks_post: {
+    parameters: {
+      path: {
+        token: string;
+      };
+    };
+    requestBody: {
+      content: {
+        "application/json; charset=utf-8": components["schemas"]["IncomingMessageRequest"];
+      };
+    };
+    responses: {
+      200: {
+        content: {
+          "application/json; charset=utf-8": components["schemas"]["IncomingMessageResponse"];
+        };
+      };
+    };
+  };
 }
@@ -0,0 +1,11 @@
+import spec from "./api/api-schema.json";
+
+/**
+ * Server sent events are named after their type, these are the variants of
+ * `DomainEvent` in the generated OpenAPI schema, run `npm run build:schema`
+ * after adding an event.
+ */
+export const EVENT_TYPES: readonly string[] =
+  spec.components.schemas.DomainEvent.anyOf.map(
+    ({ $ref }) => $ref.split("/").pop()!
+  );
@@ -2,7 +2,7 @@ import { createContext, useContext, useEffect, useState } from "react";
 
 export interface EventSourceOptions {
   init?: EventSourceInit;
-  event?: string;
+  event?: string | readonly string[];
 }
 
 export type EventSourceMap = Map<
@@ -19,7 +19,8 @@ export const EventSourceProvider = context.Provider;
 /**
  * Subscribe to an event source and return the latest event.
  * @param url The URL of the event source to connect to
- * @param options The options to pass to the EventSource constructor
+ * @param options The options to pass to the EventSource constructor, and the
+ * name(s) of the events to listen to
  * @returns The last event received from the server
  */
 export function useEventSource(
@@ -41,7 +42,10 @@ export function useEventSource(
 
     map.set(key, value);
 
-    value.source.addEventListener(event, handler);
+    const events = typeof event === "string" ? [event] : event;
+    for (const name of events) {
+      value.source.addEventListener(name, handler);
+    }
 
     // rest data if dependencies change
     setData(null);
@@ -51,7 +55,9 @@ export function useEventSource(
     }
 
     return () => {
-      value.source.removeEventListener(event, handler);
+      for (const name of events) {
+        value.source.removeEventListener(name, handler);
+      }
       --value.count;
       if (value.count <= 0) {
         value.source.close();
@@ -2,6 +2,7 @@ import { useQueryClient, useSuspenseQuery } from "@tanstack/react-query";
 import { useEffect, useRef } from "react";
 import { useRevalidator } from "react-router-dom";
 
+import { EVENT_TYPES } from "./event-types";
 import { sessionQueryOptions } from "./session";
 import { useEventSource } from "./use-event-source";
 import { useLoaderData } from "./use-loader-data";
@@ -29,7 +30,7 @@ export function useLiveLoader<T>(
   const sessionQuery = useSuspenseQuery(sessionQueryOptions());
   //return useLoaderData<T>();
   const queryClient = useQueryClient();
-  const data = useEventSource(eventSourceUrl);
+  const data = useEventSource(eventSourceUrl, { event: EVENT_TYPES });
   const { revalidate } = useRevalidator();
 
   const resolver = useRef(dataResolver);
@@ -54,7 +55,7 @@ export function useLiveLoader<T>(
 
       // data: {"payload":{"type":"UserLoggedOut","username":"Mark - firefox"},"type":"UserLoggedOut"}
 
-      if (event?.type === "UserLoggedOut") {
+      if (event?.type === "UserLoggedOut" || event?.type === "UserWasBanned") {
         const username = event.payload.username;
 
         console.log({ username });
@@ -66,6 +67,17 @@ export function useLiveLoader<T>(
         }
         return;
       }
+
+      // The event source reconnects by itself after the `retry` time the
+      // server sent, refetching now would only hit the server that is going
+      // away. Refetch once it should be back, with the same kind of jitter so
+      // that clients don't all refetch at once.
+      if (event?.type === "ServerShuttingDown") {
+        const delay = event.payload.reconnect_after_ms * (1 + Math.random());
+        const timeout = setTimeout(() => debouncedRefresh.current(), delay);
+
+        return () => clearTimeout(timeout);
+      }
     } catch (e) {
       console.log("wasnt able to parse event", data);
     }